@@ -0,0 +1,1089 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    csr,
+    instruction::{
+        sign_extend, Instruction, InstructionTypeAmo, InstructionTypeB, InstructionTypeCsr,
+        InstructionTypeI, InstructionTypeR, InstructionTypeS, InstructionTypeU, ABI_REGISTER_NAMES,
+    },
+    symbols::SymbolTable,
+};
+
+/// A problem in the assembly source, `line` and `column` are both 1 indexed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// The output of the assembler.
+///
+/// `.text` is laid out first, starting at the origin, and `.data` follows directly after it.
+pub struct Program {
+    pub origin: u32,
+    pub words: Vec<i32>,
+    /// Byte address of every label in the source
+    pub labels: HashMap<String, u32>,
+}
+
+impl Program {
+    pub fn symbols(&self) -> SymbolTable {
+        let mut symbols = SymbolTable::new();
+        for (name, address) in self.labels.iter() {
+            symbols.insert(name, *address);
+        }
+        symbols
+    }
+}
+
+/// Assembles `source` into machine words, starting at address 0.
+pub fn assemble(source: &str) -> Result<Vec<i32>, AssembleError> {
+    assemble_program(source, 0).map(|program| program.words)
+}
+
+/// Assembles `source` for a program that will be loaded at the byte address `origin`.
+pub fn assemble_program(source: &str, origin: u32) -> Result<Program, AssembleError> {
+    // First pass, work out where every statement and label lives
+    let mut text: Vec<Statement> = Vec::new();
+    let mut data: Vec<Statement> = Vec::new();
+    let mut text_labels: Vec<(Token, usize)> = Vec::new();
+    let mut data_labels: Vec<(Token, usize)> = Vec::new();
+    let mut text_size = 0;
+    let mut data_size = 0;
+    let mut section = Section::Text;
+
+    let sizing = Assembler { labels: None };
+    for (idx, line) in source.lines().enumerate() {
+        let (labels, statement) = parse_line(idx + 1, line)?;
+        let (statements, section_labels, size) = match section {
+            Section::Text => (&mut text, &mut text_labels, &mut text_size),
+            Section::Data => (&mut data, &mut data_labels, &mut data_size),
+        };
+        for label in labels {
+            section_labels.push((label, *size));
+        }
+        let (mnemonic, operands) = match statement {
+            Some(statement) => statement,
+            None => continue,
+        };
+        let kind = match mnemonic.text.to_lowercase().as_str() {
+            ".text" => {
+                section = Section::Text;
+                continue;
+            }
+            ".data" => {
+                section = Section::Data;
+                continue;
+            }
+            ".globl" | ".global" => continue,
+            ".word" => {
+                if operands.is_empty() {
+                    return Err(mnemonic.error("Expected at least one value"));
+                }
+                StatementKind::Words
+            }
+            ".zero" | ".space" => {
+                expect_operands(&mnemonic, &operands, 1)?;
+                let bytes = operands[0].immediate(0, 0x0010_0000)?;
+                StatementKind::Zero((bytes as usize).div_ceil(4))
+            }
+            directive if directive.starts_with('.') => {
+                return Err(mnemonic.error(&format!("Unknown directive `{}`", mnemonic.text)))
+            }
+            _ => StatementKind::Instruction,
+        };
+        let statement = Statement {
+            offset: *size,
+            kind,
+            mnemonic,
+            operands,
+        };
+        *size += sizing.size_of(&statement, origin)?;
+        statements.push(statement);
+    }
+
+    let data_origin = origin + (text_size as u32) * 4;
+    let mut labels: HashMap<String, u32> = HashMap::new();
+    let all_labels = text_labels
+        .iter()
+        .map(|(token, offset)| (token, origin + *offset as u32 * 4))
+        .chain(
+            data_labels
+                .iter()
+                .map(|(token, offset)| (token, data_origin + *offset as u32 * 4)),
+        );
+    for (token, address) in all_labels {
+        if labels.insert(token.text.to_string(), address).is_some() {
+            return Err(token.error(&format!("Duplicate label `{}`", token.text)));
+        }
+    }
+
+    // Second pass, now that labels are known everything can be encoded
+    let assembler = Assembler {
+        labels: Some(&labels),
+    };
+    let mut words = Vec::with_capacity(text_size + data_size);
+    for statement in text.iter() {
+        assembler.emit(statement, origin, &mut words)?;
+    }
+    for statement in data.iter() {
+        assembler.emit(statement, data_origin, &mut words)?;
+    }
+
+    Ok(Program {
+        origin,
+        words,
+        labels,
+    })
+}
+
+enum Section {
+    Text,
+    Data,
+}
+
+enum StatementKind {
+    Instruction,
+    Words,
+    Zero(usize),
+}
+
+struct Statement<'a> {
+    /// Offset in words from the start of the section
+    offset: usize,
+    kind: StatementKind,
+    mnemonic: Token<'a>,
+    operands: Vec<Token<'a>>,
+}
+
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Token<'a> {
+    fn error(&self, message: &str) -> AssembleError {
+        AssembleError {
+            line: self.line,
+            column: self.column,
+            message: message.to_string(),
+        }
+    }
+
+    // A piece of this token starting `start` bytes in, with surrounding whitespace removed
+    fn slice(&self, start: usize, end: usize) -> Token<'a> {
+        let raw = &self.text[start..end];
+        let trimmed = raw.trim_start();
+        Token {
+            text: trimmed.trim_end(),
+            line: self.line,
+            column: self.column + self.text[..start].chars().count() + raw.len() - trimmed.len(),
+        }
+    }
+
+    fn register(&self) -> Result<usize, AssembleError> {
+        let name = self.text.to_lowercase();
+        if let Some(idx) = ABI_REGISTER_NAMES.iter().position(|abi| *abi == name) {
+            return Ok(idx);
+        }
+        if name == "fp" {
+            return Ok(8);
+        }
+        if let Some(number) = name.strip_prefix('x') {
+            if let Ok(idx) = number.parse::<usize>() {
+                if idx < 32 && !number.starts_with('+') {
+                    return Ok(idx);
+                }
+            }
+        }
+        Err(self.error(&format!("Expected a register, found `{}`", self.text)))
+    }
+
+    fn number(&self) -> Option<i64> {
+        let (negative, digits) = match self.text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, self.text.strip_prefix('+').unwrap_or(self.text)),
+        };
+        let lower = digits.to_lowercase();
+        let value = if let Some(hex) = lower.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).ok()?
+        } else if let Some(binary) = lower.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2).ok()?
+        } else if let Some(octal) = lower.strip_prefix("0o") {
+            i64::from_str_radix(octal, 8).ok()?
+        } else if lower.starts_with(|c: char| c.is_ascii_digit()) {
+            lower.parse::<i64>().ok()?
+        } else {
+            return None;
+        };
+        Some(if negative { -value } else { value })
+    }
+
+    fn immediate(&self, min: i64, max: i64) -> Result<i32, AssembleError> {
+        let value = self
+            .number()
+            .ok_or_else(|| self.error(&format!("Expected an immediate, found `{}`", self.text)))?;
+        if value < min || value > max {
+            return Err(self.error(&format!(
+                "Immediate {} is out of range ({} to {})",
+                value, min, max
+            )));
+        }
+        Ok(value as i32)
+    }
+
+    // A CSR name or address
+    fn csr(&self) -> Result<u16, AssembleError> {
+        if let Some(csr) = csr::from_name(&self.text.to_lowercase()) {
+            return Ok(csr);
+        }
+        match self.number() {
+            Some(value) if (0..=0xFFF).contains(&value) => Ok(value as u16),
+            _ => Err(self.error(&format!("Expected a CSR, found `{}`", self.text))),
+        }
+    }
+
+    // `imm(reg)`, where the immediate may be left off
+    fn memory(&self) -> Result<(i32, usize), AssembleError> {
+        let open = self.text.find('(');
+        let close = self.text.rfind(')');
+        match (open, close) {
+            (Some(open), Some(close)) if open < close && close == self.text.len() - 1 => {
+                let offset = self.slice(0, open);
+                let imm = if offset.text.is_empty() {
+                    0
+                } else {
+                    offset.immediate(-2048, 2047)?
+                };
+                Ok((imm, self.slice(open + 1, close).register()?))
+            }
+            _ => Err(self.error(&format!(
+                "Expected a memory operand like `8(sp)`, found `{}`",
+                self.text
+            ))),
+        }
+    }
+}
+
+struct Assembler<'l> {
+    // None during the first pass, where only the size of each statement matters
+    labels: Option<&'l HashMap<String, u32>>,
+}
+
+impl<'l> Assembler<'l> {
+    fn size_of(&self, statement: &Statement, origin: u32) -> Result<usize, AssembleError> {
+        match statement.kind {
+            StatementKind::Words => Ok(statement.operands.len()),
+            StatementKind::Zero(words) => Ok(words),
+            StatementKind::Instruction => {
+                let address = origin + statement.offset as u32 * 4;
+                Ok(self
+                    .expand(&statement.mnemonic, &statement.operands, address)?
+                    .len())
+            }
+        }
+    }
+
+    fn emit(
+        &self,
+        statement: &Statement,
+        origin: u32,
+        words: &mut Vec<i32>,
+    ) -> Result<(), AssembleError> {
+        match statement.kind {
+            StatementKind::Words => {
+                for operand in statement.operands.iter() {
+                    words.push(self.value(operand)?);
+                }
+            }
+            StatementKind::Zero(count) => words.extend(std::iter::repeat_n(0, count)),
+            StatementKind::Instruction => {
+                let address = origin + statement.offset as u32 * 4;
+                let instructions =
+                    self.expand(&statement.mnemonic, &statement.operands, address)?;
+                words.extend(instructions.iter().map(Instruction::to_i32));
+            }
+        }
+        Ok(())
+    }
+
+    fn label(&self, token: &Token) -> Result<Option<u32>, AssembleError> {
+        let valid = token
+            .text
+            .starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+            && token.text.chars().all(is_label_char);
+        if !valid {
+            return Err(token.error(&format!("Expected a label, found `{}`", token.text)));
+        }
+        match self.labels {
+            None => Ok(None),
+            Some(labels) => match labels.get(token.text) {
+                Some(address) => Ok(Some(*address)),
+                None => Err(token.error(&format!("Undefined label `{}`", token.text))),
+            },
+        }
+    }
+
+    // A `.word` value, either a number or the address of a label
+    fn value(&self, token: &Token) -> Result<i32, AssembleError> {
+        match token.number() {
+            Some(_) => token.immediate(i32::MIN as i64, u32::MAX as i64),
+            None => Ok(self.label(token)?.unwrap_or(0) as i32),
+        }
+    }
+
+    // Byte offset from `address` to a branch or jump target, which is either a label or a literal offset
+    fn offset(&self, token: &Token, address: u32, bits: u32) -> Result<i32, AssembleError> {
+        let limit = 1i64 << (bits - 1);
+        let offset = match token.number() {
+            Some(_) => token.immediate(-limit, limit - 1)?,
+            None => match self.label(token)? {
+                Some(target) => target.wrapping_sub(address) as i32,
+                None => 0,
+            },
+        };
+        if (offset as i64) < -limit || (offset as i64) >= limit {
+            return Err(token.error(&format!("Target `{}` is out of range", token.text)));
+        }
+        if offset % 2 != 0 {
+            return Err(token.error(&format!("Target `{}` is misaligned", token.text)));
+        }
+        Ok(offset)
+    }
+
+    // PC relative offset to a label for the auipc based pseudo instructions
+    fn relative(&self, token: &Token, address: u32) -> Result<i32, AssembleError> {
+        match self.label(token)? {
+            Some(target) => Ok(target.wrapping_sub(address) as i32),
+            None => Ok(0),
+        }
+    }
+
+    fn expand(
+        &self,
+        mnemonic: &Token,
+        operands: &[Token],
+        address: u32,
+    ) -> Result<Vec<Instruction>, AssembleError> {
+        let ops = operands;
+        let name = mnemonic.text.to_lowercase();
+        let instruction = match name.as_str() {
+            "lui" | "auipc" => {
+                expect_operands(mnemonic, ops, 2)?;
+                let i = InstructionTypeU {
+                    rd: ops[0].register()?,
+                    imm: ops[1].immediate(-0x80000, 0xFFFFF)? << 12,
+                };
+                match name.as_str() {
+                    "lui" => Instruction::LUI(i),
+                    _ => Instruction::AUIPC(i),
+                }
+            }
+            "jal" => {
+                let (rd, target) = match ops.len() {
+                    1 => (1, &ops[0]),
+                    _ => {
+                        expect_operands(mnemonic, ops, 2)?;
+                        (ops[0].register()?, &ops[1])
+                    }
+                };
+                Instruction::JAL(InstructionTypeU {
+                    rd,
+                    imm: self.offset(target, address, 21)?,
+                })
+            }
+            "jalr" => {
+                let i = match ops.len() {
+                    1 => InstructionTypeI {
+                        rd: 1,
+                        rs1: ops[0].register()?,
+                        imm: 0,
+                    },
+                    2 => {
+                        let (imm, rs1) = ops[1].memory()?;
+                        InstructionTypeI {
+                            rd: ops[0].register()?,
+                            rs1,
+                            imm,
+                        }
+                    }
+                    _ => {
+                        expect_operands(mnemonic, ops, 3)?;
+                        InstructionTypeI {
+                            rd: ops[0].register()?,
+                            rs1: ops[1].register()?,
+                            imm: ops[2].immediate(-2048, 2047)?,
+                        }
+                    }
+                };
+                Instruction::JALR(i)
+            }
+            "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => {
+                expect_operands(mnemonic, ops, 3)?;
+                let b = InstructionTypeB {
+                    rs1: ops[0].register()?,
+                    rs2: ops[1].register()?,
+                    imm: self.offset(&ops[2], address, 13)?,
+                };
+                match name.as_str() {
+                    "beq" => Instruction::BEQ(b),
+                    "bne" => Instruction::BNE(b),
+                    "blt" => Instruction::BLT(b),
+                    "bge" => Instruction::BGE(b),
+                    "bltu" => Instruction::BLTU(b),
+                    _ => Instruction::BGEU(b),
+                }
+            }
+            "lb" | "lh" | "lw" | "lbu" | "lhu" => {
+                expect_operands(mnemonic, ops, 2)?;
+                let (imm, rs1) = ops[1].memory()?;
+                let i = InstructionTypeI {
+                    rd: ops[0].register()?,
+                    rs1,
+                    imm,
+                };
+                match name.as_str() {
+                    "lb" => Instruction::LB(i),
+                    "lh" => Instruction::LH(i),
+                    "lw" => Instruction::LW(i),
+                    "lbu" => Instruction::LBU(i),
+                    _ => Instruction::LHU(i),
+                }
+            }
+            "sb" | "sh" | "sw" => {
+                expect_operands(mnemonic, ops, 2)?;
+                let (imm, rs1) = ops[1].memory()?;
+                let s = InstructionTypeS {
+                    rs2: ops[0].register()?,
+                    rs1,
+                    imm,
+                };
+                match name.as_str() {
+                    "sb" => Instruction::SB(s),
+                    "sh" => Instruction::SH(s),
+                    _ => Instruction::SW(s),
+                }
+            }
+            "addi" | "slti" | "sltiu" | "xori" | "ori" | "andi" => {
+                expect_operands(mnemonic, ops, 3)?;
+                let i = InstructionTypeI {
+                    rd: ops[0].register()?,
+                    rs1: ops[1].register()?,
+                    imm: ops[2].immediate(-2048, 2047)?,
+                };
+                match name.as_str() {
+                    "addi" => Instruction::ADDI(i),
+                    "slti" => Instruction::SLTI(i),
+                    "sltiu" => Instruction::SLTIU(i),
+                    "xori" => Instruction::XORI(i),
+                    "ori" => Instruction::ORI(i),
+                    _ => Instruction::ANDI(i),
+                }
+            }
+            "slli" | "srli" | "srai" => {
+                expect_operands(mnemonic, ops, 3)?;
+                let i = InstructionTypeI {
+                    rd: ops[0].register()?,
+                    rs1: ops[1].register()?,
+                    imm: ops[2].immediate(0, 31)?,
+                };
+                match name.as_str() {
+                    "slli" => Instruction::SLLI(i),
+                    "srli" => Instruction::SRLI(i),
+                    _ => Instruction::SRAI(i),
+                }
+            }
+            "add" | "sub" | "sll" | "slt" | "sltu" | "xor" | "srl" | "sra" | "or" | "and" => {
+                expect_operands(mnemonic, ops, 3)?;
+                let r = InstructionTypeR {
+                    rd: ops[0].register()?,
+                    rs1: ops[1].register()?,
+                    rs2: ops[2].register()?,
+                };
+                match name.as_str() {
+                    "add" => Instruction::ADD(r),
+                    "sub" => Instruction::SUB(r),
+                    "sll" => Instruction::SLL(r),
+                    "slt" => Instruction::SLT(r),
+                    "sltu" => Instruction::SLTU(r),
+                    "xor" => Instruction::XOR(r),
+                    "srl" => Instruction::SRL(r),
+                    "sra" => Instruction::SRA(r),
+                    "or" => Instruction::OR(r),
+                    _ => Instruction::AND(r),
+                }
+            }
+            "mul" | "mulh" | "mulhsu" | "mulhu" | "div" | "divu" | "rem" | "remu" => {
+                expect_operands(mnemonic, ops, 3)?;
+                let r = InstructionTypeR {
+                    rd: ops[0].register()?,
+                    rs1: ops[1].register()?,
+                    rs2: ops[2].register()?,
+                };
+                match name.as_str() {
+                    "mul" => Instruction::MUL(r),
+                    "mulh" => Instruction::MULH(r),
+                    "mulhsu" => Instruction::MULHSU(r),
+                    "mulhu" => Instruction::MULHU(r),
+                    "div" => Instruction::DIV(r),
+                    "divu" => Instruction::DIVU(r),
+                    "rem" => Instruction::REM(r),
+                    _ => Instruction::REMU(r),
+                }
+            }
+            "fence" => {
+                let (pred, succ) = match ops.len() {
+                    0 => (0b1111, 0b1111),
+                    _ => {
+                        expect_operands(mnemonic, ops, 2)?;
+                        (fence_set(&ops[0])?, fence_set(&ops[1])?)
+                    }
+                };
+                Instruction::FENCE {
+                    fm: 0,
+                    pred,
+                    succ,
+                    rs1: 0,
+                    rd: 0,
+                }
+            }
+            "ecall" => {
+                expect_operands(mnemonic, ops, 0)?;
+                Instruction::ECALL
+            }
+            "ebreak" => {
+                expect_operands(mnemonic, ops, 0)?;
+                Instruction::EBREAK
+            }
+            "mret" => {
+                expect_operands(mnemonic, ops, 0)?;
+                Instruction::MRET
+            }
+            "wfi" => {
+                expect_operands(mnemonic, ops, 0)?;
+                Instruction::WFI
+            }
+            "csrrw" | "csrrs" | "csrrc" => {
+                expect_operands(mnemonic, ops, 3)?;
+                let i = InstructionTypeCsr {
+                    rd: ops[0].register()?,
+                    csr: ops[1].csr()?,
+                    rs1: ops[2].register()?,
+                };
+                match name.as_str() {
+                    "csrrw" => Instruction::CSRRW(i),
+                    "csrrs" => Instruction::CSRRS(i),
+                    _ => Instruction::CSRRC(i),
+                }
+            }
+            "csrrwi" | "csrrsi" | "csrrci" => {
+                expect_operands(mnemonic, ops, 3)?;
+                let i = InstructionTypeCsr {
+                    rd: ops[0].register()?,
+                    csr: ops[1].csr()?,
+                    rs1: ops[2].immediate(0, 31)? as usize,
+                };
+                match name.as_str() {
+                    "csrrwi" => Instruction::CSRRWI(i),
+                    "csrrsi" => Instruction::CSRRSI(i),
+                    _ => Instruction::CSRRCI(i),
+                }
+            }
+
+            // Pseudo instructions
+            "nop" => {
+                expect_operands(mnemonic, ops, 0)?;
+                Instruction::ADDI(InstructionTypeI {
+                    rd: 0,
+                    rs1: 0,
+                    imm: 0,
+                })
+            }
+            "mv" => {
+                expect_operands(mnemonic, ops, 2)?;
+                Instruction::ADDI(InstructionTypeI {
+                    rd: ops[0].register()?,
+                    rs1: ops[1].register()?,
+                    imm: 0,
+                })
+            }
+            "not" => {
+                expect_operands(mnemonic, ops, 2)?;
+                Instruction::XORI(InstructionTypeI {
+                    rd: ops[0].register()?,
+                    rs1: ops[1].register()?,
+                    imm: -1,
+                })
+            }
+            "neg" => {
+                expect_operands(mnemonic, ops, 2)?;
+                Instruction::SUB(InstructionTypeR {
+                    rd: ops[0].register()?,
+                    rs1: 0,
+                    rs2: ops[1].register()?,
+                })
+            }
+            "li" => {
+                expect_operands(mnemonic, ops, 2)?;
+                let rd = ops[0].register()?;
+                let value = ops[1].immediate(i32::MIN as i64, u32::MAX as i64)?;
+                return Ok(load_immediate(rd, value));
+            }
+            "la" => {
+                expect_operands(mnemonic, ops, 2)?;
+                let rd = ops[0].register()?;
+                let (hi, lo) = split_hi_lo(self.relative(&ops[1], address)?);
+                return Ok(vec![
+                    Instruction::AUIPC(InstructionTypeU { rd, imm: hi }),
+                    Instruction::ADDI(InstructionTypeI {
+                        rd,
+                        rs1: rd,
+                        imm: lo,
+                    }),
+                ]);
+            }
+            "call" => {
+                expect_operands(mnemonic, ops, 1)?;
+                let (hi, lo) = split_hi_lo(self.relative(&ops[0], address)?);
+                return Ok(vec![
+                    Instruction::AUIPC(InstructionTypeU { rd: 1, imm: hi }),
+                    Instruction::JALR(InstructionTypeI {
+                        rd: 1,
+                        rs1: 1,
+                        imm: lo,
+                    }),
+                ]);
+            }
+            "j" => {
+                expect_operands(mnemonic, ops, 1)?;
+                Instruction::JAL(InstructionTypeU {
+                    rd: 0,
+                    imm: self.offset(&ops[0], address, 21)?,
+                })
+            }
+            "jr" => {
+                expect_operands(mnemonic, ops, 1)?;
+                Instruction::JALR(InstructionTypeI {
+                    rd: 0,
+                    rs1: ops[0].register()?,
+                    imm: 0,
+                })
+            }
+            "ret" => {
+                expect_operands(mnemonic, ops, 0)?;
+                Instruction::JALR(InstructionTypeI {
+                    rd: 0,
+                    rs1: 1,
+                    imm: 0,
+                })
+            }
+            "csrr" => {
+                expect_operands(mnemonic, ops, 2)?;
+                Instruction::CSRRS(InstructionTypeCsr {
+                    rd: ops[0].register()?,
+                    csr: ops[1].csr()?,
+                    rs1: 0,
+                })
+            }
+            "csrw" | "csrs" | "csrc" => {
+                expect_operands(mnemonic, ops, 2)?;
+                let i = InstructionTypeCsr {
+                    rd: 0,
+                    csr: ops[0].csr()?,
+                    rs1: ops[1].register()?,
+                };
+                match name.as_str() {
+                    "csrw" => Instruction::CSRRW(i),
+                    "csrs" => Instruction::CSRRS(i),
+                    _ => Instruction::CSRRC(i),
+                }
+            }
+            "csrwi" | "csrsi" | "csrci" => {
+                expect_operands(mnemonic, ops, 2)?;
+                let i = InstructionTypeCsr {
+                    rd: 0,
+                    csr: ops[0].csr()?,
+                    rs1: ops[1].immediate(0, 31)? as usize,
+                };
+                match name.as_str() {
+                    "csrwi" => Instruction::CSRRWI(i),
+                    "csrsi" => Instruction::CSRRSI(i),
+                    _ => Instruction::CSRRCI(i),
+                }
+            }
+            "rdcycle" | "rdcycleh" | "rdtime" | "rdtimeh" | "rdinstret" | "rdinstreth" => {
+                expect_operands(mnemonic, ops, 1)?;
+                Instruction::CSRRS(InstructionTypeCsr {
+                    rd: ops[0].register()?,
+                    csr: csr::from_name(&name[2..]).unwrap(),
+                    rs1: 0,
+                })
+            }
+            "beqz" | "bnez" => {
+                expect_operands(mnemonic, ops, 2)?;
+                let b = InstructionTypeB {
+                    rs1: ops[0].register()?,
+                    rs2: 0,
+                    imm: self.offset(&ops[1], address, 13)?,
+                };
+                match name.as_str() {
+                    "beqz" => Instruction::BEQ(b),
+                    _ => Instruction::BNE(b),
+                }
+            }
+            _ => match atomic(mnemonic, ops)? {
+                Some(instruction) => instruction,
+                None => {
+                    return Err(mnemonic.error(&format!("Unknown instruction `{}`", mnemonic.text)))
+                }
+            },
+        };
+        Ok(vec![instruction])
+    }
+}
+
+fn expect_operands(
+    mnemonic: &Token,
+    operands: &[Token],
+    count: usize,
+) -> Result<(), AssembleError> {
+    if operands.len() != count {
+        return Err(mnemonic.error(&format!(
+            "`{}` expects {} operand(s), found {}",
+            mnemonic.text,
+            count,
+            operands.len()
+        )));
+    }
+    Ok(())
+}
+
+// `lr.w`, `sc.w` and the AMOs, with an optional `.aq`, `.rl` or `.aqrl` ordering suffix
+fn atomic(mnemonic: &Token, ops: &[Token]) -> Result<Option<Instruction>, AssembleError> {
+    let name = mnemonic.text.to_lowercase();
+    let (base, ordering) = match name.find(".w") {
+        Some(idx) => name.split_at(idx + 2),
+        None => return Ok(None),
+    };
+    let (aq, rl) = match ordering {
+        "" => (false, false),
+        ".aq" => (true, false),
+        ".rl" => (false, true),
+        ".aqrl" => (true, true),
+        _ => return Ok(None),
+    };
+    let address = |token: &Token| match token.memory()? {
+        (0, rs1) => Ok(rs1),
+        _ => Err(token.error("Atomics only take a register address, like `(a0)`")),
+    };
+    if base == "lr.w" {
+        expect_operands(mnemonic, ops, 2)?;
+        return Ok(Some(Instruction::LR(InstructionTypeAmo {
+            rd: ops[0].register()?,
+            rs1: address(&ops[1])?,
+            rs2: 0,
+            aq,
+            rl,
+        })));
+    }
+    let instruction: fn(InstructionTypeAmo) -> Instruction = match base {
+        "sc.w" => Instruction::SC,
+        "amoswap.w" => Instruction::AMOSWAP,
+        "amoadd.w" => Instruction::AMOADD,
+        "amoxor.w" => Instruction::AMOXOR,
+        "amoand.w" => Instruction::AMOAND,
+        "amoor.w" => Instruction::AMOOR,
+        "amomin.w" => Instruction::AMOMIN,
+        "amomax.w" => Instruction::AMOMAX,
+        "amominu.w" => Instruction::AMOMINU,
+        "amomaxu.w" => Instruction::AMOMAXU,
+        _ => return Ok(None),
+    };
+    expect_operands(mnemonic, ops, 3)?;
+    Ok(Some(instruction(InstructionTypeAmo {
+        rd: ops[0].register()?,
+        rs2: ops[1].register()?,
+        rs1: address(&ops[2])?,
+        aq,
+        rl,
+    })))
+}
+
+fn fence_set(token: &Token) -> Result<i32, AssembleError> {
+    let mut set = 0;
+    for c in token.text.to_lowercase().chars() {
+        set |= match c {
+            'i' => 0b1000,
+            'o' => 0b0100,
+            'r' => 0b0010,
+            'w' => 0b0001,
+            _ => return Err(token.error(&format!("Invalid fence set `{}`", token.text))),
+        };
+    }
+    Ok(set)
+}
+
+// Splits a 32 bit value into a LUI/AUIPC upper part and a sign extended 12 bit lower part
+fn split_hi_lo(value: i32) -> (i32, i32) {
+    let lo = sign_extend(value & 0xFFF, 12);
+    (value.wrapping_sub(lo), lo)
+}
+
+fn load_immediate(rd: usize, value: i32) -> Vec<Instruction> {
+    if (-2048..=2047).contains(&value) {
+        return vec![Instruction::ADDI(InstructionTypeI {
+            rd,
+            rs1: 0,
+            imm: value,
+        })];
+    }
+    let (hi, lo) = split_hi_lo(value);
+    let mut instructions = vec![Instruction::LUI(InstructionTypeU { rd, imm: hi })];
+    if lo != 0 {
+        instructions.push(Instruction::ADDI(InstructionTypeI {
+            rd,
+            rs1: rd,
+            imm: lo,
+        }));
+    }
+    instructions
+}
+
+fn is_label_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'
+}
+
+type ParsedLine<'a> = (Vec<Token<'a>>, Option<(Token<'a>, Vec<Token<'a>>)>);
+
+// Splits a line into its labels, and optionally a mnemonic/directive with its operands
+fn parse_line(line_number: usize, line: &str) -> Result<ParsedLine<'_>, AssembleError> {
+    let line = match line.find('#') {
+        Some(comment) => &line[..comment],
+        None => line,
+    };
+    let whole = Token {
+        text: line,
+        line: line_number,
+        column: 1,
+    };
+    let mut labels = Vec::new();
+    let mut position = 0;
+    loop {
+        let rest = &line[position..];
+        let start = position + (rest.len() - rest.trim_start().len());
+        if start == line.len() {
+            return Ok((labels, None));
+        }
+        let end = line[start..]
+            .find(|c: char| !is_label_char(c))
+            .map_or(line.len(), |idx| start + idx);
+        let word = whole.slice(start, end);
+        if line[end..].starts_with(':') {
+            if word.text.is_empty() {
+                return Err(word.error("Expected a label name before `:`"));
+            }
+            labels.push(word);
+            position = end + 1;
+            continue;
+        }
+        if word.text.is_empty() {
+            return Err(word.error(&format!(
+                "Unexpected `{}`",
+                line[start..].chars().next().unwrap_or(' ')
+            )));
+        }
+
+        let mut operands = Vec::new();
+        if !line[end..].trim().is_empty() {
+            let mut operand_start = end;
+            for (idx, c) in line[end..].char_indices() {
+                if c == ',' {
+                    operands.push(whole.slice(operand_start, end + idx));
+                    operand_start = end + idx + 1;
+                }
+            }
+            operands.push(whole.slice(operand_start, line.len()));
+        }
+        if let Some(empty) = operands.iter().find(|operand| operand.text.is_empty()) {
+            return Err(empty.error("Expected an operand"));
+        }
+        return Ok((labels, Some((word, operands))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_instructions() {
+        let words = assemble(
+            "
+            addi ra, zero, 1     # comment
+            sw x2, 8(x1)
+            lui t0, 0x12345
+            lw a0, (sp)
+            add x3, x1, x2
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            words,
+            vec![0x00100093, 0x0020a423, 0x123452b7, 0x00012503, 0x002081b3]
+        );
+    }
+
+    #[test]
+    fn csr_instructions() {
+        let words = assemble(
+            "
+            csrr a0, mstatus
+            csrw mtvec, t0
+            csrsi mie, 8
+            rdcycle a1
+            csrrw a0, 0x7c0, a1
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            words,
+            vec![
+                0x30002573,
+                0x30529073,
+                0x30446073,
+                0xc00025f3_u32 as i32,
+                0x7c059573
+            ]
+        );
+        assert!(assemble("csrr a0, 0x1000").is_err());
+        assert!(assemble("csrwi mie, 32").is_err());
+    }
+
+    #[test]
+    fn atomics() {
+        let words = assemble(
+            "
+            lr.w.aq a0, (a1)
+            sc.w.rl a2, a3, (a1)
+            amoadd.w a0, a1, 0(a2)
+            amomaxu.w.aqrl t2, t0, (t1)
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            words,
+            vec![0x1405a52f, 0x1ad5a62f, 0x00b6252f, 0xe65323af_u32 as i32]
+        );
+        assert!(assemble("lr.w a0, 4(a1)").is_err());
+        assert!(assemble("amoadd.w.acq a0, a1, (a2)").is_err());
+    }
+
+    #[test]
+    fn labels_and_branches() {
+        let words = assemble(
+            "
+            start:
+                nop
+            loop: addi a0, a0, -1
+                bnez a0, loop
+                j start
+                jal end
+            end:
+            ",
+        )
+        .unwrap();
+        let expected = [
+            "addi x0, x0, 0",
+            "addi x10, x10, -1",
+            "bne x10, x0, -4",
+            "jal x0, -12",
+            "jal x1, 4",
+        ]
+        .iter()
+        .map(|line| assemble(line).unwrap()[0])
+        .collect::<Vec<i32>>();
+        assert_eq!(words, expected);
+    }
+
+    #[test]
+    fn load_immediate_expansion() {
+        assert_eq!(
+            assemble("li a0, 42").unwrap(),
+            assemble("addi a0, zero, 42").unwrap()
+        );
+        assert_eq!(
+            assemble("li a0, 0x1000").unwrap(),
+            assemble("lui a0, 1").unwrap()
+        );
+        // 0x12345FFF needs the upper part rounded up, since the lower part is negative
+        assert_eq!(
+            assemble("li a0, 0x12345FFF").unwrap(),
+            assemble("lui a0, 0x12346\naddi a0, a0, -1").unwrap()
+        );
+        assert_eq!(
+            assemble("li a0, 0xFFFFFFFF").unwrap(),
+            assemble("addi a0, zero, -1").unwrap()
+        );
+    }
+
+    #[test]
+    fn data_section() {
+        let program = assemble_program(
+            "
+            .text
+                la a0, value
+                call func
+            func:
+                ret
+            .data
+            value: .word 0xDEADBEEF, func
+            ",
+            0x100,
+        )
+        .unwrap();
+        assert_eq!(program.labels["func"], 0x110);
+        assert_eq!(program.labels["value"], 0x114);
+        assert_eq!(
+            program.words,
+            vec![
+                assemble("auipc a0, 0").unwrap()[0],
+                assemble("addi a0, a0, 20").unwrap()[0],
+                assemble("auipc ra, 0").unwrap()[0],
+                assemble("jalr ra, 8(ra)").unwrap()[0],
+                assemble("ret").unwrap()[0],
+                0xDEADBEEFu32 as i32,
+                0x110,
+            ]
+        );
+    }
+
+    #[test]
+    fn error_positions() {
+        let err = assemble("nop\n  addi a0, a9, 1").err().unwrap();
+        assert_eq!((err.line, err.column), (2, 12));
+
+        let err = assemble("\tfoo a0").err().unwrap();
+        assert_eq!((err.line, err.column), (1, 2));
+
+        let err = assemble("addi a0, a0, 4096").err().unwrap();
+        assert_eq!((err.line, err.column), (1, 14));
+
+        let err = assemble("  j nowhere").err().unwrap();
+        assert_eq!((err.line, err.column), (1, 5));
+        assert_eq!(err.message, "Undefined label `nowhere`");
+
+        let err = assemble("a:\na:").err().unwrap();
+        assert_eq!((err.line, err.column), (2, 1));
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+
+/// A peripheral or memory that answers accesses to the address range it's attached at.
+///
+/// Offsets are relative to the start of that range. Returning `Err` reports a bus error. Only
+/// the byte accessors are required, wider accesses are split into bytes by default. Accesses
+/// that aren't aligned to their width are always split into bytes before reaching a device.
+///
+/// Reads take `&self`, devices whose reads have side effects need interior mutability.
+#[allow(clippy::result_unit_err)]
+pub trait Device: Send {
+    fn read_u8(&self, offset: u32) -> Result<u8, ()>;
+
+    fn write_u8(&mut self, offset: u32, data: u8) -> Result<(), ()>;
+
+    fn read_u16(&self, offset: u32) -> Result<u16, ()> {
+        Ok(u16::from_le_bytes([
+            self.read_u8(offset)?,
+            self.read_u8(offset + 1)?,
+        ]))
+    }
+
+    fn read_u32(&self, offset: u32) -> Result<u32, ()> {
+        let low = self.read_u16(offset)? as u32;
+        let high = self.read_u16(offset + 2)? as u32;
+        Ok(low | high << 16)
+    }
+
+    fn write_u16(&mut self, offset: u32, data: u16) -> Result<(), ()> {
+        let [low, high] = data.to_le_bytes();
+        self.write_u8(offset, low)?;
+        self.write_u8(offset + 1, high)
+    }
+
+    fn write_u32(&mut self, offset: u32, data: u32) -> Result<(), ()> {
+        self.write_u16(offset, data as u16)?;
+        self.write_u16(offset + 2, (data >> 16) as u16)
+    }
+
+    /// Host side write used by loaders, defaults to byte writes.
+    fn load(&mut self, offset: u32, data: &[u8]) -> Result<(), ()> {
+        for (idx, byte) in data.iter().enumerate() {
+            self.write_u8(offset + idx as u32, *byte)?;
+        }
+        Ok(())
+    }
+
+    /// Host side read used by loaders, debuggers and syscall handlers, defaults to byte reads.
+    fn read(&self, offset: u32, len: usize) -> Result<Vec<u8>, ()> {
+        (0..len as u32)
+            .map(|idx| self.read_u8(offset + idx))
+            .collect()
+    }
+}
+
+/// Plain read/write memory, used for RAM and video RAM.
+pub struct Ram {
+    data: Vec<u8>,
+}
+
+impl Ram {
+    /// Zero filled memory of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        Ram {
+            data: vec![0; size],
+        }
+    }
+
+    fn slice(&self, offset: u32, len: usize) -> Result<&[u8], ()> {
+        let start = offset as usize;
+        self.data.get(start..start + len).ok_or(())
+    }
+
+    fn slice_mut(&mut self, offset: u32, len: usize) -> Result<&mut [u8], ()> {
+        let start = offset as usize;
+        self.data.get_mut(start..start + len).ok_or(())
+    }
+}
+
+impl Device for Ram {
+    fn read_u8(&self, offset: u32) -> Result<u8, ()> {
+        Ok(self.slice(offset, 1)?[0])
+    }
+
+    fn write_u8(&mut self, offset: u32, data: u8) -> Result<(), ()> {
+        self.slice_mut(offset, 1)?[0] = data;
+        Ok(())
+    }
+
+    fn read_u16(&self, offset: u32) -> Result<u16, ()> {
+        let bytes = self.slice(offset, 2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&self, offset: u32) -> Result<u32, ()> {
+        let bytes = self.slice(offset, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn write_u16(&mut self, offset: u32, data: u16) -> Result<(), ()> {
+        self.slice_mut(offset, 2)?
+            .copy_from_slice(&data.to_le_bytes());
+        Ok(())
+    }
+
+    fn write_u32(&mut self, offset: u32, data: u32) -> Result<(), ()> {
+        self.slice_mut(offset, 4)?
+            .copy_from_slice(&data.to_le_bytes());
+        Ok(())
+    }
+
+    fn load(&mut self, offset: u32, data: &[u8]) -> Result<(), ()> {
+        self.slice_mut(offset, data.len())?.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, offset: u32, len: usize) -> Result<Vec<u8>, ()> {
+        Ok(self.slice(offset, len)?.to_vec())
+    }
+}
+
+/// Memory the guest can only read, loaders can still fill it.
+pub struct Rom(Ram);
+
+impl Rom {
+    /// Zero filled memory of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        Rom(Ram::new(size))
+    }
+}
+
+impl Device for Rom {
+    fn read_u8(&self, offset: u32) -> Result<u8, ()> {
+        self.0.read_u8(offset)
+    }
+
+    fn write_u8(&mut self, _offset: u32, _data: u8) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn read_u16(&self, offset: u32) -> Result<u16, ()> {
+        self.0.read_u16(offset)
+    }
+
+    fn read_u32(&self, offset: u32) -> Result<u32, ()> {
+        self.0.read_u32(offset)
+    }
+
+    fn load(&mut self, offset: u32, data: &[u8]) -> Result<(), ()> {
+        self.0.load(offset, data)
+    }
+
+    fn read(&self, offset: u32, len: usize) -> Result<Vec<u8>, ()> {
+        self.0.read(offset, len)
+    }
+}
+
+/// Why a device couldn't be attached to the bus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapError {
+    /// The range is empty or runs past the end of the address space
+    InvalidRange { base: u32, size: u32 },
+    /// The range overlaps one that's already attached
+    Overlap { base: u32, size: u32 },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::InvalidRange { base, size } => {
+                write!(f, "Invalid range of {:#x} bytes at {:#010x}", size, base)
+            }
+            MapError::Overlap { base, size } => write!(
+                f,
+                "Range of {:#x} bytes at {:#010x} overlaps an attached device",
+                size, base
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, Pineapple, StepError};
+    use std::sync::{Arc, Mutex};
+
+    // Remembers every write, and reads back how many there have been
+    struct Recorder(Arc<Mutex<Vec<(u32, u32)>>>);
+
+    impl Device for Recorder {
+        fn read_u8(&self, _offset: u32) -> Result<u8, ()> {
+            Ok(self.0.lock().unwrap().len() as u8)
+        }
+
+        fn write_u8(&mut self, offset: u32, data: u8) -> Result<(), ()> {
+            self.write_u32(offset, data as u32)
+        }
+
+        fn write_u32(&mut self, offset: u32, data: u32) -> Result<(), ()> {
+            self.0.lock().unwrap().push((offset, data));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn custom_device() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let mut pineapple = Pineapple::new();
+        pineapple
+            .attach_device(0x90000000, 0x10, Box::new(Recorder(writes.clone())))
+            .unwrap();
+        let program = assemble(
+            "
+            li t0, 0x90000000
+            li t1, 0x1234
+            sw t1, 4(t0)
+            sb t1, 9(t0)
+            lw a0, 0(t0)
+            lhu a1, 2(t0)
+            ebreak
+            ",
+        )
+        .unwrap();
+        pineapple.set_program(&program, 0);
+        assert_eq!(pineapple.run_for(100), Err(StepError::Breakpoint));
+        assert_eq!(*writes.lock().unwrap(), vec![(4, 0x1234), (9, 0x34)]);
+        // Wider reads are built from byte reads unless the device overrides them
+        assert_eq!(
+            pineapple.get_registers().unwrap()[10..12],
+            [0x02020202, 0x0202]
+        );
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        let mut pineapple = Pineapple::new();
+        assert_eq!(
+            pineapple.attach_device(0x1F000, 0x2000, Box::new(Ram::new(0x2000))),
+            Err(MapError::Overlap {
+                base: 0x1F000,
+                size: 0x2000
+            })
+        );
+        assert_eq!(
+            pineapple.attach_device(0xFFFFFFF0, 0x20, Box::new(Ram::new(0x20))),
+            Err(MapError::InvalidRange {
+                base: 0xFFFFFFF0,
+                size: 0x20
+            })
+        );
+        assert_eq!(
+            pineapple.attach_device(0x20000, 0x1000, Box::new(Ram::new(0x1000))),
+            Ok(())
+        );
+        pineapple.load_binary(&[1, 2, 3, 4], 0x20FFC).unwrap();
+    }
+}
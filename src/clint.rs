@@ -0,0 +1,299 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::bus::Device;
+
+/// Machine software interrupt pending register of hart 0, bit 0 raises the interrupt. Hart `n`'s
+/// is at `MSIP + 4 * n`
+pub const MSIP: u32 = 0x0000;
+/// Machine timer compare register of hart 0, 64 bits. Hart `n`'s is at `MTIMECMP + 8 * n`
+pub const MTIMECMP: u32 = 0x4000;
+/// Machine timer, 64 bits
+pub const MTIME: u32 = 0xBFF8;
+/// Bytes the CLINT takes up on the bus
+pub const SIZE: u32 = 0x10000;
+
+/// Bit of [`crate::Pineapple::pending_interrupts`] for the machine software interrupt
+pub const MACHINE_SOFTWARE_INTERRUPT: u32 = 1 << 3;
+/// Bit of [`crate::Pineapple::pending_interrupts`] for the machine timer interrupt
+pub const MACHINE_TIMER_INTERRUPT: u32 = 1 << 7;
+
+/// How fast mtime counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimerClock {
+    /// One tick per retired instruction, or per round of instructions when there are several
+    /// harts
+    #[default]
+    Retired,
+    /// `timer_hz` ticks a second on a core running at `core_hz`, which retires one instruction
+    /// per cycle
+    Scaled { core_hz: u64, timer_hz: u64 },
+    /// `hz` ticks a second of the host's wall clock
+    WallClock { hz: u64 },
+}
+
+/// State shared between the CLINT on the bus and the [`crate::Pineapple`] owning it.
+#[derive(Default)]
+pub(crate) struct ClintState {
+    timer: Mutex<Timer>,
+}
+
+struct Timer {
+    clock: TimerClock,
+    // mtime as of `since`, and how many instructions all harts together have retired since
+    mtime: u64,
+    since: Instant,
+    cycles: u64,
+    // One of each per hart
+    mtimecmp: Vec<u64>,
+    msip: Vec<bool>,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer {
+            clock: TimerClock::default(),
+            mtime: 0,
+            since: Instant::now(),
+            cycles: 0,
+            mtimecmp: vec![u64::MAX],
+            msip: vec![false],
+        }
+    }
+}
+
+impl Timer {
+    fn mtime(&self) -> u64 {
+        // The harts run side by side, so a core cycle is one instruction from each
+        let cycles = self.cycles / self.msip.len() as u64;
+        match self.clock {
+            TimerClock::Retired => self.mtime.wrapping_add(cycles),
+            TimerClock::Scaled { core_hz, timer_hz } => {
+                let ticks = cycles as u128 * timer_hz as u128 / core_hz.max(1) as u128;
+                self.mtime.wrapping_add(ticks as u64)
+            }
+            TimerClock::WallClock { hz } => {
+                let ticks = self.since.elapsed().as_nanos() * hz as u128 / 1_000_000_000;
+                self.mtime.wrapping_add(ticks as u64)
+            }
+        }
+    }
+
+    // Restarts counting from `mtime`
+    fn set_mtime(&mut self, mtime: u64) {
+        self.mtime = mtime;
+        self.since = Instant::now();
+        self.cycles = 0;
+    }
+
+    fn read_register(&self, register: u32) -> u32 {
+        match register {
+            _ if !register.is_multiple_of(4) => 0,
+            MTIME => self.mtime() as u32,
+            0xBFFC => (self.mtime() >> 32) as u32,
+            MSIP..MTIMECMP => {
+                let msip = self.msip.get(hart(register, MSIP, 4));
+                msip.map_or(0, |msip| *msip as u32)
+            }
+            MTIMECMP..MTIME => {
+                let mtimecmp = self.mtimecmp.get(hart(register, MTIMECMP, 8)).copied();
+                match register % 8 {
+                    0 => mtimecmp.map_or(0, low),
+                    _ => mtimecmp.map_or(0, high),
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, register: u32, data: u32) {
+        let with_low = |value: u64| (value & !0xFFFF_FFFF) | data as u64;
+        let with_high = |value: u64| (value & 0xFFFF_FFFF) | (data as u64) << 32;
+        match register {
+            _ if !register.is_multiple_of(4) => {}
+            MTIME => self.set_mtime(with_low(self.mtime())),
+            0xBFFC => self.set_mtime(with_high(self.mtime())),
+            MSIP..MTIMECMP => {
+                if let Some(msip) = self.msip.get_mut(hart(register, MSIP, 4)) {
+                    *msip = data & 1 != 0;
+                }
+            }
+            MTIMECMP..MTIME => {
+                if let Some(mtimecmp) = self.mtimecmp.get_mut(hart(register, MTIMECMP, 8)) {
+                    *mtimecmp = match register % 8 {
+                        0 => with_low(*mtimecmp),
+                        _ => with_high(*mtimecmp),
+                    };
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// The hart a register in an array of per hart registers belongs to
+fn hart(register: u32, base: u32, stride: u32) -> usize {
+    ((register - base) / stride) as usize
+}
+
+fn low(value: u64) -> u32 {
+    value as u32
+}
+
+fn high(value: u64) -> u32 {
+    (value >> 32) as u32
+}
+
+impl ClintState {
+    /// Gives another hart its own msip and mtimecmp. The timer keeps its value, but from now on
+    /// counts one tick for every instruction from each hart.
+    pub fn add_hart(&self) {
+        let mut timer = self.timer.lock().unwrap();
+        let mtime = timer.mtime();
+        timer.msip.push(false);
+        timer.mtimecmp.push(u64::MAX);
+        timer.set_mtime(mtime);
+    }
+
+    /// Counts an instruction retired by any hart.
+    pub fn tick(&self) {
+        let mut timer = self.timer.lock().unwrap();
+        timer.cycles = timer.cycles.wrapping_add(1);
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.timer.lock().unwrap().mtime()
+    }
+
+    pub fn set_clock(&self, clock: TimerClock) {
+        let mut timer = self.timer.lock().unwrap();
+        let mtime = timer.mtime();
+        timer.clock = clock;
+        timer.set_mtime(mtime);
+    }
+
+    /// Interrupts pending for `hart` as `mip` bits.
+    pub fn pending(&self, hart: usize) -> u32 {
+        let timer = self.timer.lock().unwrap();
+        let mut pending = 0;
+        if timer.msip[hart] {
+            pending |= MACHINE_SOFTWARE_INTERRUPT;
+        }
+        if timer.mtime() >= timer.mtimecmp[hart] {
+            pending |= MACHINE_TIMER_INTERRUPT;
+        }
+        pending
+    }
+}
+
+/// A core local interruptor, laid out like SiFive's CLINT with an msip and mtimecmp for each
+/// hart.
+///
+/// Registers are 32 bits wide, with the 64 bit ones split into a low and a high word. Narrower
+/// writes replace part of a register.
+pub(crate) struct Clint {
+    state: Arc<ClintState>,
+}
+
+impl Clint {
+    pub fn new(state: Arc<ClintState>) -> Self {
+        Clint { state }
+    }
+}
+
+impl Device for Clint {
+    fn read_u8(&self, offset: u32) -> Result<u8, ()> {
+        Ok((self.read_u32(offset & !3)? >> ((offset % 4) * 8)) as u8)
+    }
+
+    fn write_u8(&mut self, offset: u32, data: u8) -> Result<(), ()> {
+        let mut timer = self.state.timer.lock().unwrap();
+        let shift = (offset % 4) * 8;
+        let register = timer.read_register(offset & !3) & !(0xFF << shift);
+        timer.write_register(offset & !3, register | (data as u32) << shift);
+        Ok(())
+    }
+
+    fn read_u32(&self, offset: u32) -> Result<u32, ()> {
+        Ok(self.state.timer.lock().unwrap().read_register(offset))
+    }
+
+    fn write_u32(&mut self, offset: u32, data: u32) -> Result<(), ()> {
+        self.state
+            .timer
+            .lock()
+            .unwrap()
+            .write_register(offset, data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, Pineapple};
+
+    #[test]
+    fn timer_compare() {
+        let mut pineapple = Pineapple::new();
+        let program = assemble(
+            "
+            li t0, 0x02000000
+            li t1, 0x4000
+            add t1, t0, t1
+            li t2, 20
+            sw t2, 0(t1)        # mtimecmp low
+            sw zero, 4(t1)      # mtimecmp high
+            li t1, 1
+            sw t1, 0(t0)        # msip
+        wait:
+            wfi                 # An idle loop, waiting counts as an instruction
+            j wait
+            ",
+        )
+        .unwrap();
+        pineapple.set_program(&program, 0);
+        assert_eq!(pineapple.pending_interrupts(), 0);
+        pineapple.run_for(8).unwrap();
+        assert_eq!(pineapple.mtime(), 8);
+        assert_eq!(pineapple.pending_interrupts(), MACHINE_SOFTWARE_INTERRUPT);
+
+        pineapple.run_for(12).unwrap();
+        assert_eq!(pineapple.mtime(), 20);
+        assert_eq!(
+            pineapple.pending_interrupts(),
+            MACHINE_SOFTWARE_INTERRUPT | MACHINE_TIMER_INTERRUPT
+        );
+        assert_eq!(
+            pineapple.get_data_range(0x0200BFF8, 0x0200C000),
+            Ok(vec![20, 0])
+        );
+    }
+
+    #[test]
+    fn clocks_and_writes() {
+        let state = Arc::new(ClintState::default());
+        let mut clint = Clint::new(state.clone());
+        state.set_clock(TimerClock::Scaled {
+            core_hz: 100,
+            timer_hz: 10,
+        });
+        for _ in 0..25 {
+            state.tick();
+        }
+        assert_eq!(state.mtime(), 2);
+
+        // Writing mtime restarts the count from the new value
+        clint.write_u32(MTIME + 4, 1).unwrap();
+        assert_eq!(state.mtime(), 1 << 32 | 2);
+        clint.write_u8(MTIME, 0x10).unwrap();
+        assert_eq!(clint.read_u32(MTIME), Ok(0x10));
+        for _ in 0..10 {
+            state.tick();
+        }
+        assert_eq!(clint.read_u16(MTIME), Ok(0x11));
+        assert_eq!(clint.read_u32(MTIMECMP + 4), Ok(u32::MAX));
+    }
+}
@@ -0,0 +1,296 @@
+//! RV32C, where each 16 bit instruction stands for a 32 bit one.
+
+use crate::instruction::{
+    sign_extend, DecodeError, DecodeErrorKind, Instruction, InstructionTypeB, InstructionTypeI,
+    InstructionTypeR, InstructionTypeS, InstructionTypeU,
+};
+
+// Bits start..=end of the parcel, shifted down to bit 0
+fn bits(parcel: u16, start: u32, end: u32) -> i32 {
+    ((parcel as i32) >> end) & ((1 << (start - end + 1)) - 1)
+}
+
+// Moves bits start..=end of the parcel to `to`
+fn field(parcel: u16, start: u32, end: u32, to: u32) -> i32 {
+    bits(parcel, start, end) << to
+}
+
+// The registers x8-x15, which the three bit register fields name
+fn compact_register(parcel: u16, end: u32) -> usize {
+    8 + bits(parcel, end + 2, end) as usize
+}
+
+// The 6 bit signed immediate of C.ADDI, C.LI and C.ANDI
+fn immediate(parcel: u16) -> i32 {
+    sign_extend(field(parcel, 12, 12, 5) | bits(parcel, 6, 2), 6)
+}
+
+// The 6 bit shift amount, whose top bit has to be clear on RV32
+fn shift_amount(parcel: u16) -> Option<i32> {
+    match bits(parcel, 12, 12) {
+        0 => Some(bits(parcel, 6, 2)),
+        _ => None,
+    }
+}
+
+// The jump offset of C.J and C.JAL
+fn jump_offset(parcel: u16) -> i32 {
+    sign_extend(
+        field(parcel, 12, 12, 11)
+            | field(parcel, 11, 11, 4)
+            | field(parcel, 10, 9, 8)
+            | field(parcel, 8, 8, 10)
+            | field(parcel, 7, 7, 6)
+            | field(parcel, 6, 6, 7)
+            | field(parcel, 5, 3, 1)
+            | field(parcel, 2, 2, 5),
+        12,
+    )
+}
+
+// The branch offset of C.BEQZ and C.BNEZ
+fn branch_offset(parcel: u16) -> i32 {
+    sign_extend(
+        field(parcel, 12, 12, 8)
+            | field(parcel, 11, 10, 3)
+            | field(parcel, 6, 5, 6)
+            | field(parcel, 4, 3, 1)
+            | field(parcel, 2, 2, 5),
+        9,
+    )
+}
+
+impl Instruction {
+    /// Whether an instruction whose low half is `parcel` is a 16 bit one.
+    pub fn is_compressed(parcel: u16) -> bool {
+        parcel & 0b11 != 0b11
+    }
+
+    /// Decodes a 16 bit instruction into the 32 bit instruction it expands to.
+    ///
+    /// The floating point loads and stores, and encodings that are reserved or only exist on
+    /// RV64, are [`DecodeErrorKind::Unsupported`].
+    pub fn try_decode_compressed(parcel: u16) -> Result<Instruction, DecodeError> {
+        let unsupported = || DecodeError::new(parcel as i32, DecodeErrorKind::Unsupported);
+        let rd = bits(parcel, 11, 7) as usize;
+        let rs2 = bits(parcel, 6, 2) as usize;
+        let rd_compact = compact_register(parcel, 2);
+        let rs1_compact = compact_register(parcel, 7);
+        // Offsets of C.LW and C.SW
+        let word_offset =
+            field(parcel, 12, 10, 3) | field(parcel, 6, 6, 2) | field(parcel, 5, 5, 6);
+
+        let instruction = match (parcel & 0b11, bits(parcel, 15, 13)) {
+            // C.ADDI4SPN, where an all zero parcel is the defined illegal instruction
+            (0b00, 0b000) => {
+                let imm = field(parcel, 12, 11, 4)
+                    | field(parcel, 10, 7, 6)
+                    | field(parcel, 6, 6, 2)
+                    | field(parcel, 5, 5, 3);
+                if imm == 0 {
+                    return Err(unsupported());
+                }
+                Instruction::ADDI(InstructionTypeI {
+                    imm,
+                    rs1: 2,
+                    rd: rd_compact,
+                })
+            }
+            (0b00, 0b010) => Instruction::LW(InstructionTypeI {
+                imm: word_offset,
+                rs1: rs1_compact,
+                rd: rd_compact,
+            }),
+            (0b00, 0b110) => Instruction::SW(InstructionTypeS {
+                imm: word_offset,
+                rs1: rs1_compact,
+                rs2: rd_compact,
+            }),
+
+            // C.NOP and C.ADDI
+            (0b01, 0b000) => Instruction::ADDI(InstructionTypeI {
+                imm: immediate(parcel),
+                rs1: rd,
+                rd,
+            }),
+            (0b01, 0b001) => Instruction::JAL(InstructionTypeU {
+                imm: jump_offset(parcel),
+                rd: 1,
+            }),
+            // C.LI
+            (0b01, 0b010) => Instruction::ADDI(InstructionTypeI {
+                imm: immediate(parcel),
+                rs1: 0,
+                rd,
+            }),
+            // C.ADDI16SP
+            (0b01, 0b011) if rd == 2 => {
+                let imm = sign_extend(
+                    field(parcel, 12, 12, 9)
+                        | field(parcel, 6, 6, 4)
+                        | field(parcel, 5, 5, 6)
+                        | field(parcel, 4, 3, 7)
+                        | field(parcel, 2, 2, 5),
+                    10,
+                );
+                if imm == 0 {
+                    return Err(unsupported());
+                }
+                Instruction::ADDI(InstructionTypeI { imm, rs1: 2, rd: 2 })
+            }
+            // C.LUI
+            (0b01, 0b011) => {
+                let imm = immediate(parcel) << 12;
+                if imm == 0 {
+                    return Err(unsupported());
+                }
+                Instruction::LUI(InstructionTypeU { imm, rd })
+            }
+            (0b01, 0b100) => {
+                let r_type = InstructionTypeR {
+                    rs2: rd_compact,
+                    rs1: rs1_compact,
+                    rd: rs1_compact,
+                };
+                let i_type = |imm| InstructionTypeI {
+                    imm,
+                    rs1: rs1_compact,
+                    rd: rs1_compact,
+                };
+                match (
+                    bits(parcel, 11, 10),
+                    bits(parcel, 12, 12),
+                    bits(parcel, 6, 5),
+                ) {
+                    (0b00, _, _) => {
+                        Instruction::SRLI(i_type(shift_amount(parcel).ok_or_else(unsupported)?))
+                    }
+                    (0b01, _, _) => {
+                        Instruction::SRAI(i_type(shift_amount(parcel).ok_or_else(unsupported)?))
+                    }
+                    (0b10, _, _) => Instruction::ANDI(i_type(immediate(parcel))),
+                    (0b11, 0, 0b00) => Instruction::SUB(r_type),
+                    (0b11, 0, 0b01) => Instruction::XOR(r_type),
+                    (0b11, 0, 0b10) => Instruction::OR(r_type),
+                    (0b11, 0, _) => Instruction::AND(r_type),
+                    // C.SUBW and C.ADDW
+                    _ => return Err(unsupported()),
+                }
+            }
+            // C.J
+            (0b01, 0b101) => Instruction::JAL(InstructionTypeU {
+                imm: jump_offset(parcel),
+                rd: 0,
+            }),
+            (0b01, 0b110) => Instruction::BEQ(InstructionTypeB {
+                imm: branch_offset(parcel),
+                rs2: 0,
+                rs1: rs1_compact,
+            }),
+            (0b01, 0b111) => Instruction::BNE(InstructionTypeB {
+                imm: branch_offset(parcel),
+                rs2: 0,
+                rs1: rs1_compact,
+            }),
+
+            (0b10, 0b000) => Instruction::SLLI(InstructionTypeI {
+                imm: shift_amount(parcel).ok_or_else(unsupported)?,
+                rs1: rd,
+                rd,
+            }),
+            // C.LWSP
+            (0b10, 0b010) if rd != 0 => Instruction::LW(InstructionTypeI {
+                imm: field(parcel, 12, 12, 5) | field(parcel, 6, 4, 2) | field(parcel, 3, 2, 6),
+                rs1: 2,
+                rd,
+            }),
+            (0b10, 0b100) => match (bits(parcel, 12, 12), rd, rs2) {
+                // C.JR
+                (0, 0, 0) => return Err(unsupported()),
+                (0, rs1, 0) => Instruction::JALR(InstructionTypeI { imm: 0, rs1, rd: 0 }),
+                // C.MV
+                (0, rd, rs2) => Instruction::ADD(InstructionTypeR { rs2, rs1: 0, rd }),
+                (_, 0, 0) => Instruction::EBREAK,
+                // C.JALR
+                (_, rs1, 0) => Instruction::JALR(InstructionTypeI { imm: 0, rs1, rd: 1 }),
+                // C.ADD
+                (_, rd, rs2) => Instruction::ADD(InstructionTypeR { rs2, rs1: rd, rd }),
+            },
+            // C.SWSP
+            (0b10, 0b110) => Instruction::SW(InstructionTypeS {
+                imm: field(parcel, 12, 9, 2) | field(parcel, 8, 7, 6),
+                rs2,
+                rs1: 2,
+            }),
+
+            (0b00, 0b100) => {
+                return Err(DecodeError::new(
+                    parcel as i32,
+                    DecodeErrorKind::UnknownFunct3,
+                ))
+            }
+            // The floating point loads and stores, and C.LWSP into x0
+            _ => return Err(unsupported()),
+        };
+        Ok(instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expansions() {
+        // Each compressed instruction and the 32 bit one it stands for
+        let pairs: [(u16, u32); 28] = [
+            (0x1fe0, 0x3fc10413), // c.addi4spn s0, sp, 1020
+            (0x5d7c, 0x07c52783), // c.lw a5, 124(a0)
+            (0xc3a4, 0x0497a023), // c.sw s1, 64(a5)
+            (0x0001, 0x00000013), // c.nop
+            (0x1501, 0xfe050513), // c.addi a0, -32
+            (0x3001, 0x801ff0ef), // c.jal -2048
+            (0x437d, 0x01f00313), // c.li t1, 31
+            (0x7101, 0xe0010113), // c.addi16sp sp, -512
+            (0x7901, 0xfffe0937), // c.lui s2, 0xfffe0
+            (0x6505, 0x00001537), // c.lui a0, 1
+            (0x827d, 0x01f65613), // c.srli a2, 31
+            (0x8405, 0x40145413), // c.srai s0, 1
+            (0x9b7d, 0xfff77713), // c.andi a4, -1
+            (0x8c9d, 0x40f484b3), // c.sub s1, a5
+            (0x8c25, 0x00944433), // c.xor s0, s1
+            (0x8d4d, 0x00b56533), // c.or a0, a1
+            (0x8e75, 0x00d67633), // c.and a2, a3
+            (0xaffd, 0x7fe0006f), // c.j 2046
+            (0xd281, 0xf00680e3), // c.beqz a3, -256
+            (0xec7d, 0x0e041f63), // c.bnez s0, 254
+            (0x02c6, 0x01129293), // c.slli t0, 17
+            (0x50fe, 0x0fc12083), // c.lwsp ra, 252(sp)
+            (0x8082, 0x00008067), // c.jr ra
+            (0x856e, 0x01b00533), // c.mv a0, s11
+            (0x9002, 0x00100073), // c.ebreak
+            (0x9f82, 0x000f80e7), // c.jalr t6
+            (0x912e, 0x00b10133), // c.add sp, a1
+            (0xc172, 0x09c12023), // c.swsp t3, 128(sp)
+        ];
+        for (parcel, word) in pairs {
+            assert!(Instruction::is_compressed(parcel));
+            assert_eq!(
+                Instruction::try_decode_compressed(parcel),
+                Instruction::try_decode(word as i32),
+                "{:#06x}",
+                parcel
+            );
+        }
+    }
+
+    #[test]
+    fn reserved_encodings() {
+        // The all zero parcel, C.LWSP into x0, C.JR x0, C.ADDI16SP 0, a shift by 32 and C.FLW
+        for parcel in [0x0000, 0x4002, 0x8002, 0x6101, 0x1006, 0x6000] {
+            let err = Instruction::try_decode_compressed(parcel).err().unwrap();
+            assert_eq!(err.kind, DecodeErrorKind::Unsupported, "{:#06x}", parcel);
+        }
+        assert!(!Instruction::is_compressed(0x0013));
+    }
+}
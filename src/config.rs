@@ -0,0 +1,477 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    bus::{Device, Ram, Rom},
+    clint::{Clint, ClintState},
+    memory::MemorySystem,
+    special_registry::{RegistryState, SpecialRegistry},
+    MemoryModel, Pineapple,
+};
+
+/// What backs a region of the memory map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionKind {
+    Ram,
+    /// Read only to the guest, loaders can still fill it
+    Rom,
+    /// RAM that the video output is read from
+    Vram,
+    /// A host supplied [`Device`], or the built in `special_registry` or `clint`
+    Mmio,
+}
+
+/// Which kinds of guest access a region allows, written as a string such as `"rwx"` in files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const READ_WRITE: Permissions = Permissions {
+        read: true,
+        write: true,
+        execute: false,
+    };
+
+    pub const ALL: Permissions = Permissions {
+        read: true,
+        write: true,
+        execute: true,
+    };
+
+    fn parse(text: &str) -> Option<Permissions> {
+        let mut permissions = Permissions {
+            read: false,
+            write: false,
+            execute: false,
+        };
+        for c in text.chars() {
+            let flag = match c {
+                'r' => &mut permissions.read,
+                'w' => &mut permissions.write,
+                'x' => &mut permissions.execute,
+                _ => return None,
+            };
+            if *flag {
+                return None;
+            }
+            *flag = true;
+        }
+        Some(permissions)
+    }
+}
+
+impl<'de> Deserialize<'de> for Permissions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Permissions::parse(&text)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid permissions \"{}\"", text)))
+    }
+}
+
+/// One entry of the memory map.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RegionConfig {
+    pub name: String,
+    #[serde(deserialize_with = "number")]
+    pub base: u32,
+    #[serde(deserialize_with = "number")]
+    pub size: u32,
+    pub kind: RegionKind,
+    /// Defaults to `rwx` for RAM, `rx` for ROM and `rw` for everything else
+    pub permissions: Option<Permissions>,
+    /// Name of the device backing an MMIO region, defaults to the region's name
+    pub device: Option<String>,
+}
+
+impl RegionConfig {
+    fn permissions(&self) -> Permissions {
+        self.permissions.unwrap_or(match self.kind {
+            RegionKind::Ram => Permissions::ALL,
+            RegionKind::Rom => Permissions {
+                read: true,
+                write: false,
+                execute: true,
+            },
+            RegionKind::Vram | RegionKind::Mmio => Permissions::READ_WRITE,
+        })
+    }
+
+    fn end(&self) -> u64 {
+        self.base as u64 + self.size as u64
+    }
+}
+
+/// A description of a board: its memory map and how instructions are fetched.
+///
+/// Read from TOML with [`MachineConfig::from_toml`]:
+///
+/// ```
+/// use pineapple_sim::config::MachineConfig;
+///
+/// let config = MachineConfig::from_toml(r#"
+///     [[regions]]
+///     name = "ram"
+///     base = 0x0
+///     size = 0x10000
+///     kind = "ram"
+///
+///     [[regions]]
+///     name = "boot"
+///     base = 0x20000000
+///     size = 0x1000
+///     kind = "rom"
+///     permissions = "rx"
+/// "#).unwrap();
+/// assert_eq!(config.regions.len(), 2);
+/// ```
+///
+/// or from JSON with [`MachineConfig::from_json`], where addresses may also be hex strings.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct MachineConfig {
+    #[serde(default)]
+    pub memory_model: MemoryModel,
+    pub regions: Vec<RegionConfig>,
+}
+
+impl Default for MachineConfig {
+    /// The memory map from MemoryLayout.md.
+    fn default() -> Self {
+        let region = |name: &str, base, size, kind| RegionConfig {
+            name: name.to_string(),
+            base,
+            size,
+            kind,
+            permissions: None,
+            device: None,
+        };
+        MachineConfig {
+            memory_model: MemoryModel::default(),
+            regions: vec![
+                region("ram", 0x00000000, 0x20000, RegionKind::Ram),
+                region("clint", 0x02000000, crate::clint::SIZE, RegionKind::Mmio),
+                region("vram", 0x40000000, 0x800, RegionKind::Vram),
+                region("special_registry", 0x80000000, 0x10, RegionKind::Mmio),
+            ],
+        }
+    }
+}
+
+impl MachineConfig {
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, ConfigError> {
+        serde_json::from_str(text).map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+
+    /// Checks that every region is well formed and that none of them overlap.
+    ///
+    /// There can only be one video region. With the Harvard [`MemoryModel`] executable regions
+    /// have to lie inside instruction memory, which covers the bottom 2 MiB of the address space.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (idx, region) in self.regions.iter().enumerate() {
+            if region.size == 0 || region.end() > 1 << 32 {
+                return Err(ConfigError::InvalidRange(region.name.clone()));
+            }
+            if self.memory_model == MemoryModel::Harvard
+                && region.permissions().execute
+                && region.end() > crate::INSTRUCTION_MEMORY_SIZE as u64
+            {
+                return Err(ConfigError::NotFetchable(region.name.clone()));
+            }
+            for other in self.regions[..idx].iter() {
+                if other.name == region.name {
+                    return Err(ConfigError::DuplicateName(region.name.clone()));
+                }
+                if region.kind == RegionKind::Vram && other.kind == RegionKind::Vram {
+                    return Err(ConfigError::SecondVram(region.name.clone()));
+                }
+                if (region.base as u64) < other.end() && (other.base as u64) < region.end() {
+                    return Err(ConfigError::Overlap(
+                        other.name.clone(),
+                        region.name.clone(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Builds the bus, taking MMIO devices out of `devices` by name, or from `builtin` if they
+    // aren't supplied
+    pub(crate) fn build(
+        &self,
+        mut devices: HashMap<String, Box<dyn Device>>,
+        builtin: &BuiltinDevices,
+    ) -> Result<MemorySystem, ConfigError> {
+        self.validate()?;
+        let mut memory = MemorySystem::empty();
+        for region in self.regions.iter() {
+            let device: Box<dyn Device> = match region.kind {
+                RegionKind::Ram | RegionKind::Vram => Box::new(Ram::new(region.size as usize)),
+                RegionKind::Rom => Box::new(Rom::new(region.size as usize)),
+                RegionKind::Mmio => {
+                    let name = region.device.as_ref().unwrap_or(&region.name);
+                    devices
+                        .remove(name)
+                        .or_else(|| builtin.device(name))
+                        .ok_or_else(|| ConfigError::MissingDevice(name.clone()))?
+                }
+            };
+            memory
+                .attach(region.base, region.size, region.permissions(), device)
+                .expect("Regions were already validated");
+            if region.kind == RegionKind::Vram {
+                memory.set_video_memory(region.base, region.size);
+            }
+        }
+        Ok(memory)
+    }
+}
+
+// State of the devices the simulator provides itself, shared with the core
+#[derive(Default)]
+pub(crate) struct BuiltinDevices {
+    pub registry: Arc<RegistryState>,
+    pub clint: Arc<ClintState>,
+}
+
+impl BuiltinDevices {
+    fn device(&self, name: &str) -> Option<Box<dyn Device>> {
+        match name {
+            "special_registry" => Some(Box::new(SpecialRegistry::new(self.registry.clone()))),
+            "clint" => Some(Box::new(Clint::new(self.clint.clone()))),
+            _ => None,
+        }
+    }
+}
+
+/// Why a [`MachineConfig`] couldn't be read or used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The file isn't valid TOML/JSON or doesn't describe a machine
+    Parse(String),
+    /// The named region is empty or runs past the end of the address space
+    InvalidRange(String),
+    /// Two regions share a name
+    DuplicateName(String),
+    /// The two named regions overlap
+    Overlap(String, String),
+    /// No device was supplied for an MMIO region
+    MissingDevice(String),
+    /// The named region is a second video region
+    SecondVram(String),
+    /// The named region is executable, but outside the Harvard model's instruction memory
+    NotFetchable(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Parse(message) => write!(f, "Invalid machine description: {}", message),
+            ConfigError::InvalidRange(name) => write!(f, "Region {} has an invalid range", name),
+            ConfigError::DuplicateName(name) => write!(f, "Region {} is defined twice", name),
+            ConfigError::Overlap(first, second) => {
+                write!(f, "Regions {} and {} overlap", first, second)
+            }
+            ConfigError::MissingDevice(name) => write!(f, "No device named {} was supplied", name),
+            ConfigError::SecondVram(name) => {
+                write!(f, "Region {} is a second video region", name)
+            }
+            ConfigError::NotFetchable(name) => {
+                write!(
+                    f,
+                    "Region {} is executable but outside instruction memory",
+                    name
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Pineapple {
+    /// Builds a machine from a description of its memory map.
+    ///
+    /// `devices` supplies the devices behind MMIO regions, keyed by device name.
+    pub fn from_config(
+        config: &MachineConfig,
+        devices: HashMap<String, Box<dyn Device>>,
+    ) -> Result<Self, ConfigError> {
+        let builtin = BuiltinDevices::default();
+        let memory = config.build(devices, &builtin)?;
+        Ok(Pineapple::with_memory_system(
+            config.memory_model,
+            memory,
+            builtin,
+        ))
+    }
+}
+
+// Accepts plain integers, or strings in decimal or 0x prefixed hex since JSON has no hex literals
+fn number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Number {
+        Integer(u32),
+        Text(String),
+    }
+    match Number::deserialize(deserializer)? {
+        Number::Integer(value) => Ok(value),
+        Number::Text(text) => {
+            let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+                Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16),
+                None => text.replace('_', "").parse(),
+            };
+            parsed.map_err(|_| serde::de::Error::custom(format!("invalid number \"{}\"", text)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, StepError};
+
+    struct Constant(u8);
+
+    impl Device for Constant {
+        fn read_u8(&self, _offset: u32) -> Result<u8, ()> {
+            Ok(self.0)
+        }
+
+        fn write_u8(&mut self, _offset: u32, _data: u8) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_board_with_rom_and_device() {
+        let config = MachineConfig::from_json(
+            r#"{
+                "regions": [
+                    { "name": "rom", "base": "0x1000_0000", "size": 4096, "kind": "rom" },
+                    { "name": "ram", "base": 0, "size": "0x8000", "kind": "ram", "permissions": "rw" },
+                    { "name": "id", "base": "0x90000000", "size": 4, "kind": "mmio", "device": "board_id" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.memory_model, MemoryModel::Unified);
+
+        let mut devices: HashMap<String, Box<dyn Device>> = HashMap::new();
+        devices.insert("board_id".to_string(), Box::new(Constant(7)));
+        let mut pineapple = Pineapple::from_config(&config, devices).unwrap();
+        let program = assemble(
+            "
+            li t0, 0x90000000
+            lbu a0, 0(t0)
+            ebreak
+            ",
+        )
+        .unwrap();
+        pineapple.set_program(&program, 0x10000000);
+        pineapple.jump_to(0x10000000);
+        assert_eq!(pineapple.run_for(10), Err(StepError::Breakpoint));
+        assert_eq!(pineapple.get_registers().unwrap()[10], 7);
+
+        // RAM without execute permission can't be fetched from
+        pineapple.set_program(&program, 0);
+        pineapple.jump_to(0);
+        assert_eq!(pineapple.step(), Err(StepError::InstructionAccessFault(0)));
+    }
+
+    #[test]
+    fn invalid_maps() {
+        let config = MachineConfig::from_toml(
+            r#"
+            memory_model = "harvard"
+
+            [[regions]]
+            name = "ram"
+            base = 0x0
+            size = 0x20000
+            kind = "ram"
+
+            [[regions]]
+            name = "vram"
+            base = 0x1F000
+            size = 0x800
+            kind = "vram"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.memory_model, MemoryModel::Harvard);
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Overlap("ram".to_string(), "vram".to_string()))
+        );
+
+        let region = |name: &str, base, kind| RegionConfig {
+            name: name.to_string(),
+            base,
+            size: 0x1000,
+            kind,
+            permissions: None,
+            device: None,
+        };
+        let mut config = MachineConfig::default();
+        config
+            .regions
+            .push(region("vram2", 0x41000000, RegionKind::Vram));
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::SecondVram("vram2".to_string()))
+        );
+
+        // Instruction memory only covers the bottom 2 MiB
+        let mut config = MachineConfig {
+            memory_model: MemoryModel::Harvard,
+            ..Default::default()
+        };
+        config
+            .regions
+            .push(region("flash", 0x00200000, RegionKind::Rom));
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::NotFetchable("flash".to_string()))
+        );
+        config.memory_model = MemoryModel::Unified;
+        assert_eq!(config.validate(), Ok(()));
+
+        let mut config = MachineConfig::default();
+        config.regions.push(RegionConfig {
+            name: "uart".to_string(),
+            base: 0x10000000,
+            size: 8,
+            kind: RegionKind::Mmio,
+            permissions: None,
+            device: None,
+        });
+        assert_eq!(
+            Pineapple::from_config(&config, HashMap::new()).err(),
+            Some(ConfigError::MissingDevice("uart".to_string()))
+        );
+
+        assert!(matches!(
+            MachineConfig::from_toml(
+                "[[regions]]\nname = \"x\"\nbase = 0\nsize = 1\nkind = \"flash\""
+            ),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            MachineConfig::from_json(
+                r#"{"regions": [{"name": "x", "base": 0, "size": 1, "kind": "ram", "permissions": "rwq"}]}"#
+            ),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+}
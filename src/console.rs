@@ -0,0 +1,192 @@
+use crate::{
+    video::{Framebuffer, Image, PixelFormat},
+    Pineapple,
+};
+
+/// One character cell of the text console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub character: u8,
+    pub attribute: u8,
+}
+
+impl Cell {
+    /// Palette index of the character itself.
+    pub fn foreground(&self) -> u8 {
+        self.attribute & 0xF
+    }
+
+    /// Palette index behind the character.
+    pub fn background(&self) -> u8 {
+        self.attribute >> 4
+    }
+}
+
+/// A character cell view of video RAM, matching [`PixelFormat::Text`].
+///
+/// Cells are stored row by row as a character byte followed by an attribute byte. The default
+/// 40x25 grid fills the 2 KiB video region, and is what [`Pineapple::render_video`] uses when
+/// the guest selects text mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextConsole {
+    columns: u32,
+    rows: u32,
+}
+
+impl Default for TextConsole {
+    fn default() -> Self {
+        TextConsole::new(40, 25)
+    }
+}
+
+impl TextConsole {
+    pub fn new(columns: u32, rows: u32) -> Self {
+        TextConsole {
+            columns: columns.max(1),
+            rows,
+        }
+    }
+
+    pub fn columns(&self) -> u32 {
+        self.columns
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+
+    // Rows that fit in `len` bytes of video RAM
+    fn visible_rows(&self, len: usize) -> usize {
+        (self.rows as usize).min(len / (self.columns as usize * 2))
+    }
+
+    /// The cell at `column`, `row`, if it's on the console and inside `vram`.
+    pub fn cell(&self, vram: &[u8], column: u32, row: u32) -> Option<Cell> {
+        if column >= self.columns || row as usize >= self.visible_rows(vram.len()) {
+            return None;
+        }
+        let idx = (row as usize * self.columns as usize + column as usize) * 2;
+        Some(Cell {
+            character: vram[idx],
+            attribute: vram[idx + 1],
+        })
+    }
+
+    /// The characters on screen, one `String` per row. Characters outside printable ASCII read
+    /// as spaces, and rows that don't fit in `vram` are left out.
+    pub fn text(&self, vram: &[u8]) -> Vec<String> {
+        let row_bytes = self.columns as usize * 2;
+        vram.chunks_exact(row_bytes)
+            .take(self.visible_rows(vram.len()))
+            .map(|row| {
+                row.chunks(2)
+                    .map(|cell| match cell[0] {
+                        c @ 0x20..=0x7E => c as char,
+                        _ => ' ',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// A text mode [`Framebuffer`] with this console's width.
+    pub fn framebuffer(&self) -> Framebuffer {
+        Framebuffer::new(PixelFormat::Text).width(self.columns)
+    }
+
+    /// Draws the console with the embedded 8x8 font.
+    pub fn render(&self, vram: &[u8]) -> Image {
+        let len = self.visible_rows(vram.len()) * self.columns as usize * 2;
+        self.framebuffer().render(&vram[..len])
+    }
+}
+
+#[allow(clippy::result_unit_err)]
+impl Pineapple {
+    /// Reads video RAM back as text through `console`.
+    ///
+    /// Fails if the machine has no video RAM.
+    pub fn screen_text(&self, console: &TextConsole) -> Result<Vec<String>, ()> {
+        Ok(console.text(&self.video_ram()?))
+    }
+
+    /// Draws video RAM as text through `console`, ignoring the mode the guest selected.
+    pub fn render_console(&self, console: &TextConsole) -> Result<Image, ()> {
+        Ok(console.render(&self.video_ram()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, StepError};
+
+    #[test]
+    fn guest_prints_text() {
+        let mut pineapple = Pineapple::new();
+        let program = assemble(
+            "
+                li t0, 0x40000000
+                la t1, message
+                li t2, 0x4B         # yellow on blue
+            next:
+                lbu t3, 0(t1)
+                beqz t3, done
+                sb t3, 82(t0)       # second row, second column
+                sb t2, 83(t0)
+                addi t0, t0, 2
+                addi t1, t1, 1
+                j next
+            done:
+                li t0, 0x80000000
+                li t1, 3
+                sw t1, 8(t0)
+                ebreak
+            message:
+                .word 0x00216948    # \"Hi!\"
+            ",
+        )
+        .unwrap();
+        pineapple.set_program(&program, 0);
+        assert_eq!(pineapple.run_for(1000), Err(StepError::Breakpoint));
+
+        let console = TextConsole::default();
+        let text = pineapple.screen_text(&console).unwrap();
+        assert_eq!(text.len(), 25);
+        assert_eq!(text[1].trim_end(), " Hi!");
+        assert_eq!(text[0], " ".repeat(40));
+
+        let vram = pineapple.video_ram().unwrap();
+        let cell = console.cell(&vram, 1, 1).unwrap();
+        assert_eq!(
+            (cell.character, cell.foreground(), cell.background()),
+            (b'H', 0xB, 4)
+        );
+        assert_eq!(console.cell(&vram, 40, 0), None);
+
+        // The guest selected text mode, which renders the same 40x25 grid
+        let image = pineapple.render_video().unwrap();
+        assert_eq!(image, pineapple.render_console(&console).unwrap());
+        assert_eq!((image.width(), image.height()), (320, 200));
+        // The top row of 'H' is 0x33, lighting its first pixel
+        assert_eq!(image.pixel(8, 8), [0xFF, 0xFF, 0x00, 0xFF]);
+        assert_eq!(image.pixel(10, 8), [0x00, 0x00, 0x80, 0xFF]);
+    }
+
+    #[test]
+    fn custom_geometry() {
+        let console = TextConsole::new(4, 3);
+        let mut vram = vec![0; 32];
+        for (idx, c) in b"abcdefgh".iter().enumerate() {
+            vram[idx * 2] = *c;
+        }
+        vram[2] = b'\n';
+        assert_eq!(console.text(&vram), vec!["a cd", "efgh", "    "]);
+        assert_eq!(console.cell(&vram, 0, 3), None);
+
+        // Rows past the end of video RAM are left out
+        assert_eq!(TextConsole::new(4, 10).text(&vram).len(), 4);
+        let image = console.render(&vram);
+        assert_eq!((image.width(), image.height()), (32, 24));
+    }
+}
@@ -0,0 +1,341 @@
+//! The control and status registers reachable through the Zicsr instructions.
+//!
+//! Only machine mode exists, so every CSR listed here is accessible. CSRs whose address has
+//! bits 11:10 set are read-only, and the writable ones only keep the bits that mean something
+//! here (WARL), so writing anything else is silently fixed up rather than refused.
+
+use crate::{
+    clint::ClintState,
+    instruction::{DecodeError, DecodeErrorKind, Instruction, InstructionTypeCsr},
+    special_registry::RegistryState,
+    trap::{self, TrapRegisters},
+    Pineapple, StepError,
+};
+
+// Unprivileged counters, read-only views of the machine ones
+pub const CYCLE: u16 = 0xC00;
+pub const TIME: u16 = 0xC01;
+pub const INSTRET: u16 = 0xC02;
+pub const CYCLEH: u16 = 0xC80;
+pub const TIMEH: u16 = 0xC81;
+pub const INSTRETH: u16 = 0xC82;
+
+// Machine information, read-only
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+
+// Machine trap setup and handling
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSTATUSH: u16 = 0x310;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+
+// Machine counters
+pub const MCYCLE: u16 = 0xB00;
+pub const MINSTRET: u16 = 0xB02;
+pub const MCYCLEH: u16 = 0xB80;
+pub const MINSTRETH: u16 = 0xB82;
+
+/// misa for RV32I: MXL = 1 and the I extension bit.
+pub const MISA_RV32I: u32 = (1 << 30) | (1 << 8);
+
+/// Assembly names of the CSRs, for the assembler and disassembler.
+pub const NAMES: [(u16, &str); 25] = [
+    (CYCLE, "cycle"),
+    (TIME, "time"),
+    (INSTRET, "instret"),
+    (CYCLEH, "cycleh"),
+    (TIMEH, "timeh"),
+    (INSTRETH, "instreth"),
+    (MVENDORID, "mvendorid"),
+    (MARCHID, "marchid"),
+    (MIMPID, "mimpid"),
+    (MHARTID, "mhartid"),
+    (MSTATUS, "mstatus"),
+    (MISA, "misa"),
+    (MIE, "mie"),
+    (MTVEC, "mtvec"),
+    (MSTATUSH, "mstatush"),
+    (MSCRATCH, "mscratch"),
+    (MEPC, "mepc"),
+    (MCAUSE, "mcause"),
+    (MTVAL, "mtval"),
+    (MIP, "mip"),
+    (MCYCLE, "mcycle"),
+    (MINSTRET, "minstret"),
+    (MCYCLEH, "mcycleh"),
+    (MINSTRETH, "minstreth"),
+    // Names older toolchains still emit
+    (MTVAL, "mbadaddr"),
+];
+
+/// The name of `csr`, if it has one.
+pub fn name(csr: u16) -> Option<&'static str> {
+    NAMES
+        .iter()
+        .find(|(address, _)| *address == csr)
+        .map(|(_, name)| *name)
+}
+
+/// The CSR called `name`.
+pub fn from_name(name: &str) -> Option<u16> {
+    NAMES
+        .iter()
+        .find(|(_, other)| *other == name)
+        .map(|(address, _)| *address)
+}
+
+/// Whether `csr` can only be read, going by its address.
+pub fn is_read_only(csr: u16) -> bool {
+    (csr >> 10) & 0b11 == 0b11
+}
+
+const MSTATUS_WRITABLE: u32 = trap::MSTATUS_MIE | trap::MSTATUS_MPIE;
+const MIE_WRITABLE: u32 =
+    (1 << trap::MACHINE_SOFTWARE_INTERRUPT) | (1 << trap::MACHINE_TIMER_INTERRUPT);
+
+// The state behind the CSRs, borrowed apart from the registers an instruction is also using
+pub(crate) struct CsrFile<'a> {
+    pub traps: &'a mut TrapRegisters,
+    pub instret: &'a mut u64,
+    pub misa: u32,
+    pub mhartid: u32,
+    pub registry: &'a RegistryState,
+    pub clint: &'a ClintState,
+}
+
+fn low(value: u64) -> u32 {
+    value as u32
+}
+
+fn high(value: u64) -> u32 {
+    (value >> 32) as u32
+}
+
+fn with_low(value: u64, data: u32) -> u64 {
+    (value & !0xFFFF_FFFF) | data as u64
+}
+
+fn with_high(value: u64, data: u32) -> u64 {
+    (value & 0xFFFF_FFFF) | (data as u64) << 32
+}
+
+impl CsrFile<'_> {
+    fn read(&self, csr: u16) -> Result<u32, ()> {
+        let traps = &self.traps;
+        Ok(match csr {
+            CYCLE | MCYCLE => low(self.registry.cycles()),
+            CYCLEH | MCYCLEH => high(self.registry.cycles()),
+            INSTRET | MINSTRET => low(*self.instret),
+            INSTRETH | MINSTRETH => high(*self.instret),
+            TIME => low(self.clint.mtime()),
+            TIMEH => high(self.clint.mtime()),
+            MVENDORID | MARCHID | MIMPID | MSTATUSH => 0,
+            MHARTID => self.mhartid,
+            MISA => self.misa,
+            MSTATUS => traps.mstatus,
+            MIE => traps.mie,
+            MTVEC => traps.mtvec,
+            MSCRATCH => traps.mscratch,
+            MEPC => traps.mepc,
+            MCAUSE => traps.mcause,
+            MTVAL => traps.mtval,
+            MIP => self.clint.pending(self.mhartid as usize),
+            _ => return Err(()),
+        })
+    }
+
+    fn write(&mut self, csr: u16, data: u32) -> Result<(), ()> {
+        if is_read_only(csr) {
+            return Err(());
+        }
+        let traps = &mut self.traps;
+        match csr {
+            MCYCLE => self
+                .registry
+                .set_cycles(with_low(self.registry.cycles(), data)),
+            MCYCLEH => self
+                .registry
+                .set_cycles(with_high(self.registry.cycles(), data)),
+            MINSTRET => *self.instret = with_low(*self.instret, data),
+            MINSTRETH => *self.instret = with_high(*self.instret, data),
+            MSTATUS => {
+                traps.mstatus = (data & MSTATUS_WRITABLE) | trap::MSTATUS_MPP;
+            }
+            MIE => traps.mie = data & MIE_WRITABLE,
+            // Only direct (0) and vectored (1) modes exist
+            MTVEC => traps.mtvec = data & !0b10,
+            MSCRATCH => traps.mscratch = data,
+            MEPC => {
+                // Only the lowest bit is fixed once 16 bit instructions can be trapped on
+                let alignment = if self.misa & (1 << 2) != 0 { !1 } else { !0b11 };
+                traps.mepc = data & alignment;
+            }
+            MCAUSE => traps.mcause = data,
+            MTVAL => traps.mtval = data,
+            // The extensions can't be switched off, and the pending bits come from the CLINT
+            MISA | MSTATUSH | MIP => {}
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    // Runs a CSR instruction, returning the old value for rd
+    pub fn execute(&mut self, instruction: &Instruction, source: u32) -> Result<u32, StepError> {
+        let illegal = || {
+            StepError::IllegalInstruction(DecodeError::new(
+                instruction.to_i32(),
+                DecodeErrorKind::IllegalCsr,
+            ))
+        };
+        let (i, write): (&InstructionTypeCsr, fn(u32, u32) -> u32) = match instruction {
+            Instruction::CSRRW(i) | Instruction::CSRRWI(i) => (i, |_, source| source),
+            Instruction::CSRRS(i) | Instruction::CSRRSI(i) => (i, |old, source| old | source),
+            Instruction::CSRRC(i) | Instruction::CSRRCI(i) => (i, |old, source| old & !source),
+            _ => unreachable!("{} isn't a CSR instruction", instruction),
+        };
+        let writes =
+            matches!(instruction, Instruction::CSRRW(_) | Instruction::CSRRWI(_)) || i.rs1 != 0;
+        // CSRRW doesn't read when rd is x0, but the CSR must still exist
+        let old = self.read(i.csr).map_err(|_| illegal())?;
+        if writes {
+            self.write(i.csr, write(old, source))
+                .map_err(|_| illegal())?;
+        }
+        Ok(old)
+    }
+}
+
+#[allow(clippy::result_unit_err)]
+impl Pineapple {
+    /// Reads a CSR the way the guest would. Fails if the CSR doesn't exist.
+    pub fn read_csr(&self, csr: u16) -> Result<u32, ()> {
+        // Reads change nothing, so copies of the registers will do
+        let mut traps = self.traps;
+        let mut instret = self.instret;
+        CsrFile {
+            traps: &mut traps,
+            instret: &mut instret,
+            misa: self.extensions.misa(),
+            mhartid: self.hart_id as u32,
+            registry: &self.special_registry,
+            clint: &self.clint,
+        }
+        .read(csr)
+    }
+
+    /// Writes a CSR the way the guest would, keeping only the bits that can be written. Fails if
+    /// the CSR doesn't exist or is read-only.
+    pub fn write_csr(&mut self, csr: u16, value: u32) -> Result<(), ()> {
+        CsrFile {
+            traps: &mut self.traps,
+            instret: &mut self.instret,
+            misa: self.extensions.misa(),
+            mhartid: self.hart_id as u32,
+            registry: &self.special_registry,
+            clint: &self.clint,
+        }
+        .write(csr, value)
+    }
+
+    /// Instructions retired so far, as counted by `instret`.
+    pub fn instructions_retired(&self) -> u64 {
+        self.instret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, Extensions, Pineapple};
+
+    #[test]
+    fn guest_csr_access() {
+        let mut pineapple = Pineapple::new();
+        let program = assemble(
+            "
+                csrr a0, misa
+                csrr a1, mhartid
+                li t0, 0x203
+                csrw mtvec, t0          # mode 3 isn't legal
+                csrr a2, mtvec
+                li t0, -1
+                csrrw a3, mstatus, t0
+                csrr a4, mstatus
+                csrrci zero, mstatus, 8
+                csrr a5, mstatus
+                rdinstret a6
+                rdcycle a7
+                ebreak
+            ",
+        )
+        .unwrap();
+        pineapple.set_program(&program, 0);
+        assert_eq!(pineapple.run_for(100), Err(StepError::Breakpoint));
+        let registers = pineapple.get_registers().unwrap();
+        assert_eq!(registers[10] as u32, pineapple.extensions().misa());
+        assert_eq!(registers[11], 0);
+        assert_eq!(registers[12], 0x201);
+        // Only MIE and MPIE can be written, and MPP always reads machine mode
+        assert_eq!(registers[13] as u32, trap::MSTATUS_MPP);
+        assert_eq!(registers[14], 0x1888);
+        assert_eq!(registers[15], 0x1880);
+        assert_eq!((registers[16], registers[17]), (10, 11));
+
+        // Read-only CSRs can be read with CSRRS x0, but not written
+        let program = assemble("csrr t0, cycle\ncsrw cycle, t0").unwrap();
+        pineapple.set_program(&program, 0);
+        pineapple.jump_to(0);
+        pineapple.step().unwrap();
+        match pineapple.step() {
+            Err(StepError::IllegalInstruction(e)) => {
+                assert_eq!(e.kind, DecodeErrorKind::IllegalCsr);
+                assert_eq!(e.word, 0xc0029073_u32 as i32);
+            }
+            other => panic!("Expected an illegal instruction, got {:?}", other),
+        }
+        assert_eq!(pineapple.get_program_counter(), Ok(4));
+    }
+
+    #[test]
+    fn host_csr_access() {
+        let mut pineapple = Pineapple::new();
+        assert_eq!(pineapple.write_csr(CYCLE, 0), Err(()));
+        assert_eq!(pineapple.read_csr(0x7C0), Err(()));
+        assert_eq!(pineapple.write_csr(0x7C0, 0), Err(()));
+
+        pineapple.write_csr(MCYCLEH, 1).unwrap();
+        pineapple.write_csr(MCYCLE, 5).unwrap();
+        assert_eq!(pineapple.read_csr(CYCLE), Ok(5));
+        assert_eq!(pineapple.read_csr(CYCLEH), Ok(1));
+        assert_eq!(pineapple.cycles(), 1 << 32 | 5);
+
+        pineapple.write_csr(MEPC, 0x103).unwrap();
+        pineapple.write_csr(MIE, u32::MAX).unwrap();
+        let traps = pineapple.trap_registers();
+        assert_eq!((traps.mepc, traps.mie), (0x102, 0x88));
+
+        // Without C, mepc has to be word aligned
+        pineapple.set_extensions(Extensions::rv32i());
+        pineapple.write_csr(MEPC, 0x103).unwrap();
+        assert_eq!(pineapple.trap_registers().mepc, 0x100);
+        pineapple.set_extensions(Extensions::default());
+
+        // Writing MISA is allowed, but can't turn anything off
+        pineapple.write_csr(MISA, 0).unwrap();
+        assert_eq!(pineapple.read_csr(MISA), Ok(pineapple.extensions().misa()));
+
+        pineapple.set_program(&assemble("nop\nnop").unwrap(), 0);
+        pineapple.run_for(2).unwrap();
+        assert_eq!(pineapple.read_csr(MINSTRET), Ok(2));
+        assert_eq!(pineapple.read_csr(TIME), Ok(pineapple.mtime() as u32));
+    }
+}
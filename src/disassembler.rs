@@ -0,0 +1,438 @@
+use std::fmt;
+
+use crate::{
+    csr,
+    instruction::{Instruction, InstructionTypeAmo, ABI_REGISTER_NAMES},
+    symbols::SymbolTable,
+};
+
+/// How registers are printed in a listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterNames {
+    /// `x0`, `x1`, ...
+    Numeric,
+    /// `zero`, `ra`, `sp`, ...
+    Abi,
+}
+
+/// Turns instruction words back into assembly text.
+///
+/// ```
+/// use pineapple_sim::disassembler::{Disassembler, RegisterNames};
+///
+/// let listing = Disassembler::new()
+///     .register_names(RegisterNames::Numeric)
+///     .listing(&[0x00100093], 0);
+/// assert_eq!(listing.to_string(), "       0:\t00100093\taddi\tx1, x0, 1\n");
+/// ```
+pub struct Disassembler<'a> {
+    register_names: RegisterNames,
+    symbols: Option<&'a SymbolTable>,
+}
+
+impl Default for Disassembler<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new() -> Self {
+        Disassembler {
+            register_names: RegisterNames::Abi,
+            symbols: None,
+        }
+    }
+
+    pub fn register_names(mut self, register_names: RegisterNames) -> Self {
+        self.register_names = register_names;
+        self
+    }
+
+    /// Symbols used to label addresses and branch targets.
+    pub fn symbols(mut self, symbols: &'a SymbolTable) -> Self {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    /// Disassembles `words`, the first of which lives at the byte address `base`.
+    pub fn listing(&self, words: &[i32], base: u32) -> Listing {
+        let lines = words
+            .iter()
+            .enumerate()
+            .map(|(idx, word)| {
+                let address = base.wrapping_add(idx as u32 * 4);
+                self.line(address, *word, 4, Instruction::try_decode(*word).ok())
+            })
+            .collect();
+        Listing { lines }
+    }
+
+    /// Disassembles code with the C extension, given as the 16 bit parcels from the byte address
+    /// `base` on.
+    ///
+    /// Each instruction takes one or two parcels. A 32 bit instruction cut off by the end of
+    /// `parcels` is listed as its lone first parcel.
+    pub fn compressed_listing(&self, parcels: &[u16], base: u32) -> Listing {
+        let mut lines = Vec::new();
+        let mut idx = 0;
+        while idx < parcels.len() {
+            let address = base.wrapping_add(idx as u32 * 2);
+            let parcel = parcels[idx];
+            let line = if Instruction::is_compressed(parcel) {
+                let decoded = Instruction::try_decode_compressed(parcel).ok();
+                self.line(address, parcel as i32, 2, decoded)
+            } else if let Some(high) = parcels.get(idx + 1) {
+                let word = ((*high as u32) << 16 | parcel as u32) as i32;
+                self.line(address, word, 4, Instruction::try_decode(word).ok())
+            } else {
+                self.line(address, parcel as i32, 2, None)
+            };
+            idx += line.size / 2;
+            lines.push(line);
+        }
+        Listing { lines }
+    }
+
+    fn line(
+        &self,
+        address: u32,
+        word: i32,
+        size: usize,
+        instruction: Option<Instruction>,
+    ) -> ListingLine {
+        let (mnemonic, operands) = match (instruction, size) {
+            (Some(instruction), _) => self.instruction(&instruction, address),
+            (None, 2) => (".short".to_string(), format!("{:#06x}", word)),
+            (None, _) => (".word".to_string(), format!("{:#010x}", word)),
+        };
+        ListingLine {
+            address,
+            word,
+            size,
+            label: self
+                .symbols
+                .and_then(|symbols| symbols.name_of(address))
+                .map(str::to_string),
+            mnemonic,
+            operands,
+        }
+    }
+
+    /// The mnemonic and operands of a single instruction located at `address`.
+    pub fn instruction(&self, instruction: &Instruction, address: u32) -> (String, String) {
+        let reg = |idx: usize| self.register(idx);
+        let (mnemonic, operands) = match instruction {
+            Instruction::LUI(i) => ("lui", format!("{}, {:#x}", reg(i.rd), (i.imm as u32) >> 12)),
+            Instruction::AUIPC(i) => (
+                "auipc",
+                format!("{}, {:#x}", reg(i.rd), (i.imm as u32) >> 12),
+            ),
+            Instruction::JAL(i) => (
+                "jal",
+                format!("{}, {}", reg(i.rd), self.target(address, i.imm)),
+            ),
+            Instruction::JALR(i) => ("jalr", format!("{}, {}({})", reg(i.rd), i.imm, reg(i.rs1))),
+            Instruction::BEQ(i) => ("beq", self.branch(i.rs1, i.rs2, address, i.imm)),
+            Instruction::BNE(i) => ("bne", self.branch(i.rs1, i.rs2, address, i.imm)),
+            Instruction::BLT(i) => ("blt", self.branch(i.rs1, i.rs2, address, i.imm)),
+            Instruction::BGE(i) => ("bge", self.branch(i.rs1, i.rs2, address, i.imm)),
+            Instruction::BLTU(i) => ("bltu", self.branch(i.rs1, i.rs2, address, i.imm)),
+            Instruction::BGEU(i) => ("bgeu", self.branch(i.rs1, i.rs2, address, i.imm)),
+            Instruction::LB(i) => ("lb", format!("{}, {}({})", reg(i.rd), i.imm, reg(i.rs1))),
+            Instruction::LH(i) => ("lh", format!("{}, {}({})", reg(i.rd), i.imm, reg(i.rs1))),
+            Instruction::LW(i) => ("lw", format!("{}, {}({})", reg(i.rd), i.imm, reg(i.rs1))),
+            Instruction::LBU(i) => ("lbu", format!("{}, {}({})", reg(i.rd), i.imm, reg(i.rs1))),
+            Instruction::LHU(i) => ("lhu", format!("{}, {}({})", reg(i.rd), i.imm, reg(i.rs1))),
+            Instruction::SB(i) => ("sb", format!("{}, {}({})", reg(i.rs2), i.imm, reg(i.rs1))),
+            Instruction::SH(i) => ("sh", format!("{}, {}({})", reg(i.rs2), i.imm, reg(i.rs1))),
+            Instruction::SW(i) => ("sw", format!("{}, {}({})", reg(i.rs2), i.imm, reg(i.rs1))),
+            Instruction::ADDI(i) => ("addi", format!("{}, {}, {}", reg(i.rd), reg(i.rs1), i.imm)),
+            Instruction::SLTI(i) => ("slti", format!("{}, {}, {}", reg(i.rd), reg(i.rs1), i.imm)),
+            Instruction::SLTIU(i) => ("sltiu", format!("{}, {}, {}", reg(i.rd), reg(i.rs1), i.imm)),
+            Instruction::XORI(i) => ("xori", format!("{}, {}, {}", reg(i.rd), reg(i.rs1), i.imm)),
+            Instruction::ORI(i) => ("ori", format!("{}, {}, {}", reg(i.rd), reg(i.rs1), i.imm)),
+            Instruction::ANDI(i) => ("andi", format!("{}, {}, {}", reg(i.rd), reg(i.rs1), i.imm)),
+            Instruction::SLLI(i) => ("slli", format!("{}, {}, {}", reg(i.rd), reg(i.rs1), i.imm)),
+            Instruction::SRLI(i) => ("srli", format!("{}, {}, {}", reg(i.rd), reg(i.rs1), i.imm)),
+            Instruction::SRAI(i) => ("srai", format!("{}, {}, {}", reg(i.rd), reg(i.rs1), i.imm)),
+            Instruction::ADD(i) => ("add", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::SUB(i) => ("sub", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::SLL(i) => ("sll", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::SLT(i) => ("slt", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::SLTU(i) => ("sltu", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::XOR(i) => ("xor", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::SRL(i) => ("srl", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::SRA(i) => ("sra", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::OR(i) => ("or", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::AND(i) => ("and", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::MUL(i) => ("mul", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::MULH(i) => ("mulh", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::MULHSU(i) => ("mulhsu", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::MULHU(i) => ("mulhu", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::DIV(i) => ("div", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::DIVU(i) => ("divu", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::REM(i) => ("rem", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::REMU(i) => ("remu", self.r_type(i.rd, i.rs1, i.rs2)),
+            Instruction::LR(i) => ("lr.w", format!("{}, ({})", reg(i.rd), reg(i.rs1))),
+            Instruction::SC(i) => ("sc.w", self.amo(i)),
+            Instruction::AMOSWAP(i) => ("amoswap.w", self.amo(i)),
+            Instruction::AMOADD(i) => ("amoadd.w", self.amo(i)),
+            Instruction::AMOXOR(i) => ("amoxor.w", self.amo(i)),
+            Instruction::AMOAND(i) => ("amoand.w", self.amo(i)),
+            Instruction::AMOOR(i) => ("amoor.w", self.amo(i)),
+            Instruction::AMOMIN(i) => ("amomin.w", self.amo(i)),
+            Instruction::AMOMAX(i) => ("amomax.w", self.amo(i)),
+            Instruction::AMOMINU(i) => ("amominu.w", self.amo(i)),
+            Instruction::AMOMAXU(i) => ("amomaxu.w", self.amo(i)),
+            Instruction::FENCE { pred, succ, .. } => (
+                "fence",
+                format!("{}, {}", fence_set(*pred), fence_set(*succ)),
+            ),
+            Instruction::ECALL => ("ecall", String::new()),
+            Instruction::EBREAK => ("ebreak", String::new()),
+            Instruction::MRET => ("mret", String::new()),
+            Instruction::WFI => ("wfi", String::new()),
+            Instruction::CSRRW(i) => (
+                "csrrw",
+                format!("{}, {}, {}", reg(i.rd), csr(i.csr), reg(i.rs1)),
+            ),
+            Instruction::CSRRS(i) => (
+                "csrrs",
+                format!("{}, {}, {}", reg(i.rd), csr(i.csr), reg(i.rs1)),
+            ),
+            Instruction::CSRRC(i) => (
+                "csrrc",
+                format!("{}, {}, {}", reg(i.rd), csr(i.csr), reg(i.rs1)),
+            ),
+            Instruction::CSRRWI(i) => (
+                "csrrwi",
+                format!("{}, {}, {}", reg(i.rd), csr(i.csr), i.rs1),
+            ),
+            Instruction::CSRRSI(i) => (
+                "csrrsi",
+                format!("{}, {}, {}", reg(i.rd), csr(i.csr), i.rs1),
+            ),
+            Instruction::CSRRCI(i) => (
+                "csrrci",
+                format!("{}, {}, {}", reg(i.rd), csr(i.csr), i.rs1),
+            ),
+        };
+        // The atomics' ordering bits are a suffix on the mnemonic
+        let ordering = match instruction {
+            Instruction::LR(i)
+            | Instruction::SC(i)
+            | Instruction::AMOSWAP(i)
+            | Instruction::AMOADD(i)
+            | Instruction::AMOXOR(i)
+            | Instruction::AMOAND(i)
+            | Instruction::AMOOR(i)
+            | Instruction::AMOMIN(i)
+            | Instruction::AMOMAX(i)
+            | Instruction::AMOMINU(i)
+            | Instruction::AMOMAXU(i) => i.ordering(),
+            _ => "",
+        };
+        (mnemonic.to_string() + ordering, operands)
+    }
+
+    fn register(&self, idx: usize) -> String {
+        match self.register_names {
+            RegisterNames::Numeric => format!("x{}", idx),
+            RegisterNames::Abi => ABI_REGISTER_NAMES[idx].to_string(),
+        }
+    }
+
+    fn r_type(&self, rd: usize, rs1: usize, rs2: usize) -> String {
+        format!(
+            "{}, {}, {}",
+            self.register(rd),
+            self.register(rs1),
+            self.register(rs2)
+        )
+    }
+
+    fn amo(&self, i: &InstructionTypeAmo) -> String {
+        format!(
+            "{}, {}, ({})",
+            self.register(i.rd),
+            self.register(i.rs2),
+            self.register(i.rs1)
+        )
+    }
+
+    fn branch(&self, rs1: usize, rs2: usize, address: u32, offset: i32) -> String {
+        format!(
+            "{}, {}, {}",
+            self.register(rs1),
+            self.register(rs2),
+            self.target(address, offset)
+        )
+    }
+
+    // Absolute address of a PC relative target, labelled with the closest symbol if there is one
+    fn target(&self, address: u32, offset: i32) -> String {
+        let target = address.wrapping_add(offset as u32);
+        match self.symbols.and_then(|symbols| symbols.nearest(target)) {
+            Some((name, 0)) => format!("{:#x} <{}>", target, name),
+            Some((name, distance)) => format!("{:#x} <{}+{:#x}>", target, name, distance),
+            None => format!("{:#x}", target),
+        }
+    }
+}
+
+// CSRs without a name are printed as their address
+fn csr(csr: u16) -> String {
+    match csr::name(csr) {
+        Some(name) => name.to_string(),
+        None => format!("{:#x}", csr),
+    }
+}
+
+fn fence_set(set: i32) -> String {
+    let set: String = ['i', 'o', 'r', 'w']
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| set & (0b1000 >> bit) != 0)
+        .map(|(_, c)| *c)
+        .collect();
+    if set.is_empty() {
+        "0".to_string()
+    } else {
+        set
+    }
+}
+
+/// A single disassembled instruction.
+pub struct ListingLine {
+    pub address: u32,
+    /// The instruction as encoded, in its low `size` bytes
+    pub word: i32,
+    /// 2 for a compressed instruction, otherwise 4
+    pub size: usize,
+    /// Symbol that starts at this address
+    pub label: Option<String>,
+    pub mnemonic: String,
+    pub operands: String,
+}
+
+/// An objdump style listing, produced by [`Disassembler::listing`].
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in self.lines.iter() {
+            if let Some(label) = &line.label {
+                writeln!(f, "\n{:08x} <{}>:", line.address, label)?;
+            }
+            write!(f, "{:8x}:\t", line.address)?;
+            match line.size {
+                2 => write!(f, "{:04x}    ", line.word)?,
+                _ => write!(f, "{:08x}", line.word)?,
+            }
+            write!(f, "\t{}", line.mnemonic)?;
+            if !line.operands.is_empty() {
+                write!(f, "\t{}", line.operands)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_program;
+
+    #[test]
+    fn listing_with_symbols() {
+        let program = assemble_program(
+            "
+            main:
+                li a0, 3
+            loop:
+                addi a0, a0, -1
+                bnez a0, loop
+                call done
+            done:
+                ret
+            ",
+            0x100,
+        )
+        .unwrap();
+        let symbols = program.symbols();
+        let mut words = program.words.clone();
+        words.push(0);
+        let listing = Disassembler::new()
+            .symbols(&symbols)
+            .listing(&words, program.origin);
+
+        let expected = "
+00000100 <main>:
+     100:\t00300513\taddi\ta0, zero, 3
+
+00000104 <loop>:
+     104:\tfff50513\taddi\ta0, a0, -1
+     108:\tfe051ee3\tbne\ta0, zero, 0x104 <loop>
+     10c:\t00000097\tauipc\tra, 0x0
+     110:\t008080e7\tjalr\tra, 8(ra)
+
+00000114 <done>:
+     114:\t00008067\tjalr\tzero, 0(ra)
+     118:\t00000000\t.word\t0x00000000
+";
+        assert_eq!(listing.to_string(), expected);
+    }
+
+    #[test]
+    fn mixed_compressed_code() {
+        // c.li a0, 5; c.nop; addi a0, a0, 1; c.j -6; and the first half of a cut off lui
+        let parcels = [0x4515, 0x0001, 0x0513, 0x0015, 0xbfed, 0x0537];
+        let listing = Disassembler::new().compressed_listing(&parcels, 0x100);
+        let expected = "     100:\t4515    \taddi\ta0, zero, 5
+     102:\t0001    \taddi\tzero, zero, 0
+     104:\t00150513\taddi\ta0, a0, 1
+     108:\tbfed    \tjal\tzero, 0x102
+     10a:\t0537    \t.short\t0x0537
+";
+        assert_eq!(listing.to_string(), expected);
+
+        // A machine with the C extension lists its code the same way
+        let mut pineapple = crate::Pineapple::new();
+        pineapple.set_program(&[0x00014515, 0x00150513], 0);
+        let listing = pineapple.disassemble(0, 8, &Disassembler::new()).unwrap();
+        let addresses: Vec<u32> = listing.lines.iter().map(|line| line.address).collect();
+        assert_eq!(addresses, [0, 2, 4]);
+        assert_eq!(listing.lines[2].mnemonic, "addi");
+    }
+
+    #[test]
+    fn numeric_registers_and_offsets() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("func", 0x10);
+        let disassembler = Disassembler::new()
+            .register_names(RegisterNames::Numeric)
+            .symbols(&symbols);
+        let (mnemonic, operands) =
+            disassembler.instruction(&Instruction::from_i32(0x008000ef), 0x10);
+        assert_eq!(mnemonic, "jal");
+        assert_eq!(operands, "x1, 0x18 <func+0x8>");
+
+        let (mnemonic, operands) = disassembler.instruction(&Instruction::from_i32(0x0ff0000f), 0);
+        assert_eq!(mnemonic, "fence");
+        assert_eq!(operands, "iorw, iorw");
+
+        let (mnemonic, operands) = disassembler.instruction(&Instruction::from_i32(0x30002573), 0);
+        assert_eq!(mnemonic, "csrrs");
+        assert_eq!(operands, "x10, mstatus, x0");
+        let (mnemonic, operands) = disassembler.instruction(&Instruction::from_i32(0x7c0fd073), 0);
+        assert_eq!(mnemonic, "csrrwi");
+        assert_eq!(operands, "x0, 0x7c0, 31");
+        let (mnemonic, operands) = disassembler.instruction(&Instruction::from_i32(0x1ad5a62f), 0);
+        assert_eq!(mnemonic, "sc.w.rl");
+        assert_eq!(operands, "x12, x13, (x11)");
+    }
+}
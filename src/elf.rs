@@ -0,0 +1,430 @@
+use std::fmt;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{symbols::SymbolTable, MemoryModel, Pineapple};
+
+const EM_RISCV: u16 = 243;
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+/// Why an ELF file could not be loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file doesn't start with the ELF magic number
+    NotElf,
+    /// A valid ELF file, but not a little endian RV32 executable
+    Unsupported(&'static str),
+    /// A header or table points past the end of the file
+    Truncated,
+    /// A segment doesn't fit inside a single mapped device
+    ReservedRange { address: u32, size: u32 },
+    /// An executable segment or the entry point isn't aligned to instructions, 4 bytes or 2 with
+    /// the C extension
+    Misaligned(u32),
+    /// The device a segment lands on refused to be loaded
+    Refused { address: u32, size: u32 },
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "Not an ELF file"),
+            ElfError::Unsupported(reason) => write!(f, "Unsupported ELF file: {}", reason),
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::ReservedRange { address, size } => write!(
+                f,
+                "Segment at {:#010x} ({:#x} bytes) isn't backed by a single device",
+                address, size
+            ),
+            ElfError::Misaligned(address) => write!(f, "Address {:#010x} is misaligned", address),
+            ElfError::Refused { address, size } => write!(
+                f,
+                "The device at {:#010x} refused the {:#x} byte segment",
+                address, size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+/// A `PT_LOAD` segment.
+pub struct Segment {
+    pub address: u32,
+    /// The bytes stored in the file
+    pub data: Vec<u8>,
+    /// Size in memory, anything past the end of `data` is zero filled when it's loaded
+    pub size: u32,
+    pub executable: bool,
+}
+
+impl Segment {
+    // The segment's contents as they are laid out in memory
+    fn contents(&self) -> Vec<u8> {
+        let mut contents = self.data.clone();
+        contents.resize(self.size as usize, 0);
+        contents
+    }
+}
+
+/// The parts of an ELF executable the simulator cares about.
+pub struct ElfImage {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: SymbolTable,
+}
+
+impl ElfImage {
+    /// Parses a little endian ELF32 RISC-V executable.
+    pub fn parse(bytes: &[u8]) -> Result<ElfImage, ElfError> {
+        if bytes.len() < 4 || bytes[..4] != [0x7F, b'E', b'L', b'F'] {
+            return Err(ElfError::NotElf);
+        }
+        let header = slice(bytes, 0, 52)?;
+        if header[4] != 1 {
+            return Err(ElfError::Unsupported("not a 32 bit ELF file"));
+        }
+        if header[5] != 1 {
+            return Err(ElfError::Unsupported("not little endian"));
+        }
+        if LittleEndian::read_u16(&header[18..]) != EM_RISCV {
+            return Err(ElfError::Unsupported("not a RISC-V ELF file"));
+        }
+        if LittleEndian::read_u16(&header[16..]) != ET_EXEC {
+            return Err(ElfError::Unsupported("not an executable"));
+        }
+        let entry = LittleEndian::read_u32(&header[24..]);
+        let phoff = LittleEndian::read_u32(&header[28..]) as usize;
+        let shoff = LittleEndian::read_u32(&header[32..]) as usize;
+        let phentsize = LittleEndian::read_u16(&header[42..]) as usize;
+        let phnum = LittleEndian::read_u16(&header[44..]) as usize;
+        let shentsize = LittleEndian::read_u16(&header[46..]) as usize;
+        let shnum = LittleEndian::read_u16(&header[48..]) as usize;
+
+        let mut segments = Vec::new();
+        for idx in 0..phnum {
+            let ph = slice(bytes, phoff + idx * phentsize, 32)?;
+            if LittleEndian::read_u32(&ph[0..]) != PT_LOAD {
+                continue;
+            }
+            let offset = LittleEndian::read_u32(&ph[4..]) as usize;
+            // Firmware is placed at its load address, startup code moves anything that runs elsewhere
+            let address = LittleEndian::read_u32(&ph[12..]);
+            let filesz = LittleEndian::read_u32(&ph[16..]) as usize;
+            let memsz = LittleEndian::read_u32(&ph[20..]) as usize;
+            let flags = LittleEndian::read_u32(&ph[24..]);
+            if memsz == 0 {
+                continue;
+            }
+            // The zero filled tail is only allocated once the segment is known to fit in memory
+            segments.push(Segment {
+                address,
+                data: slice(bytes, offset, filesz.min(memsz))?.to_vec(),
+                size: memsz as u32,
+                executable: flags & PF_X != 0,
+            });
+        }
+
+        let mut symbols = SymbolTable::new();
+        for idx in 0..shnum {
+            let sh = slice(bytes, shoff + idx * shentsize, 40)?;
+            if LittleEndian::read_u32(&sh[4..]) != SHT_SYMTAB {
+                continue;
+            }
+            let table = slice(
+                bytes,
+                LittleEndian::read_u32(&sh[16..]) as usize,
+                LittleEndian::read_u32(&sh[20..]) as usize,
+            )?;
+            let link = LittleEndian::read_u32(&sh[24..]) as usize;
+            let strtab_header = slice(bytes, shoff + link * shentsize, 40)?;
+            let strtab = slice(
+                bytes,
+                LittleEndian::read_u32(&strtab_header[16..]) as usize,
+                LittleEndian::read_u32(&strtab_header[20..]) as usize,
+            )?;
+            for symbol in table.chunks_exact(16) {
+                let kind = symbol[12] & 0xF;
+                if kind == STT_SECTION || kind == STT_FILE {
+                    continue;
+                }
+                let name = string(strtab, LittleEndian::read_u32(&symbol[0..]) as usize)?;
+                if !name.is_empty() {
+                    symbols.insert(name, LittleEndian::read_u32(&symbol[4..]));
+                }
+            }
+        }
+
+        Ok(ElfImage {
+            entry,
+            segments,
+            symbols,
+        })
+    }
+}
+
+fn slice(bytes: &[u8], start: usize, len: usize) -> Result<&[u8], ElfError> {
+    let end = start.checked_add(len).ok_or(ElfError::Truncated)?;
+    bytes.get(start..end).ok_or(ElfError::Truncated)
+}
+
+fn string(table: &[u8], start: usize) -> Result<&str, ElfError> {
+    let rest = table.get(start..).ok_or(ElfError::Truncated)?;
+    let end = rest
+        .iter()
+        .position(|c| *c == 0)
+        .ok_or(ElfError::Truncated)?;
+    std::str::from_utf8(&rest[..end]).map_err(|_| ElfError::Unsupported("symbol name isn't UTF-8"))
+}
+
+impl Pineapple {
+    /// Loads an ELF executable and points the program counter at its entry point.
+    ///
+    /// Every segment is copied into the device it lands on following the memory map. With the
+    /// Harvard [`crate::MemoryModel`] executable segments also go into instruction memory, so code
+    /// can be fetched while any read only data placed next to it stays readable with loads.
+    /// Returns the symbol table of the executable.
+    pub fn load_elf(&mut self, bytes: &[u8]) -> Result<SymbolTable, ElfError> {
+        let image = ElfImage::parse(bytes)?;
+
+        // Check everything before touching memory, so a bad file leaves the machine untouched
+        for segment in image.segments.iter() {
+            let size = segment.size;
+            if !self
+                .memory()
+                .is_backed(segment.address as usize, size as usize)
+            {
+                return Err(ElfError::ReservedRange {
+                    address: segment.address,
+                    size,
+                });
+            }
+            if segment.executable && self.memory_model() == MemoryModel::Harvard {
+                if !(segment.address as usize).is_multiple_of(self.instruction_alignment()) {
+                    return Err(ElfError::Misaligned(segment.address));
+                }
+                if !self.fits_instruction_memory(segment.address, size as usize) {
+                    return Err(ElfError::ReservedRange {
+                        address: segment.address,
+                        size,
+                    });
+                }
+            }
+        }
+        if !(image.entry as usize).is_multiple_of(self.instruction_alignment()) {
+            return Err(ElfError::Misaligned(image.entry));
+        }
+
+        for segment in image.segments.iter() {
+            self.place(segment.address, &segment.contents(), segment.executable)
+                .map_err(|_| ElfError::Refused {
+                    address: segment.address,
+                    size: segment.size,
+                })?;
+        }
+        self.jump_to(image.entry);
+
+        Ok(image.symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    struct TestSegment {
+        address: u32,
+        data: Vec<u8>,
+        memsz: u32,
+        flags: u32,
+    }
+
+    // Builds a minimal executable with the given segments and a symbol table
+    fn build_elf(entry: u32, segments: &[TestSegment], symbols: &[(&str, u32)]) -> Vec<u8> {
+        let mut strtab = vec![0u8];
+        let mut symtab = vec![0u8; 16];
+        for (name, value) in symbols {
+            let mut symbol = [0u8; 16];
+            LittleEndian::write_u32(&mut symbol[0..], strtab.len() as u32);
+            LittleEndian::write_u32(&mut symbol[4..], *value);
+            symbol[12] = 0x12; // GLOBAL FUNC
+            symtab.extend_from_slice(&symbol);
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+
+        let phoff = 52;
+        let mut data_offset = phoff + 32 * segments.len();
+        let mut elf = vec![0u8; data_offset];
+        elf[..4].copy_from_slice(&[0x7F, b'E', b'L', b'F']);
+        elf[4] = 1;
+        elf[5] = 1;
+        elf[6] = 1;
+        LittleEndian::write_u16(&mut elf[16..], ET_EXEC);
+        LittleEndian::write_u16(&mut elf[18..], EM_RISCV);
+        LittleEndian::write_u32(&mut elf[24..], entry);
+        LittleEndian::write_u32(&mut elf[28..], phoff as u32);
+        LittleEndian::write_u16(&mut elf[42..], 32);
+        LittleEndian::write_u16(&mut elf[44..], segments.len() as u16);
+        LittleEndian::write_u16(&mut elf[46..], 40);
+
+        for (idx, segment) in segments.iter().enumerate() {
+            let ph = phoff + idx * 32;
+            LittleEndian::write_u32(&mut elf[ph..], PT_LOAD);
+            LittleEndian::write_u32(&mut elf[ph + 4..], data_offset as u32);
+            LittleEndian::write_u32(&mut elf[ph + 8..], segment.address);
+            LittleEndian::write_u32(&mut elf[ph + 12..], segment.address);
+            LittleEndian::write_u32(&mut elf[ph + 16..], segment.data.len() as u32);
+            LittleEndian::write_u32(&mut elf[ph + 20..], segment.memsz);
+            LittleEndian::write_u32(&mut elf[ph + 24..], segment.flags);
+            data_offset += segment.data.len();
+        }
+        for segment in segments {
+            elf.extend_from_slice(&segment.data);
+        }
+
+        let symtab_offset = elf.len();
+        elf.extend_from_slice(&symtab);
+        let strtab_offset = elf.len();
+        elf.extend_from_slice(&strtab);
+
+        // Sections: null, .symtab, .strtab
+        let shoff = elf.len();
+        LittleEndian::write_u32(&mut elf[32..], shoff as u32);
+        LittleEndian::write_u16(&mut elf[48..], 3);
+        let mut sections = vec![0u8; 40 * 3];
+        LittleEndian::write_u32(&mut sections[40 + 4..], SHT_SYMTAB);
+        LittleEndian::write_u32(&mut sections[40 + 16..], symtab_offset as u32);
+        LittleEndian::write_u32(&mut sections[40 + 20..], symtab.len() as u32);
+        LittleEndian::write_u32(&mut sections[40 + 24..], 2);
+        LittleEndian::write_u32(&mut sections[80 + 4..], 3);
+        LittleEndian::write_u32(&mut sections[80 + 16..], strtab_offset as u32);
+        LittleEndian::write_u32(&mut sections[80 + 20..], strtab.len() as u32);
+        elf.extend_from_slice(&sections);
+        elf
+    }
+
+    fn to_bytes(words: &[i32]) -> Vec<u8> {
+        let mut bytes = vec![0; words.len() * 4];
+        LittleEndian::write_i32_into(words, &mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn load_segments_and_symbols() {
+        let text = assemble("_start:\n addi a0, zero, 5\n addi a1, zero, 7").unwrap();
+        let elf = build_elf(
+            0x104,
+            &[
+                TestSegment {
+                    address: 0x100,
+                    data: to_bytes(&text),
+                    memsz: 8,
+                    flags: 0b101,
+                },
+                TestSegment {
+                    address: 0x1000,
+                    data: vec![0xEF, 0xBE, 0xAD, 0xDE],
+                    memsz: 8,
+                    flags: 0b110,
+                },
+            ],
+            &[("_start", 0x100), ("value", 0x1000)],
+        );
+
+        let mut pineapple = Pineapple::new();
+        let symbols = pineapple.load_elf(&elf).unwrap();
+        assert_eq!(symbols.address_of("_start"), Some(0x100));
+        assert_eq!(symbols.name_of(0x1000), Some("value"));
+        assert_eq!(pineapple.get_program_counter(), Ok(0x104));
+        assert_eq!(
+            pineapple.get_instruction_range(0x100, 0x108),
+            Ok(text.clone())
+        );
+        assert_eq!(pineapple.get_data_range(0x100, 0x108), Ok(text));
+        assert_eq!(
+            pineapple.get_data_range(0x1000, 0x1008),
+            Ok(vec![0xDEADBEEFu32 as i32, 0])
+        );
+    }
+
+    #[test]
+    fn compressed_entry_points() {
+        let segment = TestSegment {
+            address: 0x100,
+            data: vec![0x01, 0x00, 0x01, 0x00],
+            memsz: 4,
+            flags: 0b101,
+        };
+        let elf = build_elf(0x102, &[segment], &[]);
+        let mut pineapple = Pineapple::new();
+        pineapple.load_elf(&elf).unwrap();
+        assert_eq!(pineapple.get_program_counter(), Ok(0x102));
+
+        // Without the C extension instructions are word aligned
+        pineapple.set_extensions(crate::Extensions::rv32i());
+        assert_eq!(
+            pineapple.load_elf(&elf).err(),
+            Some(ElfError::Misaligned(0x102))
+        );
+    }
+
+    #[test]
+    fn reject_reserved_ranges() {
+        let segment = |address| TestSegment {
+            address,
+            data: vec![0; 4],
+            memsz: 4,
+            flags: 0b110,
+        };
+        let mut pineapple = Pineapple::new();
+        for address in [0x0001FFFE, 0x00020000, 0x40000800, 0x80000010].iter() {
+            let elf = build_elf(0, &[segment(*address)], &[]);
+            assert_eq!(
+                pineapple.load_elf(&elf).err(),
+                Some(ElfError::ReservedRange {
+                    address: *address,
+                    size: 4
+                })
+            );
+        }
+
+        // A huge zero filled tail is rejected without being allocated
+        let huge = TestSegment {
+            memsz: 0xF0000000,
+            ..segment(0)
+        };
+        assert_eq!(
+            pineapple.load_elf(&build_elf(0, &[huge], &[])).err(),
+            Some(ElfError::ReservedRange {
+                address: 0,
+                size: 0xF0000000
+            })
+        );
+
+        // Code has to fit inside the Harvard model's instruction memory
+        let mut pineapple = Pineapple::with_memory_model(MemoryModel::Harvard);
+        let code = TestSegment {
+            flags: 0b101,
+            ..segment(0x02000000)
+        };
+        assert_eq!(
+            pineapple.load_elf(&build_elf(0, &[code], &[])).err(),
+            Some(ElfError::ReservedRange {
+                address: 0x02000000,
+                size: 4
+            })
+        );
+        assert_eq!(
+            pineapple.load_elf(b"\x7FELF").err(),
+            Some(ElfError::Truncated)
+        );
+        assert_eq!(pineapple.load_elf(b"MZ").err(), Some(ElfError::NotElf));
+    }
+}
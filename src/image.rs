@@ -0,0 +1,489 @@
+use std::fmt;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{MemoryModel, Pineapple};
+
+/// Why a memory image could not be loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    /// A record is malformed, `line` is 1 indexed
+    Syntax { line: usize, message: String },
+    /// A record's checksum doesn't match its contents
+    Checksum { line: usize },
+    /// Data doesn't fit inside a single mapped device
+    ReservedRange { address: u32, size: u32 },
+    /// The device data lands on refused to be loaded
+    Refused { address: u32, size: u32 },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Syntax { line, message } => write!(f, "Line {}: {}", line, message),
+            ImageError::Checksum { line } => write!(f, "Line {}: Checksum mismatch", line),
+            ImageError::ReservedRange { address, size } => write!(
+                f,
+                "Data at {:#010x} ({:#x} bytes) isn't backed by a single device",
+                address, size
+            ),
+            ImageError::Refused { address, size } => write!(
+                f,
+                "The device at {:#010x} refused {:#x} bytes of data",
+                address, size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+/// The contents of an Intel HEX or S-record file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Image {
+    /// Runs of contiguous bytes and the address they start at
+    pub chunks: Vec<(u32, Vec<u8>)>,
+    /// Start address record, if the file has one
+    pub entry: Option<u32>,
+}
+
+impl Image {
+    // Adds data, extending the previous chunk when it carries straight on from it
+    fn push(&mut self, address: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        if let Some((start, chunk)) = self.chunks.last_mut() {
+            if start.wrapping_add(chunk.len() as u32) == address {
+                chunk.extend_from_slice(data);
+                return;
+            }
+        }
+        self.chunks.push((address, data.to_vec()));
+    }
+
+    /// Parses an Intel HEX file.
+    pub fn from_ihex(text: &str) -> Result<Image, ImageError> {
+        let mut image = Image::default();
+        let mut base = 0u32;
+        for (idx, line) in text.lines().enumerate() {
+            let line_number = idx + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let record = line
+                .strip_prefix(':')
+                .ok_or_else(|| syntax(line_number, "Record doesn't start with `:`"))?;
+            let bytes = hex_bytes(record, line_number)?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(syntax(
+                    line_number,
+                    "Record length doesn't match its byte count",
+                ));
+            }
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err(ImageError::Checksum { line: line_number });
+            }
+            let offset = ((bytes[1] as u32) << 8) | bytes[2] as u32;
+            let data = &bytes[4..bytes.len() - 1];
+            match bytes[3] {
+                0x00 => image.push(base.wrapping_add(offset), data),
+                0x01 => return Ok(image),
+                0x02 if data.len() == 2 => base = (((data[0] as u32) << 8) | data[1] as u32) << 4,
+                0x03 if data.len() == 4 => {
+                    let segment = ((data[0] as u32) << 8) | data[1] as u32;
+                    let offset = ((data[2] as u32) << 8) | data[3] as u32;
+                    image.entry = Some((segment << 4).wrapping_add(offset));
+                }
+                0x04 if data.len() == 2 => base = (((data[0] as u32) << 8) | data[1] as u32) << 16,
+                0x05 if data.len() == 4 => image.entry = Some(big_endian(data)),
+                kind => {
+                    return Err(syntax(
+                        line_number,
+                        &format!("Invalid record type {:02X}", kind),
+                    ))
+                }
+            }
+        }
+        Err(syntax(text.lines().count(), "Missing end of file record"))
+    }
+
+    /// Parses a Motorola S-record file.
+    pub fn from_srec(text: &str) -> Result<Image, ImageError> {
+        let mut image = Image::default();
+        for (idx, line) in text.lines().enumerate() {
+            let line_number = idx + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.len() < 2 || !line.starts_with('S') {
+                return Err(syntax(line_number, "Record doesn't start with `S`"));
+            }
+            if !line.is_ascii() {
+                return Err(syntax(line_number, "Invalid character in record"));
+            }
+            let kind = line.as_bytes()[1];
+            let bytes = hex_bytes(&line[2..], line_number)?;
+            if bytes.len() < 2 || bytes.len() != bytes[0] as usize + 1 {
+                return Err(syntax(
+                    line_number,
+                    "Record length doesn't match its byte count",
+                ));
+            }
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xFF {
+                return Err(ImageError::Checksum { line: line_number });
+            }
+            let body = &bytes[1..bytes.len() - 1];
+            let address_size = match kind {
+                b'0' | b'1' | b'5' | b'9' => 2,
+                b'2' | b'6' | b'8' => 3,
+                b'3' | b'7' => 4,
+                _ => {
+                    return Err(syntax(
+                        line_number,
+                        &format!("Invalid record type S{}", kind as char),
+                    ))
+                }
+            };
+            if body.len() < address_size {
+                return Err(syntax(line_number, "Record is too short for its address"));
+            }
+            let address = big_endian(&body[..address_size]);
+            match kind {
+                b'1' | b'2' | b'3' => image.push(address, &body[address_size..]),
+                b'7' | b'8' | b'9' => {
+                    image.entry = Some(address);
+                    return Ok(image);
+                }
+                // Header and record counts
+                _ => {}
+            }
+        }
+        Ok(image)
+    }
+}
+
+fn syntax(line: usize, message: &str) -> ImageError {
+    ImageError::Syntax {
+        line,
+        message: message.to_string(),
+    }
+}
+
+fn hex_bytes(text: &str, line: usize) -> Result<Vec<u8>, ImageError> {
+    if !text.len().is_multiple_of(2) {
+        return Err(syntax(line, "Odd number of hex digits"));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|idx| {
+            text.get(idx..idx + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| syntax(line, "Invalid hex digit"))
+        })
+        .collect()
+}
+
+fn big_endian(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0u32, |value, byte| (value << 8) | *byte as u32)
+}
+
+fn record(prefix: &str, bytes: &[u8], checksum: u8) -> String {
+    let mut line = prefix.to_string();
+    for byte in bytes.iter().chain(std::iter::once(&checksum)) {
+        line.push_str(&format!("{:02X}", byte));
+    }
+    line.push('\n');
+    line
+}
+
+fn ihex_record(kind: u8, offset: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8, (offset >> 8) as u8, offset as u8, kind];
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    record(":", &bytes, sum.wrapping_neg())
+}
+
+fn srec_record(kind: char, address: &[u8], data: &[u8]) -> String {
+    let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+    bytes.extend_from_slice(address);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    record(&format!("S{}", kind), &bytes, !sum)
+}
+
+/// Writes `data`, starting at `address`, as an Intel HEX file.
+pub fn to_ihex(address: u32, data: &[u8], entry: Option<u32>) -> String {
+    let mut text = String::new();
+    let mut upper = 0u16;
+    let mut position = 0;
+    while position < data.len() {
+        let current = address.wrapping_add(position as u32);
+        if (current >> 16) as u16 != upper {
+            upper = (current >> 16) as u16;
+            text.push_str(&ihex_record(0x04, 0, &upper.to_be_bytes()));
+        }
+        // Records can't cross into the next 64K page
+        let page_left = 0x10000 - (current & 0xFFFF) as usize;
+        let len = 16.min(data.len() - position).min(page_left);
+        text.push_str(&ihex_record(
+            0x00,
+            current as u16,
+            &data[position..position + len],
+        ));
+        position += len;
+    }
+    if let Some(entry) = entry {
+        text.push_str(&ihex_record(0x05, 0, &entry.to_be_bytes()));
+    }
+    text.push_str(&ihex_record(0x01, 0, &[]));
+    text
+}
+
+/// Writes `data`, starting at `address`, as an S-record file with 32 bit addresses.
+pub fn to_srec(address: u32, data: &[u8], entry: Option<u32>) -> String {
+    let mut text = srec_record('0', &[0, 0], &[]);
+    for (idx, chunk) in data.chunks(16).enumerate() {
+        let current = address.wrapping_add(idx as u32 * 16);
+        text.push_str(&srec_record('3', &current.to_be_bytes(), chunk));
+    }
+    // The termination record is optional, and leaving it out keeps the reader's PC where it is
+    if let Some(entry) = entry {
+        text.push_str(&srec_record('7', &entry.to_be_bytes(), &[]));
+    }
+    text
+}
+
+/// Flattens memory words, as returned by [`Pineapple::get_data_range`], into little endian bytes.
+pub fn words_to_bytes(words: &[i32]) -> Vec<u8> {
+    let mut bytes = vec![0; words.len() * 4];
+    LittleEndian::write_i32_into(words, &mut bytes);
+    bytes
+}
+
+impl Pineapple {
+    /// Loads an Intel HEX file, see [`Pineapple::load_image`].
+    pub fn load_ihex(&mut self, text: &str) -> Result<(), ImageError> {
+        let image = Image::from_ihex(text)?;
+        self.load_image(&image)
+    }
+
+    /// Loads a Motorola S-record file, see [`Pineapple::load_image`].
+    pub fn load_srec(&mut self, text: &str) -> Result<(), ImageError> {
+        let image = Image::from_srec(text)?;
+        self.load_image(&image)
+    }
+
+    /// Loads a raw binary image at `address`, see [`Pineapple::load_image`].
+    pub fn load_binary(&mut self, bytes: &[u8], address: u32) -> Result<(), ImageError> {
+        self.load_image(&Image {
+            chunks: vec![(address, bytes.to_vec())],
+            entry: None,
+        })
+    }
+
+    /// Places an image in memory following the memory map.
+    ///
+    /// Like the board, with the Harvard [`crate::MemoryModel`] anything in an executable region is
+    /// treated as code and is copied into instruction memory as well. If the image has a start
+    /// address the program counter is pointed at it. If a device refuses its data, whatever came
+    /// before it has already been loaded.
+    pub fn load_image(&mut self, image: &Image) -> Result<(), ImageError> {
+        let mut executable = Vec::new();
+        for (address, data) in image.chunks.iter() {
+            let is_code = self.memory_model() == MemoryModel::Harvard
+                && self.memory().is_executable(*address as usize, data.len());
+            if !self.memory().is_backed(*address as usize, data.len())
+                || (is_code && !self.fits_instruction_memory(*address, data.len()))
+            {
+                return Err(ImageError::ReservedRange {
+                    address: *address,
+                    size: data.len() as u32,
+                });
+            }
+            executable.push(is_code);
+        }
+        for ((address, data), is_code) in image.chunks.iter().zip(executable) {
+            self.place(*address, data, is_code)
+                .map_err(|_| ImageError::Refused {
+                    address: *address,
+                    size: data.len() as u32,
+                })?;
+        }
+        if let Some(entry) = image.entry {
+            self.jump_to(entry);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ihex() {
+        let text = "
+:10010000214601360121470136007EFE09D2190140
+:100110002146017E17C20001FF5F16002148011928
+:020000040001F9
+:0400000A01020304E8
+:00000001FF
+";
+        let err = Image::from_ihex(text).err().unwrap();
+        assert_eq!(
+            err,
+            ImageError::Syntax {
+                line: 5,
+                message: "Invalid record type 0A".to_string()
+            }
+        );
+
+        let image = Image::from_ihex(&text.replace(":0400000A01020304E8\n", "")).unwrap();
+        assert_eq!(image.chunks.len(), 1);
+        assert_eq!(image.chunks[0].0, 0x100);
+        assert_eq!(image.chunks[0].1.len(), 32);
+        assert_eq!(image.chunks[0].1[..4], [0x21, 0x46, 0x01, 0x36]);
+
+        let err = Image::from_ihex(":10010000214601360121470136007EFE09D2190141\n:00000001FF");
+        assert_eq!(err, Err(ImageError::Checksum { line: 1 }));
+    }
+
+    #[test]
+    fn parse_srec() {
+        let text = "S00F000068656C6C6F202020202000003C
+S11F00007C0802A6900100049421FFF07C6C1B787C8C23783C6000003863000026
+S5030001FB
+S9030000FC";
+        let image = Image::from_srec(text).unwrap();
+        assert_eq!(image.entry, Some(0));
+        assert_eq!(image.chunks.len(), 1);
+        assert_eq!(image.chunks[0].0, 0);
+        assert_eq!(image.chunks[0].1.len(), 28);
+
+        let err = Image::from_srec("S1137AF00A0A0D0000000000000000000000000062");
+        assert_eq!(err, Err(ImageError::Checksum { line: 1 }));
+
+        for text in ["Sé00", "S1é0"] {
+            let err = Image::from_srec(text).err().unwrap();
+            assert!(matches!(err, ImageError::Syntax { .. }), "{}", err);
+        }
+        assert!(Image::from_ihex(":é00").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..40).collect();
+        for address in [0x0000_0010, 0x4000_07F0, 0x0001_FFF8].iter() {
+            let expected = Image {
+                chunks: vec![(*address, data.clone())],
+                entry: Some(0x80),
+            };
+            assert_eq!(
+                Image::from_ihex(&to_ihex(*address, &data, Some(0x80))),
+                Ok(expected)
+            );
+            let expected = Image {
+                chunks: vec![(*address, data.clone())],
+                entry: Some(0x80),
+            };
+            assert_eq!(
+                Image::from_srec(&to_srec(*address, &data, Some(0x80))),
+                Ok(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn load_and_dump() {
+        let mut pineapple = Pineapple::new();
+        let words = vec![0x00500513, 0x00700593, 0x0BADF00D];
+        let text = to_srec(0x200, &words_to_bytes(&words), Some(0x204));
+        pineapple.load_srec(&text).unwrap();
+        assert_eq!(pineapple.get_program_counter(), Ok(0x204));
+        assert_eq!(
+            pineapple.get_instruction_range(0x200, 0x20C),
+            Ok(words.clone())
+        );
+
+        let dumped = pineapple.get_data_range(0x200, 0x20C).unwrap();
+        assert_eq!(dumped, words);
+        let mut copy = Pineapple::new();
+        copy.load_ihex(&to_ihex(0x200, &words_to_bytes(&dumped), None))
+            .unwrap();
+        assert_eq!(copy.get_data_range(0x200, 0x20C), Ok(words.clone()));
+
+        // Without an entry point the program counter is left alone
+        copy.load_srec(&to_srec(0x200, &words_to_bytes(&words), Some(0x204)))
+            .unwrap();
+        copy.load_srec(&to_srec(0x200, &words_to_bytes(&words), None))
+            .unwrap();
+        assert_eq!(copy.get_program_counter(), Ok(0x204));
+
+        // Unaligned data only touches the bytes it covers
+        pineapple.load_binary(&[0xAA, 0xBB], 0x205).unwrap();
+        assert_eq!(
+            pineapple.get_instruction_range(0x204, 0x208),
+            Ok(vec![0x00BBAA93])
+        );
+
+        assert_eq!(
+            pineapple.load_binary(&[0; 8], 0x1FFFC),
+            Err(ImageError::ReservedRange {
+                address: 0x1FFFC,
+                size: 8
+            })
+        );
+    }
+
+    #[test]
+    fn refused_data_is_an_error() {
+        struct ReadOnly;
+
+        impl crate::bus::Device for ReadOnly {
+            fn read_u8(&self, _offset: u32) -> Result<u8, ()> {
+                Ok(0)
+            }
+
+            fn write_u8(&mut self, _offset: u32, _data: u8) -> Result<(), ()> {
+                Err(())
+            }
+        }
+
+        let mut pineapple = Pineapple::new();
+        pineapple
+            .attach_device(0x30000000, 0x100, Box::new(ReadOnly))
+            .unwrap();
+        assert_eq!(
+            pineapple.load_binary(&[1, 2, 3, 4], 0x30000000),
+            Err(ImageError::Refused {
+                address: 0x30000000,
+                size: 4
+            })
+        );
+    }
+
+    #[test]
+    fn harvard_only_shadows_executable_regions() {
+        let mut pineapple = Pineapple::with_memory_model(MemoryModel::Harvard);
+        pineapple
+            .load_binary(&words_to_bytes(&[0x00500513]), 0x100)
+            .unwrap();
+        // Instruction memory is word addressed
+        assert_eq!(
+            pineapple.get_instruction_range(0x40, 0x41),
+            Ok(vec![0x00500513])
+        );
+
+        // The CLINT sits past the end of instruction memory, but isn't code
+        pineapple.load_binary(&[1, 2, 3, 4], 0x02000000).unwrap();
+        assert_eq!(
+            pineapple.pending_interrupts(),
+            crate::clint::MACHINE_SOFTWARE_INTERRUPT
+        );
+    }
+}
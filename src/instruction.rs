@@ -35,33 +35,130 @@ pub(crate) fn sign_extend(instruction: i32, offset: usize) -> i32 {
     shifted >> (32 - offset)
 }
 
+/// ABI names of the general purpose registers, indexed by register number
+pub const ABI_REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Why a word could not be decoded into an [`Instruction`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    /// The major opcode (bits 6:0) isn't one we know about
+    UnknownOpcode,
+    /// The opcode is known, but funct3 doesn't name an instruction under it
+    UnknownFunct3,
+    /// funct3 is valid, but funct7 doesn't match any instruction
+    UnknownFunct7,
+    /// The encoding is valid RISC-V, but not something this core executes
+    Unsupported,
+    /// A CSR instruction naming a CSR that doesn't exist, or writing a read-only one
+    IllegalCsr,
+}
+
+/// An instruction word that failed to decode, along with the fields the decoder looked at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub word: i32,
+    pub opcode: i32,
+    pub funct3: i32,
+    pub funct7: i32,
+    pub kind: DecodeErrorKind,
+}
+
+impl DecodeError {
+    pub(crate) fn new(word: i32, kind: DecodeErrorKind) -> Self {
+        DecodeError {
+            word,
+            opcode: extract_bits!(word[6;0]),
+            funct3: extract_bits!(word[14;12]),
+            funct7: extract_bits!(word[31;25]),
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.kind {
+            DecodeErrorKind::UnknownOpcode => "unknown opcode",
+            DecodeErrorKind::UnknownFunct3 => "unknown funct3",
+            DecodeErrorKind::UnknownFunct7 => "unknown funct7",
+            DecodeErrorKind::Unsupported => "unsupported instruction",
+            DecodeErrorKind::IllegalCsr => "illegal CSR access",
+        };
+        write!(
+            f,
+            "{} in {:#010x} (opcode {:#09b}, funct3 {:#05b}, funct7 {:#09b})",
+            reason, self.word, self.opcode, self.funct3, self.funct7
+        )
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
 // TODO: Eventually Enum Variants will be their own proper types, when that happens this can be folded into a single enum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct InstructionTypeR {
     pub rs2: usize,
     pub rs1: usize,
     pub rd: usize,
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct InstructionTypeI {
     pub imm: i32,
     pub rs1: usize,
     pub rd: usize,
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct InstructionTypeS {
     pub imm: i32,
     pub rs2: usize,
     pub rs1: usize,
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct InstructionTypeB {
     pub imm: i32,
     pub rs2: usize,
     pub rs1: usize,
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct InstructionTypeU {
     pub imm: i32,
     pub rd: usize,
 }
+/// The atomic memory instructions. `aq` and `rl` ask for acquire and release ordering, which a
+/// single bus with no caches always provides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionTypeAmo {
+    pub rs2: usize,
+    pub rs1: usize,
+    pub rd: usize,
+    pub aq: bool,
+    pub rl: bool,
+}
+impl InstructionTypeAmo {
+    /// The ordering suffix of the mnemonic, such as `.aqrl`.
+    pub fn ordering(&self) -> &'static str {
+        match (self.aq, self.rl) {
+            (false, false) => "",
+            (true, false) => ".aq",
+            (false, true) => ".rl",
+            (true, true) => ".aqrl",
+        }
+    }
+}
+/// The CSR instructions. The immediate forms keep their 5 bit unsigned immediate in `rs1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionTypeCsr {
+    pub csr: u16,
+    pub rs1: usize,
+    pub rd: usize,
+}
 
 #[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     LUI(InstructionTypeU),
     AUIPC(InstructionTypeU),
@@ -100,6 +197,26 @@ pub enum Instruction {
     SRA(InstructionTypeR),
     OR(InstructionTypeR),
     AND(InstructionTypeR),
+    MUL(InstructionTypeR),
+    MULH(InstructionTypeR),
+    MULHSU(InstructionTypeR),
+    MULHU(InstructionTypeR),
+    DIV(InstructionTypeR),
+    DIVU(InstructionTypeR),
+    REM(InstructionTypeR),
+    REMU(InstructionTypeR),
+    /// LR.W, rs2 is always 0
+    LR(InstructionTypeAmo),
+    SC(InstructionTypeAmo),
+    AMOADD(InstructionTypeAmo),
+    AMOSWAP(InstructionTypeAmo),
+    AMOXOR(InstructionTypeAmo),
+    AMOOR(InstructionTypeAmo),
+    AMOAND(InstructionTypeAmo),
+    AMOMIN(InstructionTypeAmo),
+    AMOMAX(InstructionTypeAmo),
+    AMOMINU(InstructionTypeAmo),
+    AMOMAXU(InstructionTypeAmo),
     FENCE {
         fm: i32,
         pred: i32,
@@ -109,6 +226,14 @@ pub enum Instruction {
     },
     ECALL,
     EBREAK,
+    MRET,
+    WFI,
+    CSRRW(InstructionTypeCsr),
+    CSRRS(InstructionTypeCsr),
+    CSRRC(InstructionTypeCsr),
+    CSRRWI(InstructionTypeCsr),
+    CSRRSI(InstructionTypeCsr),
+    CSRRCI(InstructionTypeCsr),
 }
 
 impl fmt::Display for Instruction {
@@ -152,29 +277,102 @@ impl fmt::Display for Instruction {
             Instruction::SRA(i) => write!(f, "SRA x{} x{} x{}", i.rd, i.rs1, i.rs2),
             Instruction::OR(i) => write!(f, "OR x{} x{} x{}", i.rd, i.rs1, i.rs2),
             Instruction::AND(i) => write!(f, "AND x{} x{} x{}", i.rd, i.rs1, i.rs2),
-            Instruction::FENCE {
-                fm,
-                pred,
-                succ,
-                rs1,
-                rd,
-            } => {
-                unimplemented!(
-                    "Unimplemented instruction! {}{}{}{}{}",
-                    fm,
-                    pred,
-                    succ,
-                    rs1,
-                    rd
-                )
+            Instruction::MUL(i) => write!(f, "MUL x{} x{} x{}", i.rd, i.rs1, i.rs2),
+            Instruction::MULH(i) => write!(f, "MULH x{} x{} x{}", i.rd, i.rs1, i.rs2),
+            Instruction::MULHSU(i) => write!(f, "MULHSU x{} x{} x{}", i.rd, i.rs1, i.rs2),
+            Instruction::MULHU(i) => write!(f, "MULHU x{} x{} x{}", i.rd, i.rs1, i.rs2),
+            Instruction::DIV(i) => write!(f, "DIV x{} x{} x{}", i.rd, i.rs1, i.rs2),
+            Instruction::DIVU(i) => write!(f, "DIVU x{} x{} x{}", i.rd, i.rs1, i.rs2),
+            Instruction::REM(i) => write!(f, "REM x{} x{} x{}", i.rd, i.rs1, i.rs2),
+            Instruction::REMU(i) => write!(f, "REMU x{} x{} x{}", i.rd, i.rs1, i.rs2),
+            Instruction::LR(i) => write!(f, "LR.W{} x{} x{}", i.ordering(), i.rd, i.rs1),
+            Instruction::SC(i) => write!(f, "SC.W{} x{} x{} x{}", i.ordering(), i.rd, i.rs1, i.rs2),
+            Instruction::AMOADD(i) => write!(
+                f,
+                "AMOADD.W{} x{} x{} x{}",
+                i.ordering(),
+                i.rd,
+                i.rs1,
+                i.rs2
+            ),
+            Instruction::AMOSWAP(i) => write!(
+                f,
+                "AMOSWAP.W{} x{} x{} x{}",
+                i.ordering(),
+                i.rd,
+                i.rs1,
+                i.rs2
+            ),
+            Instruction::AMOXOR(i) => write!(
+                f,
+                "AMOXOR.W{} x{} x{} x{}",
+                i.ordering(),
+                i.rd,
+                i.rs1,
+                i.rs2
+            ),
+            Instruction::AMOOR(i) => {
+                write!(f, "AMOOR.W{} x{} x{} x{}", i.ordering(), i.rd, i.rs1, i.rs2)
             }
-            Instruction::ECALL => todo!(),
-            Instruction::EBREAK => todo!(),
+            Instruction::AMOAND(i) => write!(
+                f,
+                "AMOAND.W{} x{} x{} x{}",
+                i.ordering(),
+                i.rd,
+                i.rs1,
+                i.rs2
+            ),
+            Instruction::AMOMIN(i) => write!(
+                f,
+                "AMOMIN.W{} x{} x{} x{}",
+                i.ordering(),
+                i.rd,
+                i.rs1,
+                i.rs2
+            ),
+            Instruction::AMOMAX(i) => write!(
+                f,
+                "AMOMAX.W{} x{} x{} x{}",
+                i.ordering(),
+                i.rd,
+                i.rs1,
+                i.rs2
+            ),
+            Instruction::AMOMINU(i) => write!(
+                f,
+                "AMOMINU.W{} x{} x{} x{}",
+                i.ordering(),
+                i.rd,
+                i.rs1,
+                i.rs2
+            ),
+            Instruction::AMOMAXU(i) => write!(
+                f,
+                "AMOMAXU.W{} x{} x{} x{}",
+                i.ordering(),
+                i.rd,
+                i.rs1,
+                i.rs2
+            ),
+            Instruction::FENCE { fm, pred, succ, .. } => {
+                write!(f, "FENCE #{:#x} #{:#x} #{:#x}", fm, pred, succ)
+            }
+            Instruction::ECALL => write!(f, "ECALL"),
+            Instruction::EBREAK => write!(f, "EBREAK"),
+            Instruction::MRET => write!(f, "MRET"),
+            Instruction::WFI => write!(f, "WFI"),
+            Instruction::CSRRW(i) => write!(f, "CSRRW x{} {:#x} x{}", i.rd, i.csr, i.rs1),
+            Instruction::CSRRS(i) => write!(f, "CSRRS x{} {:#x} x{}", i.rd, i.csr, i.rs1),
+            Instruction::CSRRC(i) => write!(f, "CSRRC x{} {:#x} x{}", i.rd, i.csr, i.rs1),
+            Instruction::CSRRWI(i) => write!(f, "CSRRWI x{} {:#x} #{}", i.rd, i.csr, i.rs1),
+            Instruction::CSRRSI(i) => write!(f, "CSRRSI x{} {:#x} #{}", i.rd, i.csr, i.rs1),
+            Instruction::CSRRCI(i) => write!(f, "CSRRCI x{} {:#x} #{}", i.rd, i.csr, i.rs1),
         }
     }
 }
 
 // At the moment I don't actually simulate these
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub(crate) enum MicroSteps {
     AluOp0,     // (alu operation 0)
     MemStore,   // (memory store - RAM)
@@ -204,8 +402,7 @@ enum Immediate {
     S,
     B,
     U,
-    //#[deprecated = "J type Immediate values are depreciated!"]
-    //J,
+    J,
 }
 
 impl Immediate {
@@ -234,7 +431,6 @@ impl Immediate {
                 let inst_19_12 = extract_bits!(data[19;12]);
                 (inst_31 << 31) | (inst_30_20 << 20) | (inst_19_12 << 12)
             }
-            /* 
             Immediate::J => {
                 let inst_31 = extract_bits!(@extend data[31]);
                 let inst_19_12 = extract_bits!(data[19;12]);
@@ -247,7 +443,33 @@ impl Immediate {
                     | (inst_30_25 << 5)
                     | (inst_24_21 << 1)
             }
-            */
+        }
+    }
+
+    // Inverse of from_i32, places the immediate bits where they belong in the instruction word
+    fn to_i32(imm: Immediate, value: i32) -> i32 {
+        match imm {
+            Immediate::I => extract_bits!(value[11;0]) << 20,
+            Immediate::S => {
+                let imm_11_5 = extract_bits!(value[11;5]);
+                let imm_4_0 = extract_bits!(value[4;0]);
+                (imm_11_5 << 25) | (imm_4_0 << 7)
+            }
+            Immediate::B => {
+                let imm_12 = extract_bits!(value[12]);
+                let imm_11 = extract_bits!(value[11]);
+                let imm_10_5 = extract_bits!(value[10;5]);
+                let imm_4_1 = extract_bits!(value[4;1]);
+                (imm_12 << 31) | (imm_10_5 << 25) | (imm_4_1 << 8) | (imm_11 << 7)
+            }
+            Immediate::U => extract_bits!(value[31;12]) << 12,
+            Immediate::J => {
+                let imm_20 = extract_bits!(value[20]);
+                let imm_19_12 = extract_bits!(value[19;12]);
+                let imm_11 = extract_bits!(value[11]);
+                let imm_10_1 = extract_bits!(value[10;1]);
+                (imm_20 << 31) | (imm_10_1 << 21) | (imm_11 << 20) | (imm_19_12 << 12)
+            }
         }
     }
 }
@@ -257,10 +479,20 @@ impl Immediate {
 //}
 
 impl Instruction {
-    // TODO: This could **very** easily be turned into a macro. I'm too lazy to do so.
+    /// Decodes a single instruction word, panicking if it isn't a valid RV32I instruction.
+    ///
+    /// Prefer [`Instruction::try_decode`] for words that come from guest memory.
     pub fn from_i32(data: i32) -> Instruction {
+        match Instruction::try_decode(data) {
+            Ok(instruction) => instruction,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    // TODO: This could **very** easily be turned into a macro. I'm too lazy to do so.
+    pub fn try_decode(data: i32) -> Result<Instruction, DecodeError> {
         let opcode = extract_bits!(data[6;0]);
-        match opcode {
+        let instruction = match opcode {
             0b0110111 => Instruction::LUI(InstructionTypeU {
                 imm: Immediate::from_i32(Immediate::U, data),
                 rd: Instruction::get_rd(data),
@@ -270,14 +502,17 @@ impl Instruction {
                 rd: Instruction::get_rd(data),
             }),
             0b1101111 => Instruction::JAL(InstructionTypeU {
-                imm: Immediate::from_i32(Immediate::U, data),
-                rd: Instruction::get_rd(data),
-            }),
-            0b1100111 => Instruction::JALR(InstructionTypeI {
-                imm: Immediate::from_i32(Immediate::I, data),
-                rs1: Instruction::get_rs1(data),
+                imm: Immediate::from_i32(Immediate::J, data),
                 rd: Instruction::get_rd(data),
             }),
+            0b1100111 => match extract_bits!(data[14;12]) {
+                0b000 => Instruction::JALR(InstructionTypeI {
+                    imm: Immediate::from_i32(Immediate::I, data),
+                    rs1: Instruction::get_rs1(data),
+                    rd: Instruction::get_rd(data),
+                }),
+                _ => return Err(DecodeError::new(data, DecodeErrorKind::UnknownFunct3)),
+            },
 
             0b1100011 => {
                 let secondary_opcode = extract_bits!(data[14;12]);
@@ -312,7 +547,7 @@ impl Instruction {
                         rs1: Instruction::get_rs1(data),
                         rs2: Instruction::get_rs2(data),
                     }),
-                    _ => unimplemented!("Unimplemented Opcode!"),
+                    _ => return Err(DecodeError::new(data, DecodeErrorKind::UnknownFunct3)),
                 }
             }
             0b0000011 => {
@@ -343,7 +578,7 @@ impl Instruction {
                         rs1: Instruction::get_rs1(data),
                         rd: Instruction::get_rd(data),
                     }),
-                    _ => unimplemented!("Unimplemented Opcode!"),
+                    _ => return Err(DecodeError::new(data, DecodeErrorKind::UnknownFunct3)),
                 }
             }
             0b0100011 => {
@@ -364,7 +599,7 @@ impl Instruction {
                         rs1: Instruction::get_rs1(data),
                         rs2: Instruction::get_rs2(data),
                     }),
-                    _ => unimplemented!("Unimplemented Opcode!"),
+                    _ => return Err(DecodeError::new(data, DecodeErrorKind::UnknownFunct3)),
                 }
             }
 
@@ -402,124 +637,288 @@ impl Instruction {
                         rd: Instruction::get_rd(data),
                     }),
                     //Make sure you're getting the front zeros/1 from this point on
-                    0b001 => Instruction::SLLI(InstructionTypeI {
-                        imm: Instruction::get_shamt(data),
-                        rs1: Instruction::get_rs1(data),
-                        rd: Instruction::get_rd(data),
-                    }),
+                    0b001 => match Instruction::get_funct7(data) {
+                        0b0000000 => Instruction::SLLI(InstructionTypeI {
+                            imm: Instruction::get_shamt(data),
+                            rs1: Instruction::get_rs1(data),
+                            rd: Instruction::get_rd(data),
+                        }),
+                        _ => return Err(DecodeError::new(data, DecodeErrorKind::UnknownFunct7)),
+                    },
 
-                    0b101 => {
-                        let arethmetic_shift = extract_bits!(data[31]);
-                        match arethmetic_shift {
-                            0b0 => Instruction::SRLI(InstructionTypeI {
-                                imm: Instruction::get_shamt(data),
-                                rs1: Instruction::get_rs1(data),
-                                rd: Instruction::get_rd(data),
-                            }),
-                            0b1 => Instruction::SRAI(InstructionTypeI {
-                                imm: Instruction::get_shamt(data),
-                                rs1: Instruction::get_rs1(data),
-                                rd: Instruction::get_rd(data),
-                            }),
-                            _ => unreachable!("Impossible Bit!"),
-                        }
-                    }
-                    _ => unimplemented!("Unimplemented Opcode!"),
+                    0b101 => match Instruction::get_funct7(data) {
+                        0b0000000 => Instruction::SRLI(InstructionTypeI {
+                            imm: Instruction::get_shamt(data),
+                            rs1: Instruction::get_rs1(data),
+                            rd: Instruction::get_rd(data),
+                        }),
+                        0b0100000 => Instruction::SRAI(InstructionTypeI {
+                            imm: Instruction::get_shamt(data),
+                            rs1: Instruction::get_rs1(data),
+                            rd: Instruction::get_rd(data),
+                        }),
+                        _ => return Err(DecodeError::new(data, DecodeErrorKind::UnknownFunct7)),
+                    },
+                    _ => return Err(DecodeError::new(data, DecodeErrorKind::UnknownFunct3)),
                 }
             }
             0b0110011 => {
                 let secondary_opcode = extract_bits!(data[14;12]);
-                match secondary_opcode {
-                    0b000 => {
-                        let direction_bit = extract_bits!(data[31]);
-                        match direction_bit {
-                            0b0 => Instruction::ADD(InstructionTypeR {
-                                rs1: Instruction::get_rs1(data),
-                                rs2: Instruction::get_rs2(data),
-                                rd: Instruction::get_rd(data),
-                            }),
-                            0b1 => Instruction::SUB(InstructionTypeR {
-                                rs1: Instruction::get_rs1(data),
-                                rs2: Instruction::get_rs2(data),
-                                rd: Instruction::get_rd(data),
-                            }),
-                            _ => unimplemented!("Unimplemented Opcode!"),
-                        }
-                    }
-                    0b001 => Instruction::SLL(InstructionTypeR {
-                        rs1: Instruction::get_rs1(data),
-                        rs2: Instruction::get_rs2(data),
-                        rd: Instruction::get_rd(data),
-                    }),
-                    0b010 => Instruction::SLT(InstructionTypeR {
-                        rs1: Instruction::get_rs1(data),
-                        rs2: Instruction::get_rs2(data),
-                        rd: Instruction::get_rd(data),
-                    }),
-                    0b011 => Instruction::SLTU(InstructionTypeR {
-                        rs1: Instruction::get_rs1(data),
-                        rs2: Instruction::get_rs2(data),
-                        rd: Instruction::get_rd(data),
-                    }),
-                    0b100 => Instruction::XOR(InstructionTypeR {
-                        rs1: Instruction::get_rs1(data),
-                        rs2: Instruction::get_rs2(data),
-                        rd: Instruction::get_rd(data),
-                    }),
-                    0b101 => {
-                        let direction_bit = extract_bits!(data[31]);
-                        match direction_bit {
-                            0b0 => Instruction::SRL(InstructionTypeR {
-                                rs1: Instruction::get_rs1(data),
-                                rs2: Instruction::get_rs2(data),
-                                rd: Instruction::get_rd(data),
-                            }),
-                            0b1 => Instruction::SRA(InstructionTypeR {
-                                rs1: Instruction::get_rs1(data),
-                                rs2: Instruction::get_rs2(data),
-                                rd: Instruction::get_rd(data),
-                            }),
-                            _ => unimplemented!("Unimplemented Opcode!"),
-                        }
-                    }
-                    0b110 => Instruction::OR(InstructionTypeR {
-                        rs1: Instruction::get_rs1(data),
-                        rs2: Instruction::get_rs2(data),
-                        rd: Instruction::get_rd(data),
-                    }),
-                    0b111 => Instruction::AND(InstructionTypeR {
-                        rs1: Instruction::get_rs1(data),
-                        rs2: Instruction::get_rs2(data),
-                        rd: Instruction::get_rd(data),
-                    }),
-                    _ => unimplemented!("Unimplemented Opcode!"),
+                let r_type = InstructionTypeR {
+                    rs1: Instruction::get_rs1(data),
+                    rs2: Instruction::get_rs2(data),
+                    rd: Instruction::get_rd(data),
+                };
+                match (Instruction::get_funct7(data), secondary_opcode) {
+                    (0b0000000, 0b000) => Instruction::ADD(r_type),
+                    (0b0100000, 0b000) => Instruction::SUB(r_type),
+                    (0b0000000, 0b001) => Instruction::SLL(r_type),
+                    (0b0000000, 0b010) => Instruction::SLT(r_type),
+                    (0b0000000, 0b011) => Instruction::SLTU(r_type),
+                    (0b0000000, 0b100) => Instruction::XOR(r_type),
+                    (0b0000000, 0b101) => Instruction::SRL(r_type),
+                    (0b0100000, 0b101) => Instruction::SRA(r_type),
+                    (0b0000000, 0b110) => Instruction::OR(r_type),
+                    (0b0000000, 0b111) => Instruction::AND(r_type),
+                    (0b0000001, 0b000) => Instruction::MUL(r_type),
+                    (0b0000001, 0b001) => Instruction::MULH(r_type),
+                    (0b0000001, 0b010) => Instruction::MULHSU(r_type),
+                    (0b0000001, 0b011) => Instruction::MULHU(r_type),
+                    (0b0000001, 0b100) => Instruction::DIV(r_type),
+                    (0b0000001, 0b101) => Instruction::DIVU(r_type),
+                    (0b0000001, 0b110) => Instruction::REM(r_type),
+                    (0b0000001, 0b111) => Instruction::REMU(r_type),
+                    _ => return Err(DecodeError::new(data, DecodeErrorKind::UnknownFunct7)),
                 }
             }
 
-            0b0001111 => unimplemented!("FENCE"),
+            0b0001111 => match extract_bits!(data[14;12]) {
+                0b000 => Instruction::FENCE {
+                    fm: extract_bits!(data[31;28]),
+                    pred: extract_bits!(data[27;24]),
+                    succ: extract_bits!(data[23;20]),
+                    rs1: Instruction::get_rs1(data),
+                    rd: Instruction::get_rd(data),
+                },
+                // FENCE.I (Zifencei) isn't part of the base ISA
+                _ => return Err(DecodeError::new(data, DecodeErrorKind::UnknownFunct3)),
+            },
 
+            0b0101111 => {
+                if extract_bits!(data[14;12]) != 0b010 {
+                    return Err(DecodeError::new(data, DecodeErrorKind::UnknownFunct3));
+                }
+                let amo = InstructionTypeAmo {
+                    rs2: Instruction::get_rs2(data),
+                    rs1: Instruction::get_rs1(data),
+                    rd: Instruction::get_rd(data),
+                    aq: extract_bits!(data[26]) != 0,
+                    rl: extract_bits!(data[25]) != 0,
+                };
+                match extract_bits!(data[31;27]) {
+                    0b00010 if amo.rs2 == 0 => Instruction::LR(amo),
+                    0b00010 => return Err(DecodeError::new(data, DecodeErrorKind::Unsupported)),
+                    0b00011 => Instruction::SC(amo),
+                    0b00000 => Instruction::AMOADD(amo),
+                    0b00001 => Instruction::AMOSWAP(amo),
+                    0b00100 => Instruction::AMOXOR(amo),
+                    0b01000 => Instruction::AMOOR(amo),
+                    0b01100 => Instruction::AMOAND(amo),
+                    0b10000 => Instruction::AMOMIN(amo),
+                    0b10100 => Instruction::AMOMAX(amo),
+                    0b11000 => Instruction::AMOMINU(amo),
+                    0b11100 => Instruction::AMOMAXU(amo),
+                    _ => return Err(DecodeError::new(data, DecodeErrorKind::UnknownFunct7)),
+                }
+            }
             0b1110011 => {
-                let call = extract_bits!(data[21]);
-                match call {
-                    0b0 => unimplemented!("ECALL"),
-                    0b1 => unimplemented!("EBREAK"),
-                    _ => unimplemented!("Impossible Bit!"),
+                let csr = InstructionTypeCsr {
+                    csr: extract_bits!(data[31;20]) as u16,
+                    rs1: Instruction::get_rs1(data),
+                    rd: Instruction::get_rd(data),
+                };
+                match extract_bits!(data[14;12]) {
+                    0b000 => match data {
+                        0x00000073 => Instruction::ECALL,
+                        0x00100073 => Instruction::EBREAK,
+                        0x30200073 => Instruction::MRET,
+                        0x10500073 => Instruction::WFI,
+                        _ => return Err(DecodeError::new(data, DecodeErrorKind::Unsupported)),
+                    },
+                    0b001 => Instruction::CSRRW(csr),
+                    0b010 => Instruction::CSRRS(csr),
+                    0b011 => Instruction::CSRRC(csr),
+                    0b101 => Instruction::CSRRWI(csr),
+                    0b110 => Instruction::CSRRSI(csr),
+                    0b111 => Instruction::CSRRCI(csr),
+                    _ => return Err(DecodeError::new(data, DecodeErrorKind::UnknownFunct3)),
                 }
             }
-            _ => unimplemented!("Unimplemented Opcode!"),
+            _ => return Err(DecodeError::new(data, DecodeErrorKind::UnknownOpcode)),
+        };
+        Ok(instruction)
+    }
+
+    /// Encodes the instruction back into its 32 bit machine word.
+    pub fn to_i32(&self) -> i32 {
+        match self {
+            Instruction::LUI(i) => Instruction::encode_u(0b0110111, Immediate::U, i),
+            Instruction::AUIPC(i) => Instruction::encode_u(0b0010111, Immediate::U, i),
+            Instruction::JAL(i) => Instruction::encode_u(0b1101111, Immediate::J, i),
+            Instruction::JALR(i) => Instruction::encode_i(0b1100111, 0b000, i),
+            Instruction::BEQ(i) => Instruction::encode_b(0b000, i),
+            Instruction::BNE(i) => Instruction::encode_b(0b001, i),
+            Instruction::BLT(i) => Instruction::encode_b(0b100, i),
+            Instruction::BGE(i) => Instruction::encode_b(0b101, i),
+            Instruction::BLTU(i) => Instruction::encode_b(0b110, i),
+            Instruction::BGEU(i) => Instruction::encode_b(0b111, i),
+            Instruction::LB(i) => Instruction::encode_i(0b0000011, 0b000, i),
+            Instruction::LH(i) => Instruction::encode_i(0b0000011, 0b001, i),
+            Instruction::LW(i) => Instruction::encode_i(0b0000011, 0b010, i),
+            Instruction::LBU(i) => Instruction::encode_i(0b0000011, 0b100, i),
+            Instruction::LHU(i) => Instruction::encode_i(0b0000011, 0b101, i),
+            Instruction::SB(i) => Instruction::encode_s(0b000, i),
+            Instruction::SH(i) => Instruction::encode_s(0b001, i),
+            Instruction::SW(i) => Instruction::encode_s(0b010, i),
+            Instruction::ADDI(i) => Instruction::encode_i(0b0010011, 0b000, i),
+            Instruction::SLTI(i) => Instruction::encode_i(0b0010011, 0b010, i),
+            Instruction::SLTIU(i) => Instruction::encode_i(0b0010011, 0b011, i),
+            Instruction::XORI(i) => Instruction::encode_i(0b0010011, 0b100, i),
+            Instruction::ORI(i) => Instruction::encode_i(0b0010011, 0b110, i),
+            Instruction::ANDI(i) => Instruction::encode_i(0b0010011, 0b111, i),
+            Instruction::SLLI(i) => Instruction::encode_shift(0b001, 0b0000000, i),
+            Instruction::SRLI(i) => Instruction::encode_shift(0b101, 0b0000000, i),
+            Instruction::SRAI(i) => Instruction::encode_shift(0b101, 0b0100000, i),
+            Instruction::ADD(i) => Instruction::encode_r(0b000, 0b0000000, i),
+            Instruction::SUB(i) => Instruction::encode_r(0b000, 0b0100000, i),
+            Instruction::SLL(i) => Instruction::encode_r(0b001, 0b0000000, i),
+            Instruction::SLT(i) => Instruction::encode_r(0b010, 0b0000000, i),
+            Instruction::SLTU(i) => Instruction::encode_r(0b011, 0b0000000, i),
+            Instruction::XOR(i) => Instruction::encode_r(0b100, 0b0000000, i),
+            Instruction::SRL(i) => Instruction::encode_r(0b101, 0b0000000, i),
+            Instruction::SRA(i) => Instruction::encode_r(0b101, 0b0100000, i),
+            Instruction::OR(i) => Instruction::encode_r(0b110, 0b0000000, i),
+            Instruction::AND(i) => Instruction::encode_r(0b111, 0b0000000, i),
+            Instruction::MUL(i) => Instruction::encode_r(0b000, 0b0000001, i),
+            Instruction::MULH(i) => Instruction::encode_r(0b001, 0b0000001, i),
+            Instruction::MULHSU(i) => Instruction::encode_r(0b010, 0b0000001, i),
+            Instruction::MULHU(i) => Instruction::encode_r(0b011, 0b0000001, i),
+            Instruction::DIV(i) => Instruction::encode_r(0b100, 0b0000001, i),
+            Instruction::DIVU(i) => Instruction::encode_r(0b101, 0b0000001, i),
+            Instruction::REM(i) => Instruction::encode_r(0b110, 0b0000001, i),
+            Instruction::REMU(i) => Instruction::encode_r(0b111, 0b0000001, i),
+            Instruction::LR(i) => Instruction::encode_amo(0b00010, i),
+            Instruction::SC(i) => Instruction::encode_amo(0b00011, i),
+            Instruction::AMOADD(i) => Instruction::encode_amo(0b00000, i),
+            Instruction::AMOSWAP(i) => Instruction::encode_amo(0b00001, i),
+            Instruction::AMOXOR(i) => Instruction::encode_amo(0b00100, i),
+            Instruction::AMOOR(i) => Instruction::encode_amo(0b01000, i),
+            Instruction::AMOAND(i) => Instruction::encode_amo(0b01100, i),
+            Instruction::AMOMIN(i) => Instruction::encode_amo(0b10000, i),
+            Instruction::AMOMAX(i) => Instruction::encode_amo(0b10100, i),
+            Instruction::AMOMINU(i) => Instruction::encode_amo(0b11000, i),
+            Instruction::AMOMAXU(i) => Instruction::encode_amo(0b11100, i),
+            Instruction::FENCE {
+                fm,
+                pred,
+                succ,
+                rs1,
+                rd,
+            } => {
+                (extract_bits!(fm[3;0]) << 28)
+                    | (extract_bits!(pred[3;0]) << 24)
+                    | (extract_bits!(succ[3;0]) << 20)
+                    | Instruction::encode_registers(*rd, *rs1, 0)
+                    | 0b0001111
+            }
+            Instruction::ECALL => 0b1110011,
+            Instruction::EBREAK => (1 << 20) | 0b1110011,
+            Instruction::MRET => 0x30200073,
+            Instruction::WFI => 0x10500073,
+            Instruction::CSRRW(i) => Instruction::encode_csr(0b001, i),
+            Instruction::CSRRS(i) => Instruction::encode_csr(0b010, i),
+            Instruction::CSRRC(i) => Instruction::encode_csr(0b011, i),
+            Instruction::CSRRWI(i) => Instruction::encode_csr(0b101, i),
+            Instruction::CSRRSI(i) => Instruction::encode_csr(0b110, i),
+            Instruction::CSRRCI(i) => Instruction::encode_csr(0b111, i),
         }
     }
 
+    fn encode_registers(rd: usize, rs1: usize, rs2: usize) -> i32 {
+        ((rs2 as i32 & 0x1F) << 20) | ((rs1 as i32 & 0x1F) << 15) | ((rd as i32 & 0x1F) << 7)
+    }
+
+    fn encode_r(funct3: i32, funct7: i32, i: &InstructionTypeR) -> i32 {
+        (funct7 << 25)
+            | (funct3 << 12)
+            | Instruction::encode_registers(i.rd, i.rs1, i.rs2)
+            | 0b0110011
+    }
+
+    fn encode_i(opcode: i32, funct3: i32, i: &InstructionTypeI) -> i32 {
+        Immediate::to_i32(Immediate::I, i.imm)
+            | (funct3 << 12)
+            | Instruction::encode_registers(i.rd, i.rs1, 0)
+            | opcode
+    }
+
+    fn encode_amo(funct5: i32, i: &InstructionTypeAmo) -> i32 {
+        (funct5 << 27)
+            | ((i.aq as i32) << 26)
+            | ((i.rl as i32) << 25)
+            | (0b010 << 12)
+            | Instruction::encode_registers(i.rd, i.rs1, i.rs2)
+            | 0b0101111
+    }
+
+    fn encode_csr(funct3: i32, i: &InstructionTypeCsr) -> i32 {
+        ((i.csr as i32 & 0xFFF) << 20)
+            | (funct3 << 12)
+            | Instruction::encode_registers(i.rd, i.rs1, 0)
+            | 0b1110011
+    }
+
+    fn encode_shift(funct3: i32, funct7: i32, i: &InstructionTypeI) -> i32 {
+        let shamt = i.imm;
+        (funct7 << 25)
+            | (extract_bits!(shamt[4;0]) << 20)
+            | (funct3 << 12)
+            | Instruction::encode_registers(i.rd, i.rs1, 0)
+            | 0b0010011
+    }
+
+    fn encode_s(funct3: i32, i: &InstructionTypeS) -> i32 {
+        Immediate::to_i32(Immediate::S, i.imm)
+            | (funct3 << 12)
+            | Instruction::encode_registers(0, i.rs1, i.rs2)
+            | 0b0100011
+    }
+
+    fn encode_b(funct3: i32, i: &InstructionTypeB) -> i32 {
+        Immediate::to_i32(Immediate::B, i.imm)
+            | (funct3 << 12)
+            | Instruction::encode_registers(0, i.rs1, i.rs2)
+            | 0b1100011
+    }
+
+    fn encode_u(opcode: i32, imm: Immediate, i: &InstructionTypeU) -> i32 {
+        Immediate::to_i32(imm, i.imm) | Instruction::encode_registers(i.rd, 0, 0) | opcode
+    }
+
     fn get_rs1(data: i32) -> usize {
-        extract_bits!(data[20;16]) as usize
+        extract_bits!(data[19;15]) as usize
     }
 
     fn get_rs2(data: i32) -> usize {
-        extract_bits!(data[20;16]) as usize
+        extract_bits!(data[24;20]) as usize
     }
 
     fn get_rd(data: i32) -> usize {
-        extract_bits!(data[12;8]) as usize
+        extract_bits!(data[11;7]) as usize
+    }
+
+    fn get_funct7(data: i32) -> i32 {
+        extract_bits!(data[31;25])
     }
 
     fn get_shamt(data: i32) -> i32 {
@@ -529,6 +928,7 @@ impl Instruction {
 
 #[cfg(test)]
 #[allow(overflowing_literals)] // Needed because 0b1(x){31} is "overflowing"
+#[allow(clippy::unusual_byte_groupings)] // Literals are grouped by instruction field
 mod tests {
     use super::*;
 
@@ -597,7 +997,7 @@ mod tests {
         let imm_11 = 0b0_000000_00000_00000_000_0000_1_0000000;
         let opcode = 0b0_000000_00000_00000_000_0000_0_1111111;
 
-        let non_imm = rs2 as i32 | rs1 as i32 | funct3 | opcode;
+        let non_imm = rs2 | rs1 | funct3 | opcode;
         assert_eq!(Immediate::from_i32(Immediate::B, non_imm), 0);
 
         assert_eq!(
@@ -624,4 +1024,237 @@ mod tests {
             (inst_31 | inst_30_20 | inst_19_12)
         )
     }
+
+    #[test]
+    fn decode_register_fields() {
+        // add x3, x1, x2
+        match Instruction::try_decode(0x002081b3) {
+            Ok(Instruction::ADD(i)) => assert_eq!((i.rd, i.rs1, i.rs2), (3, 1, 2)),
+            _ => panic!("Expected ADD"),
+        }
+        // sub x5, x6, x7
+        match Instruction::try_decode(0x407302b3) {
+            Ok(Instruction::SUB(i)) => assert_eq!((i.rd, i.rs1, i.rs2), (5, 6, 7)),
+            _ => panic!("Expected SUB"),
+        }
+    }
+
+    #[test]
+    fn decode_errors() {
+        let err = Instruction::try_decode(0x7f).err().unwrap();
+        assert_eq!(err.kind, DecodeErrorKind::UnknownOpcode);
+        assert_eq!(err.opcode, 0x7f);
+
+        // Branch with funct3 = 0b010
+        let err = Instruction::try_decode(0x0000_2063).err().unwrap();
+        assert_eq!(err.kind, DecodeErrorKind::UnknownFunct3);
+        assert_eq!(err.funct3, 0b010);
+
+        // add with funct7 = 0b0000010
+        let err = Instruction::try_decode(0x042081b3).err().unwrap();
+        assert_eq!(err.kind, DecodeErrorKind::UnknownFunct7);
+        assert_eq!(err.funct7, 0b0000010);
+
+        // sret
+        let err = Instruction::try_decode(0x10200073).err().unwrap();
+        assert_eq!(err.kind, DecodeErrorKind::Unsupported);
+
+        // SYSTEM with funct3 = 0b100
+        let err = Instruction::try_decode(0x34004073).err().unwrap();
+        assert_eq!(err.kind, DecodeErrorKind::UnknownFunct3);
+    }
+
+    #[test]
+    fn immediate_j() {
+        // jal x0, -2 (every immediate bit set)
+        let imm_20_1 = 0b1_1111111111_1_11111111_00000_0000000;
+        assert_eq!(Immediate::from_i32(Immediate::J, imm_20_1), -2);
+        assert_eq!(Immediate::to_i32(Immediate::J, -2), imm_20_1);
+
+        // jal ra, 8
+        assert_eq!(Immediate::from_i32(Immediate::J, 0x008000ef), 8);
+    }
+
+    #[test]
+    fn encode_known_words() {
+        let addi = Instruction::ADDI(InstructionTypeI {
+            imm: 1,
+            rs1: 0,
+            rd: 1,
+        });
+        assert_eq!(addi.to_i32(), 0x00100093);
+        let sw = Instruction::SW(InstructionTypeS {
+            imm: 8,
+            rs1: 1,
+            rs2: 2,
+        });
+        assert_eq!(sw.to_i32(), 0x0020a423);
+        let lui = Instruction::LUI(InstructionTypeU {
+            imm: 0x12345000,
+            rd: 5,
+        });
+        assert_eq!(lui.to_i32(), 0x123452b7);
+        let jal = Instruction::JAL(InstructionTypeU { imm: 8, rd: 1 });
+        assert_eq!(jal.to_i32(), 0x008000ef);
+        // beq x1, x2, -4
+        let beq = Instruction::BEQ(InstructionTypeB {
+            imm: -4,
+            rs1: 1,
+            rs2: 2,
+        });
+        assert_eq!(beq.to_i32(), 0xfe208ee3);
+    }
+
+    #[test]
+    fn decode_encode_round_trip() {
+        let r = InstructionTypeR {
+            rd: 31,
+            rs1: 7,
+            rs2: 19,
+        };
+        let i = InstructionTypeI {
+            imm: -2048,
+            rs1: 12,
+            rd: 1,
+        };
+        let shift = InstructionTypeI {
+            imm: 31,
+            rs1: 30,
+            rd: 2,
+        };
+        let s = InstructionTypeS {
+            imm: 2047,
+            rs1: 2,
+            rs2: 17,
+        };
+        let b = InstructionTypeB {
+            imm: -4096,
+            rs1: 9,
+            rs2: 10,
+        };
+        let u = InstructionTypeU {
+            imm: 0x7FFF_F000,
+            rd: 4,
+        };
+        let j = InstructionTypeU {
+            imm: -1_048_576,
+            rd: 1,
+        };
+        let csr = InstructionTypeCsr {
+            csr: 0xFFF,
+            rs1: 5,
+            rd: 31,
+        };
+        let amo = InstructionTypeAmo {
+            rs2: 3,
+            rs1: 30,
+            rd: 12,
+            aq: true,
+            rl: false,
+        };
+        let instructions = [
+            Instruction::LUI(u),
+            Instruction::AUIPC(u),
+            Instruction::JAL(j),
+            Instruction::JAL(InstructionTypeU {
+                imm: 1_048_574,
+                rd: 0,
+            }),
+            Instruction::JALR(i),
+            Instruction::BEQ(b),
+            Instruction::BNE(b),
+            Instruction::BLT(b),
+            Instruction::BGE(InstructionTypeB {
+                imm: 4094,
+                rs1: 0,
+                rs2: 31,
+            }),
+            Instruction::BLTU(b),
+            Instruction::BGEU(b),
+            Instruction::LB(i),
+            Instruction::LH(i),
+            Instruction::LW(i),
+            Instruction::LBU(i),
+            Instruction::LHU(i),
+            Instruction::SB(s),
+            Instruction::SH(s),
+            Instruction::SW(InstructionTypeS {
+                imm: -1,
+                rs1: 31,
+                rs2: 0,
+            }),
+            Instruction::ADDI(i),
+            Instruction::SLTI(i),
+            Instruction::SLTIU(i),
+            Instruction::XORI(i),
+            Instruction::ORI(i),
+            Instruction::ANDI(InstructionTypeI {
+                imm: 2047,
+                rs1: 3,
+                rd: 3,
+            }),
+            Instruction::SLLI(shift),
+            Instruction::SRLI(shift),
+            Instruction::SRAI(shift),
+            Instruction::ADD(r),
+            Instruction::SUB(r),
+            Instruction::SLL(r),
+            Instruction::SLT(r),
+            Instruction::SLTU(r),
+            Instruction::XOR(r),
+            Instruction::SRL(r),
+            Instruction::SRA(r),
+            Instruction::OR(r),
+            Instruction::AND(r),
+            Instruction::MUL(r),
+            Instruction::MULH(r),
+            Instruction::MULHSU(r),
+            Instruction::MULHU(r),
+            Instruction::DIV(r),
+            Instruction::DIVU(r),
+            Instruction::REM(r),
+            Instruction::REMU(r),
+            Instruction::LR(InstructionTypeAmo { rs2: 0, ..amo }),
+            Instruction::SC(amo),
+            Instruction::AMOADD(amo),
+            Instruction::AMOSWAP(amo),
+            Instruction::AMOXOR(amo),
+            Instruction::AMOOR(amo),
+            Instruction::AMOAND(amo),
+            Instruction::AMOMIN(amo),
+            Instruction::AMOMAX(amo),
+            Instruction::AMOMINU(amo),
+            Instruction::AMOMAXU(amo),
+            Instruction::FENCE {
+                fm: 0b1000,
+                pred: 0b0011,
+                succ: 0b0011,
+                rs1: 0,
+                rd: 0,
+            },
+            Instruction::ECALL,
+            Instruction::EBREAK,
+            Instruction::MRET,
+            Instruction::WFI,
+            Instruction::CSRRW(csr),
+            Instruction::CSRRS(csr),
+            Instruction::CSRRC(csr),
+            Instruction::CSRRWI(InstructionTypeCsr {
+                csr: 0x340,
+                rs1: 31,
+                rd: 0,
+            }),
+            Instruction::CSRRSI(csr),
+            Instruction::CSRRCI(csr),
+        ];
+        for instruction in instructions.iter() {
+            let word = instruction.to_i32();
+            assert_eq!(
+                Instruction::try_decode(word).as_ref(),
+                Ok(instruction),
+                "{:#010x}",
+                word
+            );
+        }
+    }
 }
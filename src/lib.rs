@@ -1,18 +1,251 @@
-use std::sync::RwLock;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+};
 
-use instruction::Instruction;
+pub use instruction::{DecodeError, DecodeErrorKind, Instruction};
+pub use memory::MisalignedAccess;
 #[macro_use]
-mod instruction;
+pub mod instruction;
+pub mod assembler;
+pub mod bus;
+pub mod clint;
+pub mod compressed;
+pub mod config;
+pub mod console;
+pub mod csr;
+pub mod disassembler;
+pub mod elf;
+pub mod font;
+pub mod image;
 mod memory;
+pub mod newlib;
 mod process;
+pub mod semihosting;
+pub mod special_registry;
+pub mod symbols;
+pub mod syscall;
+pub mod trap;
+pub mod uart;
+pub mod video;
+
+/// What a successful [`Pineapple::step`] did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// The instruction ran to completion
+    Executed(Instruction),
+    /// A trap was taken instead, with this mcause, and the program counter is on its handler
+    Trapped(u32),
+}
+
+/// Why [`Pineapple::step`] could not execute the instruction at the program counter.
+///
+/// The program counter is left pointing at the offending instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepError {
+    IllegalInstruction(DecodeError),
+    /// EBREAK was executed, other than as a semihosting call that a handler serviced
+    Breakpoint,
+    /// ECALL was executed without a [`syscall::SyscallHandler`] registered
+    EnvironmentCall,
+    /// The syscall or semihosting handler asked for the program to stop
+    Exit(i32),
+    /// The syscall or semihosting handler couldn't service a call
+    SyscallFault(String),
+    /// The program counter isn't aligned to an instruction boundary
+    InstructionMisaligned(u32),
+    /// Nothing executable is mapped at the program counter
+    InstructionAccessFault(u32),
+    /// A load wasn't aligned to its width under [`MisalignedAccess::Trap`]
+    LoadMisaligned(u32),
+    /// A store wasn't aligned to its width under [`MisalignedAccess::Trap`]
+    StoreMisaligned(u32),
+    /// Nothing readable is mapped at the address of a load, or the device refused it
+    LoadAccessFault(u32),
+    /// Nothing writable is mapped at the address of a store, or the device refused it. Bytes of a
+    /// misaligned store that landed before the fault stay written
+    StoreAccessFault(u32),
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StepError::IllegalInstruction(e) => write!(f, "Illegal instruction: {}", e),
+            StepError::Breakpoint => write!(f, "Breakpoint"),
+            StepError::EnvironmentCall => write!(f, "Unhandled environment call"),
+            StepError::Exit(code) => write!(f, "Program exited with code {}", code),
+            StepError::SyscallFault(reason) => write!(f, "Environment call failed: {}", reason),
+            StepError::InstructionMisaligned(pc) => {
+                write!(f, "Misaligned instruction fetch at {:#010x}", pc)
+            }
+            StepError::InstructionAccessFault(pc) => {
+                write!(f, "Instruction fetch from unmapped address {:#010x}", pc)
+            }
+            StepError::LoadMisaligned(address) => {
+                write!(f, "Misaligned load from {:#010x}", address)
+            }
+            StepError::StoreMisaligned(address) => {
+                write!(f, "Misaligned store to {:#010x}", address)
+            }
+            StepError::LoadAccessFault(address) => {
+                write!(f, "Load access fault at {:#010x}", address)
+            }
+            StepError::StoreAccessFault(address) => {
+                write!(f, "Store access fault at {:#010x}", address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StepError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StepError::IllegalInstruction(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Where instructions are fetched from, and what the program counter counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryModel {
+    /// The program counter is a byte address and instructions are fetched through the same memory
+    /// map as loads and stores, like every RISC-V toolchain expects.
+    #[default]
+    Unified,
+    /// Instructions live in their own word indexed memory, as in the hardware model. The program
+    /// counter and link registers count words, and the byte offsets encoded in branches and jumps
+    /// are scaled down to match.
+    Harvard,
+}
+
+impl MemoryModel {
+    // How far the program counter moves for one instruction
+    fn instruction_size(self) -> usize {
+        match self {
+            MemoryModel::Unified => 4,
+            MemoryModel::Harvard => 1,
+        }
+    }
+
+    // Applies a byte offset from an instruction to a code address
+    fn jump_target(self, base: usize, offset: i32) -> usize {
+        match self {
+            MemoryModel::Unified => (base as u32).wrapping_add(offset as u32) as usize & !1,
+            MemoryModel::Harvard => (base as u32).wrapping_add((offset >> 2) as u32) as usize,
+        }
+    }
+
+    // Converts a byte address, such as an entry point, into a code address
+    fn code_address(self, address: u32) -> usize {
+        match self {
+            MemoryModel::Unified => address as usize,
+            MemoryModel::Harvard => address as usize / 4,
+        }
+    }
+}
+
+/// The standard extensions a [`Pineapple`] executes on top of RV32I.
+///
+/// Instructions from an extension that's switched off still decode, but executing them raises an
+/// illegal instruction exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extensions {
+    /// M: integer multiplication and division
+    pub m: bool,
+    /// A: atomic memory operations
+    pub a: bool,
+    /// C: 16 bit compressed instructions
+    pub c: bool,
+}
+
+impl Default for Extensions {
+    /// Everything the simulator implements, as most toolchains expect.
+    fn default() -> Self {
+        Extensions {
+            m: true,
+            a: true,
+            c: true,
+        }
+    }
+}
+
+impl Extensions {
+    /// Plain RV32I, like the Pineapple hardware.
+    pub fn rv32i() -> Self {
+        Extensions {
+            m: false,
+            a: false,
+            c: false,
+        }
+    }
+
+    /// The value of the misa CSR.
+    pub fn misa(&self) -> u32 {
+        let mut misa = csr::MISA_RV32I;
+        if self.m {
+            misa |= 1 << 12;
+        }
+        if self.a {
+            misa |= 1;
+        }
+        if self.c {
+            misa |= 1 << 2;
+        }
+        misa
+    }
+
+    fn allows(&self, instruction: &Instruction) -> bool {
+        match instruction {
+            Instruction::MUL(_)
+            | Instruction::MULH(_)
+            | Instruction::MULHSU(_)
+            | Instruction::MULHU(_)
+            | Instruction::DIV(_)
+            | Instruction::DIVU(_)
+            | Instruction::REM(_)
+            | Instruction::REMU(_) => self.m,
+            Instruction::LR(_)
+            | Instruction::SC(_)
+            | Instruction::AMOSWAP(_)
+            | Instruction::AMOADD(_)
+            | Instruction::AMOXOR(_)
+            | Instruction::AMOAND(_)
+            | Instruction::AMOOR(_)
+            | Instruction::AMOMIN(_)
+            | Instruction::AMOMAX(_)
+            | Instruction::AMOMINU(_)
+            | Instruction::AMOMAXU(_) => self.a,
+            _ => true,
+        }
+    }
+}
+
+// Bytes of instruction memory in the Harvard model, starting at address 0
+pub(crate) const INSTRUCTION_MEMORY_SIZE: usize = 0x200000;
 
 pub struct Pineapple {
     // For RISCV general_register[0] always equals 0
     // The PC is always XLEN-1
     general_register: RwLock<Vec<i32>>,
     program_counter: RwLock<usize>,
-    instruction_memory: RwLock<Vec<i32>>,
-    data_memory: memory::MemorySystem,
+    memory_model: MemoryModel,
+    // Only used by the Harvard model
+    instruction_memory: Arc<RwLock<Vec<i32>>>,
+    // Shared with the other harts on the same bus
+    data_memory: Arc<Mutex<memory::MemorySystem>>,
+    hart_id: usize,
+    syscall_handler: Option<Box<dyn syscall::SyscallHandler + Send>>,
+    semihosting_handler: Option<Box<dyn semihosting::SemihostingHandler + Send>>,
+    special_registry: Arc<special_registry::RegistryState>,
+    clint: Arc<clint::ClintState>,
+    trap_handling: trap::TrapHandling,
+    traps: trap::TrapRegisters,
+    instret: u64,
+    extensions: Extensions,
+    misaligned: memory::MisalignedPolicy,
 }
 impl Default for Pineapple {
     fn default() -> Self {
@@ -20,51 +253,189 @@ impl Default for Pineapple {
     }
 }
 
+#[allow(clippy::result_unit_err)]
 impl Pineapple {
     pub fn new() -> Self {
+        Self::with_memory_model(MemoryModel::Unified)
+    }
+
+    pub fn with_memory_model(memory_model: MemoryModel) -> Self {
+        let config = config::MachineConfig {
+            memory_model,
+            ..Default::default()
+        };
+        Self::from_config(&config, HashMap::new()).expect("The default memory map is valid")
+    }
+
+    pub(crate) fn with_memory_system(
+        memory_model: MemoryModel,
+        data_memory: memory::MemorySystem,
+        builtin: config::BuiltinDevices,
+    ) -> Self {
+        let instruction_memory = match memory_model {
+            MemoryModel::Unified => Vec::new(),
+            // 0x13 is NOOP
+            MemoryModel::Harvard => vec![0x13; INSTRUCTION_MEMORY_SIZE / 4],
+        };
         Pineapple {
             program_counter: RwLock::new(0),
-            // 0x13 is NOOP
-            instruction_memory: RwLock::new(vec![0x13; 524_288]),
+            memory_model,
+            instruction_memory: Arc::new(RwLock::new(instruction_memory)),
             general_register: RwLock::new(vec![0; 32]),
-            data_memory: memory::MemorySystem::new(),
+            data_memory: Arc::new(Mutex::new(data_memory)),
+            hart_id: 0,
+            syscall_handler: None,
+            semihosting_handler: None,
+            special_registry: builtin.registry,
+            clint: builtin.clint,
+            trap_handling: trap::TrapHandling::default(),
+            traps: trap::TrapRegisters::default(),
+            instret: 0,
+            extensions: Extensions::default(),
+            misaligned: memory::MisalignedPolicy::default(),
         }
     }
 
+    pub(crate) fn memory(&self) -> MutexGuard<'_, memory::MemorySystem> {
+        let memory = self.data_memory.lock().unwrap();
+        self.special_registry.serve(self.hart_id);
+        memory
+    }
+
+    /// Adds another hart to the machine, sharing this one's bus and everything on it.
+    ///
+    /// The new hart starts at address 0 with cleared registers, the next free mhartid and the same
+    /// memory model, extensions, misaligned access policy and trap handling, but no syscall or
+    /// semihosting handler. Each hart is stepped on its own, from one thread or several.
+    ///
+    /// Harts share the CLINT, which has an msip and mtimecmp register for each of them and
+    /// advances mtime once per round of instructions from every hart. They also share the
+    /// Special Registry, whose cycle counter advances with the instructions of every hart. A
+    /// write to its halt register only stops the hart that made it.
+    pub fn add_hart(&self) -> Pineapple {
+        self.clint.add_hart();
+        Pineapple {
+            program_counter: RwLock::new(0),
+            memory_model: self.memory_model,
+            instruction_memory: self.instruction_memory.clone(),
+            general_register: RwLock::new(vec![0; 32]),
+            data_memory: self.data_memory.clone(),
+            hart_id: self.memory().add_hart(),
+            syscall_handler: None,
+            semihosting_handler: None,
+            special_registry: self.special_registry.clone(),
+            clint: self.clint.clone(),
+            trap_handling: self.trap_handling,
+            traps: trap::TrapRegisters::default(),
+            instret: 0,
+            extensions: self.extensions,
+            misaligned: memory::MisalignedPolicy {
+                access: self.misaligned.access,
+                count: 0,
+            },
+        }
+    }
+
+    /// This hart's mhartid.
+    pub fn hart_id(&self) -> usize {
+        self.hart_id
+    }
+
     pub fn get_video_memory(&self) -> Result<Vec<i32>, ()> {
-        // Lazy
-        self.data_memory.dump_memory_range(0x40000000, 0x400007FF)
+        let (base, size) = self.memory().video_memory().ok_or(())?;
+        self.memory()
+            .dump_memory_range(base as usize, base as usize + size as usize)
+    }
+
+    pub fn memory_model(&self) -> MemoryModel {
+        self.memory_model
     }
 
+    pub fn extensions(&self) -> Extensions {
+        self.extensions
+    }
+
+    /// Chooses which extensions are executed, and reported in misa.
+    pub fn set_extensions(&mut self, extensions: Extensions) {
+        self.extensions = extensions;
+    }
+
+    pub fn misaligned_access(&self) -> MisalignedAccess {
+        self.misaligned.access
+    }
+
+    /// Chooses how this hart's loads and stores that aren't aligned to their width are handled.
+    pub fn set_misaligned_access(&mut self, policy: MisalignedAccess) {
+        self.misaligned.access = policy;
+    }
+
+    /// How many of this hart's misaligned loads and stores were split under
+    /// [`MisalignedAccess::SplitWithCounter`].
+    pub fn misaligned_access_count(&self) -> u64 {
+        self.misaligned.count
+    }
+
+    /// The program counter, a byte address or a word index depending on the [`MemoryModel`].
     pub fn get_program_counter(&self) -> Result<usize, ()> {
-        let lock = self.program_counter.read();
-        Ok(*lock.unwrap_or(Err(())?))
+        let lock = self.program_counter.read().map_err(|_| ())?;
+        Ok(*lock)
     }
 
     pub fn get_registers(&self) -> Result<Vec<i32>, ()> {
-        let lock = self.general_register.read();
-        Ok(lock.unwrap_or(return Err(())).clone())
+        let lock = self.general_register.read().map_err(|_| ())?;
+        Ok(lock.clone())
     }
 
     pub fn get_data_range(&self, start: usize, stop: usize) -> Result<Vec<i32>, ()> {
-        self.data_memory.dump_memory_range(start, stop)
+        self.memory().dump_memory_range(start, stop)
     }
 
+    /// The instruction words at the code addresses in `start..stop`.
+    ///
+    /// Code addresses are byte addresses, or word indices into instruction memory for the
+    /// Harvard model.
     pub fn get_instruction_range(&self, start: usize, stop: usize) -> Result<Vec<i32>, ()> {
-        let memory = match self.instruction_memory.try_read() {
-            Ok(rw_lock) => rw_lock,
-            Err(_) => todo!(),
-        };
-        let mut result: Vec<i32> = Vec::new();
-        result.reserve(stop - start);
+        (start..stop)
+            .step_by(self.memory_model.instruction_size())
+            .map(|address| self.fetch(address).map_err(|_| ()))
+            .collect()
+    }
 
-        for n in start..stop {
-            result.push(*memory.get(n).expect("Failed to get address"));
+    /// Disassembles the instructions at the code addresses in `start..stop`.
+    ///
+    /// With the C extension the code is walked in 16 bit parcels, so compressed instructions
+    /// are listed at their own addresses.
+    pub fn disassemble(
+        &self,
+        start: usize,
+        stop: usize,
+        disassembler: &disassembler::Disassembler,
+    ) -> Result<disassembler::Listing, ()> {
+        if self.instruction_alignment() == 2 {
+            let parcels = (start..stop)
+                .step_by(2)
+                .map(|address| self.fetch_parcel(address).map_err(|_| ()))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(disassembler.compressed_listing(&parcels, start as u32));
         }
-        Ok(result)
+        let words = self.get_instruction_range(start, stop)?;
+        // Listings always use byte addresses
+        let base = match self.memory_model {
+            MemoryModel::Unified => start,
+            MemoryModel::Harvard => start * 4,
+        };
+        Ok(disassembler.listing(&words, base as u32))
     }
 
+    /// Writes `memory` to consecutive code addresses from `start`.
     pub fn set_program(&mut self, memory: &[i32], start: usize) {
+        if self.memory_model == MemoryModel::Unified {
+            let bytes = image::words_to_bytes(memory);
+            if self.memory().load(start, &bytes).is_err() {
+                panic!("Tried to address memory out of bounds!")
+            }
+            return;
+        }
         // TODO: RWLock stuff.
         let mut instruction_memory = self.instruction_memory.write().unwrap();
 
@@ -78,12 +449,236 @@ impl Pineapple {
         }
     }
 
-    pub fn step(&mut self) -> Instruction {
+    // Reads the instruction word at a code address. With the C extension words only need to be
+    // 2 byte aligned, and one that straddles a word boundary is fetched as two parcels.
+    pub(crate) fn fetch(&self, address: usize) -> Result<i32, StepError> {
+        match self.memory_model {
+            MemoryModel::Unified => {
+                if !address.is_multiple_of(self.instruction_alignment()) {
+                    return Err(StepError::InstructionMisaligned(address as u32));
+                }
+                if address.is_multiple_of(4) {
+                    return self
+                        .memory()
+                        .fetch_u32(address)
+                        .map(|word| word as i32)
+                        .map_err(|_| StepError::InstructionAccessFault(address as u32));
+                }
+                let high = address.wrapping_add(2);
+                let low = self.fetch_parcel(address)?;
+                Ok(((self.fetch_parcel(high)? as u32) << 16 | low as u32) as i32)
+            }
+            MemoryModel::Harvard => self
+                .instruction_memory
+                .read()
+                .unwrap()
+                .get(address)
+                .copied()
+                .ok_or(StepError::InstructionAccessFault(address as u32)),
+        }
+    }
+
+    // IALIGN in bytes, which the C extension lowers to 2 where the program counter can address
+    // parcels
+    pub(crate) fn instruction_alignment(&self) -> usize {
+        if self.extensions.c && self.memory_model == MemoryModel::Unified {
+            2
+        } else {
+            4
+        }
+    }
+
+    // Jumps and branches raise the misaligned fetch themselves, before changing any registers, so
+    // mepc points at them rather than at the target
+    pub(crate) fn aligned_target(&self, target: usize) -> Result<usize, StepError> {
+        match self.memory_model {
+            MemoryModel::Unified if !target.is_multiple_of(self.instruction_alignment()) => {
+                Err(StepError::InstructionMisaligned(target as u32))
+            }
+            _ => Ok(target),
+        }
+    }
+
+    // Reads a 16 bit parcel at a byte address
+    fn fetch_parcel(&self, address: usize) -> Result<u16, StepError> {
+        self.memory()
+            .fetch_u16(address)
+            .map_err(|_| StepError::InstructionAccessFault(address as u32))
+    }
+
+    // Reads the instruction at a code address and returns it with its length in bytes. The
+    // Harvard model's word indexed program counter can't address parcels, so it only runs whole
+    // words.
+    fn fetch_instruction(&self, address: usize) -> Result<(i32, usize), StepError> {
+        if self.extensions.c && self.memory_model == MemoryModel::Unified {
+            if !address.is_multiple_of(2) {
+                return Err(StepError::InstructionMisaligned(address as u32));
+            }
+            let parcel = self.fetch_parcel(address)?;
+            if Instruction::is_compressed(parcel) {
+                return Ok((parcel as i32, 2));
+            }
+        }
+        Ok((self.fetch(address)?, 4))
+    }
+
+    // Points the program counter at a byte address, such as an entry point
+    pub(crate) fn jump_to(&mut self, address: u32) {
+        *self.program_counter.write().unwrap() = self.memory_model.code_address(address);
+    }
+
+    // Whether code placed at `address` fits inside the Harvard model's instruction memory, which
+    // only covers the bottom of the address space
+    pub(crate) fn fits_instruction_memory(&self, address: u32, len: usize) -> bool {
+        let words = self.instruction_memory.read().unwrap().len();
+        (address as usize).saturating_add(len) <= words * 4
+    }
+
+    /// Copies bytes into data memory, and for the Harvard model into instruction memory as well if
+    /// `executable` is set.
+    ///
+    /// The caller has to have checked the range with [`memory::MemorySystem::is_backed`], and with
+    /// [`Pineapple::fits_instruction_memory`] for code. Fails if the device refuses the data.
+    pub(crate) fn place(&mut self, address: u32, data: &[u8], executable: bool) -> Result<(), ()> {
+        self.memory().load(address as usize, data)?;
+        if executable && self.memory_model == MemoryModel::Harvard {
+            // Instruction memory is word addressed, so merge the bytes into the words they land in
+            let mut instruction_memory = self.instruction_memory.write().unwrap();
+            for (idx, byte) in data.iter().enumerate() {
+                let byte_address = address as usize + idx;
+                let shift = (byte_address % 4) * 8;
+                let word = &mut instruction_memory[byte_address / 4];
+                *word = (*word & !(0xFF << shift)) | ((*byte as i32) << shift);
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps `device` at `base..base + size`, where it answers loads and stores with offsets
+    /// relative to `base`. The range mustn't overlap anything already attached, and can't be
+    /// executed from.
+    pub fn attach_device(
+        &mut self,
+        base: u32,
+        size: u32,
+        device: Box<dyn bus::Device>,
+    ) -> Result<(), bus::MapError> {
+        self.memory()
+            .attach(base, size, config::Permissions::READ_WRITE, device)
+    }
+
+    /// Services the guest's ECALL instructions, replacing any previous handler.
+    pub fn set_syscall_handler(&mut self, handler: Box<dyn syscall::SyscallHandler + Send>) {
+        self.syscall_handler = Some(handler);
+    }
+
+    /// Services semihosting calls, replacing any previous handler.
+    ///
+    /// Without one, semihosting calls stop execution like any other EBREAK.
+    pub fn set_semihosting_handler(
+        &mut self,
+        handler: Box<dyn semihosting::SemihostingHandler + Send>,
+    ) {
+        self.semihosting_handler = Some(handler);
+    }
+
+    /// Called with every value the guest writes to the Special Registry's signal register,
+    /// replacing any previous handler.
+    pub fn set_host_signal_handler(&mut self, handler: Box<dyn FnMut(u32) + Send>) {
+        self.special_registry.set_signal_handler(handler);
+    }
+
+    /// How many instructions have completed, one per cycle.
+    pub fn cycles(&self) -> u64 {
+        self.special_registry.cycles()
+    }
+
+    /// The CLINT's machine timer.
+    pub fn mtime(&self) -> u64 {
+        self.clint.mtime()
+    }
+
+    /// Sets how fast the CLINT's machine timer counts, carrying on from its current value.
+    pub fn set_timer_clock(&mut self, clock: clint::TimerClock) {
+        self.clint.set_clock(clock);
+    }
+
+    /// Interrupts the CLINT is raising for this hart, as `mip` bits such as
+    /// [`clint::MACHINE_TIMER_INTERRUPT`].
+    pub fn pending_interrupts(&self) -> u32 {
+        self.clint.pending(self.hart_id)
+    }
+
+    /// The video mode last selected through the Special Registry.
+    pub fn video_mode(&self) -> u8 {
+        self.special_registry.video_mode()
+    }
+
+    /// How many times the guest has flipped the frame through the Special Registry.
+    pub fn video_flips(&self) -> u32 {
+        self.special_registry.video_flips()
+    }
+
+    /// Executes one instruction, or takes a trap instead.
+    ///
+    /// Taking an interrupt that's pending and enabled, or an exception the guest handles, only
+    /// points the program counter at the guest's handler. Its first instruction runs on the next
+    /// step.
+    pub fn step(&mut self) -> Result<Step, StepError> {
+        if let Some(cause) = self.take_interrupt() {
+            return Ok(Step::Trapped(cause));
+        }
+        match self.execute() {
+            Ok(instruction) => Ok(Step::Executed(instruction)),
+            Err(e) => self.trap_exception(e).map(Step::Trapped),
+        }
+    }
+
+    fn execute(&mut self) -> Result<Instruction, StepError> {
         let addr = *self.program_counter.read().unwrap();
-        let instr = Instruction::from_i32(self.instruction_memory.read().unwrap()[addr]);
-        self.process_instruction(&instr);
-        *self.program_counter.write().unwrap() = addr +1;
-        instr
+        let (word, size) = self.fetch_instruction(addr)?;
+        let decoded = match size {
+            2 => Instruction::try_decode_compressed(word as u16),
+            _ => Instruction::try_decode(word),
+        };
+        let instr = decoded
+            .and_then(|instr| match self.extensions.allows(&instr) {
+                true => Ok(instr),
+                false => Err(DecodeError::new(word, DecodeErrorKind::Unsupported)),
+            })
+            .map_err(StepError::IllegalInstruction)?;
+        let result = self.process_instruction(&instr, size).and_then(|_| {
+            match self.special_registry.take_halt(self.hart_id) {
+                Some(code) => Err(StepError::Exit(code)),
+                None => Ok(()),
+            }
+        });
+        if let Err(e) = result {
+            // Leave the program counter on the instruction that failed
+            *self.program_counter.write().unwrap() = addr;
+            return Err(e);
+        }
+        self.special_registry.tick();
+        self.clint.tick();
+        self.instret = self.instret.wrapping_add(1);
+        Ok(instr)
+    }
+
+    /// Steps until something stops execution, such as an exit or a breakpoint, and returns it.
+    pub fn run(&mut self) -> StepError {
+        loop {
+            if let Err(e) = self.step() {
+                return e;
+            }
+        }
+    }
+
+    /// Runs at most `steps` instructions, stopping early if one of them fails.
+    pub fn run_for(&mut self, steps: usize) -> Result<(), StepError> {
+        for _ in 0..steps {
+            self.step()?;
+        }
+        Ok(())
     }
 }
 
@@ -94,10 +689,477 @@ mod tests {
     #[test]
     fn empty_test() {
         println!("Test");
-        let mut pineapple = Pineapple::new();
+        let mut pineapple = Pineapple::with_memory_model(MemoryModel::Harvard);
         for _ in 0..5 {
-            let instruction = pineapple.step();
-            println!("{}", instruction)
+            let step = pineapple.step().unwrap();
+            println!("{:?}", step)
+        }
+    }
+
+    #[test]
+    fn writes_to_x0_are_ignored() {
+        let mut pineapple = Pineapple::new();
+        let program = assembler::assemble("li t0, 1\nsll zero, t0, t0\nadd zero, t0, t0").unwrap();
+        pineapple.set_program(&program, 0);
+        pineapple.run_for(3).unwrap();
+        assert_eq!(pineapple.get_registers().unwrap()[0], 0);
+    }
+
+    #[test]
+    fn illegal_instruction_does_not_panic() {
+        let mut pineapple = Pineapple::new();
+        pineapple.set_program(&[0x13, 0x0000_0000, 0x13], 0);
+        pineapple.step().unwrap();
+        match pineapple.step() {
+            Err(StepError::IllegalInstruction(e)) => {
+                assert_eq!(e.word, 0);
+                // An all zero parcel is the compressed encoding reserved as illegal
+                assert_eq!(e.kind, DecodeErrorKind::Unsupported);
+            }
+            _ => panic!("Expected an illegal instruction"),
+        }
+        // The program counter stays on the faulting instruction
+        assert_eq!(pineapple.get_program_counter(), Ok(4));
+    }
+
+    #[test]
+    fn jumps_and_branches_in_both_models() {
+        let program = assembler::assemble(
+            "
+                li a0, 0
+                li a1, 5
+            loop:
+                addi a0, a0, 2
+                addi a1, a1, -1
+                bnez a1, loop
+                call double
+                j end
+            double:
+                add a0, a0, a0
+                ret
+            end:
+                ebreak
+            ",
+        )
+        .unwrap();
+        for (model, end) in [(MemoryModel::Unified, 40), (MemoryModel::Harvard, 10)] {
+            let mut pineapple = Pineapple::with_memory_model(model);
+            pineapple.set_program(&program, 0);
+            assert_eq!(pineapple.run_for(100), Err(StepError::Breakpoint));
+            assert_eq!(pineapple.get_registers().unwrap()[10], 20);
+            assert_eq!(pineapple.get_program_counter(), Ok(end));
+        }
+    }
+
+    #[test]
+    fn load_and_store_widths() {
+        let mut pineapple = Pineapple::new();
+        let program = assembler::assemble(
+            "
+                li t0, 0x1000
+                li t1, 0x8899AABB
+                sw t1, 0(t0)
+                li t1, 0x7F
+                sb t1, 1(t0)
+                lb a0, 0(t0)
+                lbu a1, 0(t0)
+                lh a2, 2(t0)
+                lhu a3, 2(t0)
+                lw a4, 0(t0)
+                li t1, -1
+                sh t1, 4(t0)
+                lw a5, 4(t0)
+                lb zero, 0(t0)
+                ebreak
+            ",
+        )
+        .unwrap();
+        pineapple.set_program(&program, 0);
+        assert_eq!(pineapple.run_for(100), Err(StepError::Breakpoint));
+        let registers = pineapple.get_registers().unwrap();
+        assert_eq!(registers[0], 0);
+        assert_eq!(
+            registers[10..16],
+            [-0x45, 0xBB, -0x7767, 0x8899, 0x88997FBBu32 as i32, 0xFFFF]
+        );
+    }
+
+    #[test]
+    fn misaligned_access_policies() {
+        let program = assembler::assemble(
+            "
+                li t0, 0x1001
+                li t1, 0x11223344
+                sw t1, 0(t0)
+                lw a0, 0(t0)
+                lh a1, 1(t0)
+                lhu a2, 2(t0)
+                ebreak
+            ",
+        )
+        .unwrap();
+        let run = |policy| {
+            let mut pineapple = Pineapple::new();
+            pineapple.set_misaligned_access(policy);
+            pineapple.set_program(&program, 0);
+            let stop = pineapple.run_for(100).unwrap_err();
+            (pineapple, stop)
+        };
+
+        let (pineapple, stop) = run(MisalignedAccess::Trap);
+        assert_eq!(stop, StepError::StoreMisaligned(0x1001));
+        assert_eq!(pineapple.get_program_counter(), Ok(16));
+
+        for (policy, count) in [
+            (MisalignedAccess::Emulate, 0),
+            (MisalignedAccess::SplitWithCounter, 3),
+        ] {
+            let (pineapple, stop) = run(policy);
+            assert_eq!(stop, StepError::Breakpoint);
+            let registers = pineapple.get_registers().unwrap();
+            assert_eq!(registers[10..13], [0x11223344, 0x2233, 0x1122]);
+            assert_eq!(pineapple.misaligned_access_count(), count);
+        }
+    }
+
+    #[test]
+    fn self_modifying_code_and_fetch_faults() {
+        let mut pineapple = Pineapple::new();
+        let program = assembler::assemble(
+            "
+                li t0, 0x00500513   # addi a0, zero, 5
+                la t1, patch
+                sw t0, 0(t1)
+            patch:
+                nop
+                li t0, 0x20000
+                jr t0
+            ",
+        )
+        .unwrap();
+        pineapple.set_program(&program, 0);
+        assert_eq!(
+            pineapple.run_for(100),
+            Err(StepError::InstructionAccessFault(0x20000))
+        );
+        assert_eq!(pineapple.get_registers().unwrap()[10], 5);
+
+        // Without the C extension instructions have to be word aligned
+        pineapple.set_extensions(Extensions::rv32i());
+        pineapple.set_program(&assembler::assemble("li t0, 0x102\njr t0").unwrap(), 0x100);
+        pineapple.jump_to(0x100);
+        assert_eq!(
+            pineapple.run_for(100),
+            Err(StepError::InstructionMisaligned(0x102))
+        );
+        // The fault is raised by the jump, not by fetching its target
+        assert_eq!(pineapple.get_program_counter(), Ok(0x104));
+    }
+
+    #[test]
+    fn compressed_instructions() {
+        // c.li a0, 5; addi a1, a0, 1; c.j 4; c.li a0, 0; c.mv a2, a1; c.jal 4; c.nop; c.ebreak
+        let program = [
+            0x05934515,
+            0xa0110015_u32 as i32,
+            0x862e4501_u32 as i32,
+            0x00012011,
+            0x00019002,
+        ];
+        let mut pineapple = Pineapple::new();
+        assert_ne!(pineapple.extensions().misa() & 1 << 2, 0);
+        pineapple.set_program(&program, 0);
+        assert_eq!(pineapple.run_for(100), Err(StepError::Breakpoint));
+        assert_eq!(pineapple.get_program_counter(), Ok(16));
+        let registers = pineapple.get_registers().unwrap();
+        assert_eq!(registers[10..13], [5, 6, 6]);
+        // The link register points past the 2 byte jump
+        assert_eq!(registers[1], 14);
+
+        // The same code means something else to plain RV32I
+        let mut pineapple = Pineapple::new();
+        pineapple.set_extensions(Extensions::rv32i());
+        pineapple.set_program(&program, 0);
+        assert!(matches!(
+            pineapple.run_for(100),
+            Err(StepError::IllegalInstruction(_))
+        ));
+    }
+
+    #[test]
+    fn load_and_store_access_faults() {
+        let mut pineapple = Pineapple::new();
+        let program = assembler::assemble("li t0, 0x1FFFE\nlw a0, 0(t0)").unwrap();
+        pineapple.set_program(&program, 0);
+        // Reading past the end of RAM
+        assert_eq!(
+            pineapple.run_for(100),
+            Err(StepError::LoadAccessFault(0x1FFFE))
+        );
+        assert_eq!(pineapple.get_program_counter(), Ok(8));
+
+        let program = assembler::assemble("li t1, 0x50000000\nsw zero, 0(t1)").unwrap();
+        pineapple.set_program(&program, 0x100);
+        pineapple.jump_to(0x100);
+        assert_eq!(
+            pineapple.run_for(100),
+            Err(StepError::StoreAccessFault(0x50000000))
+        );
+        assert_eq!(pineapple.get_program_counter(), Ok(0x104));
+    }
+
+    #[test]
+    fn multiply_and_divide() {
+        let program = assembler::assemble(
+            "
+            li t0, 0x80000000
+            li t1, -1
+            li t2, 3
+            li t3, -7
+            mul a0, t3, t2
+            mulh a1, t0, t0
+            mulhsu a2, t1, t1
+            mulhu a3, t1, t1
+            div a4, t3, t2
+            rem a5, t3, t2
+            div a6, t0, t1
+            rem a7, t0, t1
+            div s2, t3, zero
+            divu s3, t3, zero
+            rem s4, t3, zero
+            remu s5, t3, zero
+            divu s6, t3, t2
+            remu s7, t3, t2
+            ebreak
+            ",
+        )
+        .unwrap();
+        let mut pineapple = Pineapple::new();
+        pineapple.set_program(&program, 0);
+        assert_eq!(pineapple.run_for(100), Err(StepError::Breakpoint));
+        let registers = pineapple.get_registers().unwrap();
+        assert_eq!(
+            registers[10..24],
+            [
+                -21,
+                0x40000000,
+                -1,
+                -2,
+                -2,
+                -1,
+                i32::MIN,
+                0,
+                -1,
+                -1,
+                -7,
+                -7,
+                0x55555553,
+                0
+            ]
+        );
+
+        // Without M the first multiply is illegal
+        let mut pineapple = Pineapple::new();
+        pineapple.set_extensions(Extensions::rv32i());
+        pineapple.set_program(&program, 0);
+        match pineapple.run_for(100) {
+            Err(StepError::IllegalInstruction(e)) => {
+                assert_eq!(e.kind, DecodeErrorKind::Unsupported);
+            }
+            other => panic!("Expected an illegal instruction, got {:?}", other),
+        }
+        assert_eq!(pineapple.get_program_counter(), Ok(16));
+        assert_eq!(pineapple.read_csr(csr::MISA), Ok(csr::MISA_RV32I));
+    }
+
+    #[test]
+    fn atomics() {
+        let program = assembler::assemble(
+            "
+            li t0, 0x1000
+            li t1, 5
+            sw t1, 0(t0)
+            li t2, -3
+            amoadd.w a0, t2, (t0)
+            amomin.w a1, t2, (t0)
+            amomaxu.w a2, t2, (t0)
+            amoswap.w a3, t1, (t0)
+            lr.w a4, (t0)
+            sc.w a5, t2, (t0)
+            sc.w a6, t1, (t0)
+            lr.w a7, (t0)
+            sw zero, 0(t0)
+            sc.w s2, t1, (t0)
+            lw s3, 0(t0)
+            ebreak
+            ",
+        )
+        .unwrap();
+        let mut pineapple = Pineapple::new();
+        pineapple.set_program(&program, 0);
+        assert_eq!(pineapple.run_for(100), Err(StepError::Breakpoint));
+        let registers = pineapple.get_registers().unwrap();
+        // 5 + -3 = 2, min(2, -3) = -3, maxu(-3, -3) = -3, then swapped for 5
+        assert_eq!(registers[10..14], [5, 2, -3, -3]);
+        // The first SC succeeds, the second has no reservation, the third lost it to a store
+        assert_eq!(registers[14..19], [5, 0, 1, -3, 1]);
+        assert_eq!(registers[19], 0);
+
+        let program = assembler::assemble("li t0, 0x1002\namoor.w a0, zero, (t0)").unwrap();
+        pineapple.set_program(&program, 0x100);
+        pineapple.jump_to(0x100);
+        assert_eq!(
+            pineapple.run_for(3),
+            Err(StepError::StoreMisaligned(0x1002))
+        );
+    }
+
+    #[test]
+    fn harts_share_memory_and_reservations() {
+        let mut first = Pineapple::new();
+        let mut second = first.add_hart();
+        assert_eq!((first.hart_id(), second.hart_id()), (0, 1));
+        assert_eq!(second.read_csr(csr::MHARTID), Ok(1));
+
+        let program = assembler::assemble(
+            "
+            li t0, 0x1000
+            lr.w a0, (t0)
+            addi a0, a0, 1
+            sc.w a1, a0, (t0)
+            ebreak
+            ",
+        )
+        .unwrap();
+        first.set_program(&program, 0);
+        let store = assembler::assemble("li t0, 0x1000\nli t1, 7\nsw t1, 0(t0)\nebreak").unwrap();
+        second.set_program(&store, 0x100);
+        second.jump_to(0x100);
+
+        // The second hart's store lands between the first's LR and SC
+        first.run_for(3).unwrap();
+        assert_eq!(second.run_for(10), Err(StepError::Breakpoint));
+        assert_eq!(first.run_for(10), Err(StepError::Breakpoint));
+        assert_eq!(first.get_registers().unwrap()[11], 1);
+        assert_eq!(first.get_data_range(0x1000, 0x1004), Ok(vec![7]));
+    }
+
+    #[test]
+    fn harts_have_their_own_interrupts_and_halt() {
+        let mut first = Pineapple::new();
+        let mut second = first.add_hart();
+        second.set_misaligned_access(MisalignedAccess::SplitWithCounter);
+        assert_eq!(first.misaligned_access(), MisalignedAccess::Emulate);
+
+        let idle = assembler::assemble(
+            "
+            li t0, 0x40
+            csrw mtvec, t0
+            csrsi mie, 8
+            csrsi mstatus, 8
+        wait:
+            wfi
+            j wait
+            ",
+        )
+        .unwrap();
+        first.set_program(&idle, 0);
+        let handler = assembler::assemble(
+            "
+            li t0, 0x80000000
+            li t1, 5
+            sw t1, 0(t0)
+            ",
+        )
+        .unwrap();
+        first.set_program(&handler, 0x40);
+        let wake = assembler::assemble(
+            "
+            li t0, 0x1001
+            lw zero, 0(t0)
+            li t0, 0x02000000
+            li t1, 1
+            sw t1, 0(t0)        # msip of hart 0
+            li t0, 0x80000000
+            li t1, 9
+            sw t1, 0(t0)
+            ",
+        )
+        .unwrap();
+        second.set_program(&wake, 0x100);
+        second.jump_to(0x100);
+
+        // mtime counts rounds of one instruction from each hart
+        for _ in 0..4 {
+            assert!(matches!(first.step(), Ok(Step::Executed(_))));
+            assert!(matches!(second.step(), Ok(Step::Executed(_))));
+        }
+        assert_eq!(first.mtime(), 4);
+        assert_eq!(second.misaligned_access_count(), 1);
+        assert_eq!(first.misaligned_access_count(), 0);
+
+        // The IPI only reaches the hart it was sent to, and the halt only stops its sender
+        assert_eq!(second.run_for(10), Err(StepError::Exit(9)));
+        assert_eq!(
+            first.pending_interrupts(),
+            clint::MACHINE_SOFTWARE_INTERRUPT
+        );
+        assert_eq!(second.pending_interrupts(), 0);
+        assert_eq!(
+            first.step(),
+            Ok(Step::Trapped(
+                trap::INTERRUPT | trap::MACHINE_SOFTWARE_INTERRUPT
+            ))
+        );
+        assert_eq!(first.run_for(10), Err(StepError::Exit(5)));
+
+        // A halt stays with the hart that wrote it, whichever hart checks first
+        second.memory().write_u32(0x80000000, 3).unwrap();
+        first.jump_to(0x10);
+        second.jump_to(0x10);
+        assert!(matches!(first.step(), Ok(Step::Executed(_))));
+        assert_eq!(second.step(), Err(StepError::Exit(3)));
+    }
+
+    #[test]
+    fn atomic_counters_across_threads() {
+        // Each hart bumps one counter with AMOADD and another with an LR/SC retry loop
+        let program = assembler::assemble(
+            "
+            li t0, 0x1000
+            addi t3, t0, 4
+            li t1, 1
+            li t2, 500
+        again:
+            amoadd.w zero, t1, (t0)
+        retry:
+            lr.w a0, (t3)
+            addi a0, a0, 1
+            sc.w a1, a0, (t3)
+            bnez a1, retry
+            addi t2, t2, -1
+            bnez t2, again
+            ebreak
+            ",
+        )
+        .unwrap();
+        let mut first = Pineapple::new();
+        first.set_program(&program, 0);
+        let mut harts: Vec<_> = (0..3).map(|_| first.add_hart()).collect();
+        harts.push(first);
+        let threads: Vec<_> = harts
+            .into_iter()
+            .map(|mut hart| std::thread::spawn(move || (hart.run(), hart)))
+            .collect();
+        let mut harts = Vec::new();
+        for thread in threads {
+            let (stop, hart) = thread.join().unwrap();
+            assert_eq!(stop, StepError::Breakpoint);
+            harts.push(hart);
         }
+        assert_eq!(
+            harts[0].get_data_range(0x1000, 0x1008),
+            Ok(vec![2000, 2000])
+        );
     }
 }
@@ -1,129 +1,328 @@
-use byteorder::{ByteOrder, LittleEndian};
-use std::{sync::RwLock, vec};
+use std::collections::HashMap;
+
+use crate::{
+    bus::{Device, MapError},
+    config::Permissions,
+};
+
+/// What happens when a load or store isn't aligned to its own width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MisalignedAccess {
+    /// Raise an address misaligned exception, like the Pineapple hardware
+    Trap,
+    /// Perform the access one byte at a time
+    #[default]
+    Emulate,
+    /// Perform the access one byte at a time, and count how often it happens
+    SplitWithCounter,
+}
+
+/// A hart's [`MisalignedAccess`] policy, and how many accesses it has split.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct MisalignedPolicy {
+    pub access: MisalignedAccess,
+    /// Accesses split while using [`MisalignedAccess::SplitWithCounter`]
+    pub count: u64,
+}
+
+impl MisalignedPolicy {
+    /// Applies the policy, returning whether an access of `width` bytes at `address` may go
+    /// ahead.
+    pub fn allow(&mut self, address: usize, width: usize) -> bool {
+        if address.is_multiple_of(width) {
+            return true;
+        }
+        match self.access {
+            MisalignedAccess::Trap => false,
+            MisalignedAccess::Emulate => true,
+            MisalignedAccess::SplitWithCounter => {
+                self.count += 1;
+                true
+            }
+        }
+    }
+}
+
+struct Region {
+    base: u32,
+    size: u32,
+    permissions: Permissions,
+    device: Box<dyn Device>,
+}
+
+impl Region {
+    // Offset of the range inside this region, if it fits entirely
+    fn offset(&self, address: usize, len: usize) -> Option<u32> {
+        let offset = address.checked_sub(self.base as usize)?;
+        if offset.checked_add(len)? <= self.size as usize {
+            Some(offset as u32)
+        } else {
+            None
+        }
+    }
+}
+
+/// The bus, mapping address ranges to devices.
 pub(crate) struct MemorySystem {
-    ram: Vec<i8>,
-    video_memory: Vec<i8>,
+    regions: Vec<Region>,
+    // Base and size of the region the video output is read from
+    video_memory: Option<(u32, u32)>,
+    // The word each hart holds an LR.W reservation on
+    reservations: HashMap<usize, usize>,
+    harts: usize,
 }
 
 impl MemorySystem {
-    pub fn new() -> Self {
+    /// A bus with nothing attached.
+    pub fn empty() -> Self {
         Self {
-            ram: vec![0; 524_288 * 4],
-            video_memory: vec![0; 2048 * 4],
+            regions: Vec::new(),
+            video_memory: None,
+            reservations: HashMap::new(),
+            harts: 1,
         }
     }
-    pub fn read_i32(&self, idx: usize) -> i32 {
-        match idx {
-            0x00000000..=0x0001FFFF => {
-                // RAM
-                let contents = &self.ram;
-                read_slice(&contents[idx..(idx + 5)])
-            }
-            0x00020000..=0x3FFFFFFF => {
-                // Reserved Space
-                unimplemented!("Reserved Space is Unimplemented!")
-            }
-            0x40000000..=0x400007FF => {
-                // Video RAM
-                let contents = &self.ram;
-                read_slice(&contents[idx..(idx + 5)])
-            }
-            0x40000800..=0x7FFFFFFF => {
-                // Reserved Space
-                unimplemented!("Reserved Space is Unimplemented!")
-            }
-            0x80000000..=0x8000000F => {
-                // Special Registry
-                unimplemented!("Special registry is unimplemented!")
-            }
-            0x80000010..=0xFFFFFFFF => {
-                // Reserved Space
-                unimplemented!("Reserved Space is Unimplemented!")
-            }
-            _ => unimplemented!("Out of range!"),
+
+    /// Maps `device` at `base..base + size`, which mustn't overlap anything already attached.
+    pub fn attach(
+        &mut self,
+        base: u32,
+        size: u32,
+        permissions: Permissions,
+        device: Box<dyn Device>,
+    ) -> Result<(), MapError> {
+        let end = base as u64 + size as u64;
+        if size == 0 || end > 1 << 32 {
+            return Err(MapError::InvalidRange { base, size });
+        }
+        let overlaps = self.regions.iter().any(|region| {
+            (base as u64) < region.base as u64 + region.size as u64 && (region.base as u64) < end
+        });
+        if overlaps {
+            return Err(MapError::Overlap { base, size });
         }
+        self.regions.push(Region {
+            base,
+            size,
+            permissions,
+            device,
+        });
+        Ok(())
     }
 
-    pub fn write_i32(&mut self, idx: usize, data: i32) {
-        match idx {
-            0x00000000..=0x0001FFFF => {
-                // RAM
-                let mut contents = &mut self.ram;
-                write_slice(&mut contents[idx..(idx + 5)], data);
-            }
-            0x00020000..=0x3FFFFFFF => {
-                // Reserved Space
-                unimplemented!("Attempted to write out of bounds!")
-            }
-            0x40000000..=0x400007FF => {
-                // Video RAM
-                let mut contents = &mut self.video_memory;
-                write_slice(&mut contents[idx..(idx + 5)], data);
-            }
-            0x40000800..=0x7FFFFFFF => {
-                // Reserved Space
-                unimplemented!("Attempted to write out of bounds!")
-            }
-            0x80000000..=0x8000000F => {
-                // Special Registry
-                unimplemented!("Attempted to write out of bounds!")
-            }
-            0x80000010..=0xFFFFFFFF => {
-                // Reserved Space
-                unimplemented!("Attempted to write out of bounds!")
-            }
+    pub fn video_memory(&self) -> Option<(u32, u32)> {
+        self.video_memory
+    }
+
+    pub fn set_video_memory(&mut self, base: u32, size: u32) {
+        self.video_memory = Some((base, size));
+    }
+
+    /// Hands out the hart ID of another hart on this bus. The first hart is 0.
+    pub fn add_hart(&mut self) -> usize {
+        self.harts += 1;
+        self.harts - 1
+    }
+
+    /// Loads an aligned word, and reserves it for `hart` in place of whatever it reserved before.
+    pub fn load_reserved(&mut self, hart: usize, address: usize) -> Result<u32, ()> {
+        let value = self.read_u32(address)?;
+        self.reservations.insert(hart, address);
+        Ok(value)
+    }
+
+    /// Stores an aligned word if `hart` still holds a reservation on it, returning whether it did.
+    /// The hart's reservation is used up either way.
+    pub fn store_conditional(
+        &mut self,
+        hart: usize,
+        address: usize,
+        data: u32,
+    ) -> Result<bool, ()> {
+        if self.reservations.remove(&hart) != Some(address) {
+            return Ok(false);
+        }
+        self.write_u32(address, data)?;
+        Ok(true)
+    }
+
+    // Stores from any hart, and from the host, break the reservations on the words they touch
+    fn invalidate(&mut self, address: usize, len: usize) {
+        if self.reservations.is_empty() {
+            return;
+        }
+        let end = address.saturating_add(len);
+        self.reservations
+            .retain(|_, reserved| *reserved + 4 <= address || end <= *reserved);
+    }
+
+    // The region holding the whole range, and the range's offset inside it
+    fn region(&self, address: usize, len: usize) -> Option<(&Region, u32)> {
+        self.regions
+            .iter()
+            .find_map(|region| Some((region, region.offset(address, len)?)))
+    }
+
+    fn region_mut(&mut self, address: usize, len: usize) -> Option<(&mut Region, u32)> {
+        self.regions.iter_mut().find_map(|region| {
+            let offset = region.offset(address, len)?;
+            Some((region, offset))
+        })
+    }
+
+    /// Fetches a 16 bit instruction parcel, which has to be aligned and executable.
+    pub fn fetch_u16(&self, address: usize) -> Result<u16, ()> {
+        match self.region(address, 2) {
+            Some((region, offset)) if region.permissions.execute => region.device.read_u16(offset),
+            _ => Err(()),
+        }
+    }
+
+    /// Fetches an instruction word, which has to be aligned and executable.
+    pub fn fetch_u32(&self, address: usize) -> Result<u32, ()> {
+        match self.region(address, 4) {
+            Some((region, offset)) if region.permissions.execute => region.device.read_u32(offset),
+            _ => Err(()),
+        }
+    }
+
+    // Regions the guest may read from or write to
+    fn readable(&self, address: usize, len: usize) -> Option<(&Region, u32)> {
+        self.region(address, len)
+            .filter(|(region, _)| region.permissions.read)
+    }
+
+    fn writable(&mut self, address: usize, len: usize) -> Option<(&mut Region, u32)> {
+        self.region_mut(address, len)
+            .filter(|(region, _)| region.permissions.write)
+    }
+
+    /// Reads a byte as the guest, failing if nothing readable is mapped there.
+    pub fn read_u8(&self, address: usize) -> Result<u8, ()> {
+        let (region, offset) = self.readable(address, 1).ok_or(())?;
+        region.device.read_u8(offset)
+    }
+
+    // Misaligned accesses are split into single bytes, so each one lands wherever it is mapped
+    pub fn read_u16(&self, address: usize) -> Result<u16, ()> {
+        match self.readable(address, 2) {
+            Some((region, offset)) if address.is_multiple_of(2) => region.device.read_u16(offset),
+            _ => Ok(u16::from_le_bytes([
+                self.read_u8(address)?,
+                self.read_u8(address.wrapping_add(1))?,
+            ])),
+        }
+    }
+
+    pub fn read_u32(&self, address: usize) -> Result<u32, ()> {
+        match self.readable(address, 4) {
+            Some((region, offset)) if address.is_multiple_of(4) => region.device.read_u32(offset),
             _ => {
-                unimplemented!("Attempted to write out of bounds!")
+                let mut bytes = [0; 4];
+                for (idx, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.read_u8(address.wrapping_add(idx))?;
+                }
+                Ok(u32::from_le_bytes(bytes))
             }
         }
     }
 
-    pub fn dump_memory_range(&self, start: usize, stop: usize) -> Result<Vec<i32>, ()> {
-        // There's probably a more eloquent way to write this
-        let mut dump: Vec<i32> = Vec::new();
-        if start < 0x00020000 && stop < 0x00020000 {
-            let contents = &self.ram;
-            for i in (start..stop).step_by(4) {
-                dump.push(read_slice(&contents[i..(i + 5)]));
+    /// Writes a single byte as the guest, leaving its neighbours alone.
+    pub fn write_u8(&mut self, address: usize, data: u8) -> Result<(), ()> {
+        self.invalidate(address, 1);
+        let (region, offset) = self.writable(address, 1).ok_or(())?;
+        region.device.write_u8(offset, data)
+    }
+
+    /// Writes two bytes in little endian order. A misaligned write that fails part way through
+    /// keeps the bytes written before the failure, as do wider writes.
+    pub fn write_u16(&mut self, address: usize, data: u16) -> Result<(), ()> {
+        self.invalidate(address, 2);
+        match self.writable(address, 2) {
+            Some((region, offset)) if address.is_multiple_of(2) => {
+                region.device.write_u16(offset, data)
             }
-            return Ok(dump);
+            _ => self.write_bytes(address, &data.to_le_bytes()),
         }
-        if start < 0x40000000 && stop < 0x40000000 {
-            let contents = &self.video_memory;
-            for i in (start..stop).step_by(4) {
-                dump.push(read_slice(&contents[i..(i + 5)]));
+    }
+
+    pub fn write_u32(&mut self, address: usize, data: u32) -> Result<(), ()> {
+        self.invalidate(address, 4);
+        match self.writable(address, 4) {
+            Some((region, offset)) if address.is_multiple_of(4) => {
+                region.device.write_u32(offset, data)
             }
-            return Ok(dump);
+            _ => self.write_bytes(address, &data.to_le_bytes()),
         }
-        Err(())
     }
-}
 
-impl Default for MemorySystem {
-    fn default() -> Self {
-        Self::new()
+    fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Result<(), ()> {
+        for (idx, byte) in bytes.iter().enumerate() {
+            self.write_u8(address.wrapping_add(idx), *byte)?;
+        }
+        Ok(())
     }
-}
 
-fn read_slice(slice: &[i8]) -> i32 {
-    let u8slice = conv(slice);
+    /// Copies `bytes` into memory starting at `address`, bypassing any device side effects.
+    ///
+    /// The whole range has to fit inside a single device.
+    pub fn load(&mut self, address: usize, bytes: &[u8]) -> Result<(), ()> {
+        self.invalidate(address, bytes.len());
+        let (region, offset) = self.region_mut(address, bytes.len()).ok_or(())?;
+        region.device.load(offset, bytes)
+    }
 
-    LittleEndian::read_i32(u8slice)
-}
+    /// Copies `len` bytes out of memory starting at `address`, the inverse of [`MemorySystem::load`].
+    pub fn read(&self, address: usize, len: usize) -> Result<Vec<u8>, ()> {
+        let (region, offset) = self.region(address, len).ok_or(())?;
+        region.device.read(offset, len)
+    }
 
-fn write_slice(slice: &mut [i8], contents: i32) {
-    LittleEndian::write_i32(conv_mut(slice), contents);
-}
+    /// Whether `len` bytes starting at `address` all sit inside a single device.
+    pub fn is_backed(&self, address: usize, len: usize) -> bool {
+        self.region(address, len).is_some()
+    }
+
+    /// Whether `len` bytes starting at `address` all sit inside a single executable device.
+    pub fn is_executable(&self, address: usize, len: usize) -> bool {
+        self.region(address, len)
+            .is_some_and(|(region, _)| region.permissions.execute)
+    }
 
-fn conv_mut<'a>(p: &'a mut [i8]) -> &'a mut [u8] {
-    // Safety: this is fine since they're equivilant size/shapes
-    unsafe {
-        &mut *(p as *mut [i8] as *mut [u8])
+    pub fn dump_memory_range(&self, start: usize, stop: usize) -> Result<Vec<i32>, ()> {
+        (start..stop)
+            .step_by(4)
+            .map(|address| {
+                let bytes = self.read(address, 4)?;
+                Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            })
+            .collect()
     }
 }
-fn conv<'a>(p: &'a [i8]) -> &'a [u8] {
-    // Safety: this is fine since they're equivilant size/shapes
-    unsafe {
-        &*(p as *const [i8] as *const [u8])
+
+#[cfg(test)]
+mod tests {
+    use crate::config::MachineConfig;
+    use std::collections::HashMap;
+
+    #[test]
+    fn sub_word_accesses() {
+        let mut memory = MachineConfig::default()
+            .build(HashMap::new(), &Default::default())
+            .unwrap();
+        memory.write_u32(0x100, 0x11223344).unwrap();
+        memory.write_u8(0x101, 0xAA).unwrap();
+        assert_eq!(memory.read_u32(0x100).unwrap(), 0x1122AA44);
+        memory.write_u16(0x102, 0xBBCC).unwrap();
+        assert_eq!(memory.read_u32(0x100).unwrap(), 0xBBCCAA44);
+        assert_eq!(memory.read_u16(0x101).unwrap(), 0xCCAA);
+        assert_eq!(memory.read_u8(0x103).unwrap(), 0xBB);
+
+        // Video RAM is its own region, not an alias of RAM
+        memory.write_u32(0x40000000, 0xDEADBEEF).unwrap();
+        assert_eq!(memory.read_u32(0x40000000).unwrap(), 0xDEADBEEF);
+        assert_eq!(memory.read_u32(0).unwrap(), 0);
+        memory.write_u8(0x400007FF, 0x12).unwrap();
+        assert_eq!(memory.read_u8(0x400007FF).unwrap(), 0x12);
     }
 }
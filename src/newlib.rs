@@ -0,0 +1,418 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::symbols::SymbolTable;
+use crate::syscall::{HostIo, HostIoBuilder, SyscallAction, SyscallContext, SyscallHandler};
+
+// Syscall numbers from the RISC-V Linux ABI, which newlib's libgloss and picolibc both use
+const SYS_OPENAT: i32 = 56;
+const SYS_CLOSE: i32 = 57;
+const SYS_LSEEK: i32 = 62;
+const SYS_READ: i32 = 63;
+const SYS_WRITE: i32 = 64;
+const SYS_FSTAT: i32 = 80;
+const SYS_EXIT: i32 = 93;
+const SYS_EXIT_GROUP: i32 = 94;
+const SYS_GETTIMEOFDAY: i32 = 169;
+const SYS_BRK: i32 = 214;
+const SYS_OPEN: i32 = 1024;
+
+// errno values as newlib defines them
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EFAULT: i32 = 14;
+const EEXIST: i32 = 17;
+const EINVAL: i32 = 22;
+const ESPIPE: i32 = 29;
+const ENOSYS: i32 = 88;
+
+// open() flags as newlib defines them
+const O_ACCMODE: i32 = 0b11;
+const O_WRONLY: i32 = 1;
+const O_RDWR: i32 = 2;
+const O_APPEND: i32 = 0x0008;
+const O_CREAT: i32 = 0x0200;
+const O_TRUNC: i32 = 0x0400;
+const O_EXCL: i32 = 0x0800;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFREG: u32 = 0o100000;
+
+// Size of libgloss' `struct kernel_stat` on RV32
+const STAT_SIZE: usize = 128;
+const MAX_PATH: usize = 4096;
+
+/// A [`SyscallHandler`] that implements the syscalls newlib and picolibc need to run C programs.
+///
+/// Handles exit, read, write, open, close, lseek, fstat, brk and gettimeofday. File descriptors
+/// 0, 1 and 2 go to the host provided streams, and files can only be opened inside the sandbox
+/// directory. Without a sandbox every open fails.
+///
+/// By default the heap handed out by `brk` runs from 0x00010000 up to 0x0001C000, which leaves
+/// the top 16 KiB of RAM for the stack. Use [`NewlibSyscalls::heap`] or
+/// [`NewlibSyscalls::heap_from`] to fit it around the program that's actually loaded.
+pub struct NewlibSyscalls {
+    io: HostIo,
+    files: HashMap<i32, File>,
+    next_fd: i32,
+    heap_start: u32,
+    program_break: u32,
+    heap_limit: u32,
+}
+
+impl HostIoBuilder for NewlibSyscalls {
+    fn host_io(&mut self) -> &mut HostIo {
+        &mut self.io
+    }
+}
+
+impl Default for NewlibSyscalls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NewlibSyscalls {
+    /// Creates a handler wired up to the host process' own stdin, stdout and stderr.
+    pub fn new() -> Self {
+        NewlibSyscalls {
+            io: HostIo::default(),
+            files: HashMap::new(),
+            next_fd: 3,
+            heap_start: 0x0001_0000,
+            program_break: 0x0001_0000,
+            heap_limit: 0x0001_C000,
+        }
+    }
+
+    /// Sets the initial program break and how far `brk` may move it.
+    pub fn heap(mut self, start: u32, limit: u32) -> Self {
+        self.heap_start = start;
+        self.program_break = start;
+        self.heap_limit = limit;
+        self
+    }
+
+    /// Starts the heap where the program's linker script says it does, at `__heap_start` or
+    /// failing that `_end` or `end`, and ends it at `__heap_end` if there is one.
+    pub fn heap_from(self, symbols: &SymbolTable) -> Self {
+        let start = ["__heap_start", "_end", "end"]
+            .iter()
+            .find_map(|name| symbols.address_of(name))
+            .unwrap_or(self.heap_start);
+        let limit = symbols.address_of("__heap_end").unwrap_or(self.heap_limit);
+        self.heap(start, limit)
+    }
+
+    fn open(&mut self, context: &SyscallContext, path: i32, flags: i32) -> Result<i32, i32> {
+        let path = context
+            .read_c_string(path as u32, MAX_PATH)
+            .map_err(|_| EFAULT)?;
+        let path = String::from_utf8(path).map_err(|_| EINVAL)?;
+        let host = self.io.host_path(&path).ok_or(EACCES)?;
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        if flags & O_APPEND != 0 {
+            options.append(true);
+        }
+        if flags & O_TRUNC != 0 {
+            options.truncate(true);
+        }
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+        let file = options.open(host).map_err(|e| errno(&e))?;
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(fd, file);
+        Ok(fd)
+    }
+
+    fn read(
+        &mut self,
+        context: &mut SyscallContext,
+        fd: i32,
+        buffer: i32,
+        len: i32,
+    ) -> Result<i32, i32> {
+        // Only allocate once the guest's buffer is known to be backed by memory
+        if !context.is_mapped(buffer as u32, len.max(0) as usize) {
+            return Err(EFAULT);
+        }
+        let mut data = vec![0; len.max(0) as usize];
+        let count = match fd {
+            0 => self.io.stdin.read(&mut data),
+            1 | 2 => return Err(EBADF),
+            _ => self.files.get_mut(&fd).ok_or(EBADF)?.read(&mut data),
+        }
+        .map_err(|e| errno(&e))?;
+        context
+            .write_memory(buffer as u32, &data[..count])
+            .map_err(|_| EFAULT)?;
+        Ok(count as i32)
+    }
+
+    fn write(
+        &mut self,
+        context: &SyscallContext,
+        fd: i32,
+        buffer: i32,
+        len: i32,
+    ) -> Result<i32, i32> {
+        let data = context
+            .read_memory(buffer as u32, len.max(0) as usize)
+            .map_err(|_| EFAULT)?;
+        let stream: &mut dyn Write = match fd {
+            0 => return Err(EBADF),
+            1 => &mut self.io.stdout,
+            2 => &mut self.io.stderr,
+            _ => self.files.get_mut(&fd).ok_or(EBADF)?,
+        };
+        stream
+            .write_all(&data)
+            .and_then(|_| stream.flush())
+            .map_err(|e| errno(&e))?;
+        Ok(data.len() as i32)
+    }
+
+    fn lseek(&mut self, fd: i32, offset: i32, whence: i32) -> Result<i32, i32> {
+        let file = match fd {
+            0..=2 => return Err(ESPIPE),
+            _ => self.files.get_mut(&fd).ok_or(EBADF)?,
+        };
+        let position = match whence {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(EINVAL),
+        };
+        let position = file.seek(position).map_err(|e| errno(&e))?;
+        Ok(position as i32)
+    }
+
+    fn fstat(&mut self, context: &mut SyscallContext, fd: i32, buffer: i32) -> Result<i32, i32> {
+        let (mode, size) = match fd {
+            0..=2 => (S_IFCHR | 0o620, 0),
+            _ => {
+                let metadata = self
+                    .files
+                    .get(&fd)
+                    .ok_or(EBADF)?
+                    .metadata()
+                    .map_err(|e| errno(&e))?;
+                (S_IFREG | 0o644, metadata.len())
+            }
+        };
+        let mut stat = [0u8; STAT_SIZE];
+        stat[16..20].copy_from_slice(&mode.to_le_bytes());
+        stat[20..24].copy_from_slice(&1u32.to_le_bytes()); // st_nlink
+        stat[48..56].copy_from_slice(&size.to_le_bytes());
+        stat[56..60].copy_from_slice(&512u32.to_le_bytes()); // st_blksize
+        context
+            .write_memory(buffer as u32, &stat)
+            .map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+
+    fn gettimeofday(&mut self, context: &mut SyscallContext, buffer: i32) -> Result<i32, i32> {
+        if buffer == 0 {
+            return Ok(0);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // struct timeval, with a 64 bit time_t
+        let mut timeval = [0u8; 16];
+        timeval[0..8].copy_from_slice(&now.as_secs().to_le_bytes());
+        timeval[8..12].copy_from_slice(&now.subsec_micros().to_le_bytes());
+        context
+            .write_memory(buffer as u32, &timeval)
+            .map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+
+    // Like Linux, returns the new break on success and the old one on failure
+    fn brk(&mut self, address: i32) -> i32 {
+        let address = address as u32;
+        if (self.heap_start..=self.heap_limit).contains(&address) {
+            self.program_break = address;
+        }
+        self.program_break as i32
+    }
+}
+
+impl SyscallHandler for NewlibSyscalls {
+    fn ecall(&mut self, context: &mut SyscallContext) -> SyscallAction {
+        let args: Vec<i32> = (10..14).map(|idx| context.register(idx)).collect();
+        let result = match context.register(17) {
+            SYS_EXIT | SYS_EXIT_GROUP => return SyscallAction::Exit(args[0]),
+            SYS_OPEN => self.open(context, args[0], args[1]),
+            // Relative paths always start at the sandbox root, so the directory is ignored
+            SYS_OPENAT => self.open(context, args[1], args[2]),
+            SYS_CLOSE => match args[0] {
+                0..=2 => Ok(0),
+                fd => self.files.remove(&fd).map(|_| 0).ok_or(EBADF),
+            },
+            SYS_LSEEK => self.lseek(args[0], args[1], args[2]),
+            SYS_READ => self.read(context, args[0], args[1], args[2]),
+            SYS_WRITE => self.write(context, args[0], args[1], args[2]),
+            SYS_FSTAT => self.fstat(context, args[0], args[1]),
+            SYS_GETTIMEOFDAY => self.gettimeofday(context, args[0]),
+            SYS_BRK => Ok(self.brk(args[0])),
+            _ => Err(ENOSYS),
+        };
+        // Failures are reported to the guest as a negative errno
+        context.set_register(10, result.unwrap_or_else(|errno| -errno));
+        SyscallAction::Continue
+    }
+}
+
+fn errno(error: &io::Error) -> i32 {
+    match error.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::InvalidInput => EINVAL,
+        _ => EIO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscall::SharedBuffer;
+    use crate::{assembler::assemble, Pineapple, StepError};
+
+    fn run(source: &str, data: &[u8], handler: NewlibSyscalls) -> (Pineapple, StepError) {
+        let mut pineapple = Pineapple::new();
+        pineapple.set_program(&assemble(source).unwrap(), 0);
+        pineapple.load_binary(data, 0x1000).unwrap();
+        pineapple.set_syscall_handler(Box::new(handler));
+        let stop = pineapple.run_for(1000).err().unwrap();
+        (pineapple, stop)
+    }
+
+    #[test]
+    fn write_to_stdout_and_exit() {
+        let stdout = SharedBuffer::default();
+        let (_, stop) = run(
+            "
+            li a0, 1
+            li a1, 0x1000
+            li a2, 6
+            li a7, 64
+            ecall
+            mv s0, a0
+            li a0, 5
+            li a7, 93
+            ecall
+            ",
+            b"hello\n",
+            NewlibSyscalls::new().stdout(Box::new(stdout.clone())),
+        );
+        assert_eq!(stop, StepError::Exit(5));
+        assert_eq!(*stdout.0.lock().unwrap(), b"hello\n");
+    }
+
+    #[test]
+    fn read_from_stdin_and_brk() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("_end", 0x10000);
+        symbols.insert("__heap_end", 0x18000);
+        let (pineapple, _) = run(
+            "
+            li a0, 0
+            li a1, 0x1000
+            li a2, 16
+            li a7, 63
+            ecall
+            mv s0, a0
+            li a0, 0
+            li a7, 214
+            ecall
+            mv s1, a0
+            li a0, 0x30000
+            ecall
+            mv s2, a0
+            li a0, 0x12000
+            ecall
+            mv s3, a0
+            li a0, 0x8000       # Below the start of the heap
+            ecall
+            mv s4, a0
+            li a0, 0
+            li a1, 0x1000
+            li a2, 0x7FFFFFFF   # Far more than RAM holds
+            li a7, 63
+            ecall
+            mv s5, a0
+            li a7, 93
+            ecall
+            ",
+            &[],
+            NewlibSyscalls::new()
+                .stdin(Box::new(&b"abc"[..]))
+                .heap_from(&symbols),
+        );
+        let registers = pineapple.get_registers().unwrap();
+        assert_eq!(registers[8..10], [3, 0x10000]);
+        assert_eq!(registers[18..22], [0x10000, 0x12000, 0x12000, -EFAULT]);
+        assert_eq!(pineapple.get_data_range(0x1000, 0x1004), Ok(vec![0x636261]));
+    }
+
+    #[test]
+    fn sandboxed_files() {
+        let sandbox = std::env::temp_dir().join(format!("pineapple-newlib-{}", std::process::id()));
+        std::fs::create_dir_all(&sandbox).unwrap();
+        let mut data = b"/out.txt\0../escape\0".to_vec();
+        data.resize(0x20, 0);
+        data.extend_from_slice(b"data");
+        let (pineapple, stop) = run(
+            "
+            li a0, 0x1000
+            li a1, 0x601        # O_WRONLY | O_CREAT | O_TRUNC
+            li a7, 1024
+            ecall
+            mv s0, a0
+            li a1, 0x1020
+            li a2, 4
+            li a7, 64
+            ecall
+            mv a0, s0
+            li a7, 57
+            ecall
+            li a0, -100
+            li a1, 0x1009
+            li a2, 0
+            li a7, 56
+            ecall
+            mv s1, a0
+            li a0, 0
+            li a7, 93
+            ecall
+            ",
+            &data,
+            NewlibSyscalls::new().sandbox(&sandbox),
+        );
+        assert_eq!(stop, StepError::Exit(0));
+        let registers = pineapple.get_registers().unwrap();
+        assert_eq!(registers[8], 3);
+        assert_eq!(registers[9], -EACCES);
+        assert_eq!(std::fs::read(sandbox.join("out.txt")).unwrap(), b"data");
+        std::fs::remove_dir_all(&sandbox).unwrap();
+    }
+}
@@ -1,192 +1,237 @@
-use crate::instruction::sign_extend;
-use crate::{instruction::Instruction, Pineapple};
+use crate::csr::CsrFile;
+use crate::semihosting;
+use crate::syscall::{SyscallAction, SyscallContext};
+use crate::{instruction::Instruction, MemoryModel, Pineapple, StepError};
 
 impl Pineapple {
-    pub(crate) fn process_instruction(&mut self, instruction: &Instruction) {
+    // `size` is the length of the instruction in bytes, which is 2 for compressed instructions
+    pub(crate) fn process_instruction(
+        &mut self,
+        instruction: &Instruction,
+        size: usize,
+    ) -> Result<(), StepError> {
         let mut registers = self.general_register.write().unwrap();
         let mut pc = self.program_counter.write().unwrap();
+        let model = self.memory_model;
+        // Jumps and branches overwrite the PC again, the caller restores it if this fails
+        let address = *pc;
+        *pc = model.jump_target(address, size as i32);
         match instruction {
             Instruction::LUI (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 registers[i.rd] = i.imm;
             }
             Instruction::AUIPC (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
-                registers[i.rd] = *pc as i32 + i.imm;
+                registers[i.rd] = (address as i32).wrapping_add(match model {
+                    MemoryModel::Unified => i.imm,
+                    MemoryModel::Harvard => i.imm >> 2,
+                });
             }
             Instruction::JAL (i) => {
-                let target_address = *pc as i32 + i.imm;
+                let target_address = self.aligned_target(model.jump_target(address, i.imm))?;
                 if i.rd != 0 {
-                    registers[i.rd] = *pc as i32 + 1
+                    registers[i.rd] = *pc as i32
                 }
-                *pc = target_address as usize;
+                *pc = target_address;
             }
             Instruction::JALR (i) => {
-                let target_address = (registers[i.rs1] + i.imm) & -2;
+                let target_address = model.jump_target(registers[i.rs1] as u32 as usize, i.imm);
+                let target_address = self.aligned_target(target_address)?;
                 if i.rd != 0 {
-                    registers[i.rd] = *pc as i32 + 1
+                    registers[i.rd] = *pc as i32
                 }
-                *pc = target_address as usize;
+                *pc = target_address;
             }
             Instruction::BEQ (i) => {
                 if registers[i.rs1] == registers[i.rs2] {
-                    let (result, _) = (*pc as i32).overflowing_add(i.imm);
-                    *pc = result as usize;
+                    *pc = self.aligned_target(model.jump_target(address, i.imm))?;
                 }
             }
             Instruction::BNE (i) => {
                 if registers[i.rs1] != registers[i.rs2] {
-                    let (result, _) = (*pc as i32).overflowing_add(i.imm);
-                    *pc = result as usize;
+                    *pc = self.aligned_target(model.jump_target(address, i.imm))?;
                 }
             }
             Instruction::BLT (i) => {
                 if registers[i.rs1] < registers[i.rs2] {
-                    let (result, _) = (*pc as i32).overflowing_add(i.imm);
-                    *pc = result as usize;
+                    *pc = self.aligned_target(model.jump_target(address, i.imm))?;
                 }
             }
             Instruction::BGE (i) => {
                 if registers[i.rs1] >= registers[i.rs2] {
-                    let (result, _) = (*pc as i32).overflowing_add(i.imm);
-                    *pc = result as usize;
+                    *pc = self.aligned_target(model.jump_target(address, i.imm))?;
                 }
             }
             Instruction::BLTU (i) => {
                 if (registers[i.rs1] as u32) < (registers[i.rs2] as u32) {
-                    let (result, _) = (*pc as i32).overflowing_add(i.imm);
-                    *pc = result as usize;
+                    *pc = self.aligned_target(model.jump_target(address, i.imm))?;
                 }
             }
             Instruction::BGEU (i) => {
                 if (registers[i.rs1] as u32) >= (registers[i.rs2] as u32) {
-                    let (result, _) = (*pc as i32).overflowing_add(i.imm);
-                    *pc = result as usize;
+                    *pc = self.aligned_target(model.jump_target(address, i.imm))?;
                 }
             }
             Instruction::LB (i) => {
-                let offset = (registers[i.rs1] +i.imm) as usize;
-                let data = self.data_memory.read_i32(offset);
-                registers[i.rd] = extract_bits!(@extend data[7;0]);
+                let source = effective_address(&registers, i.rs1, i.imm);
+                let value = self.memory().read_u8(source)
+                    .map_err(|_| StepError::LoadAccessFault(source as u32))?;
+                if i.rd != 0 {
+                    registers[i.rd] = value as i8 as i32;
+                }
             }
             Instruction::LH (i) => {
-                let offset = (registers[i.rs1] +i.imm) as usize;
-                let data = self.data_memory.read_i32(offset);
-                registers[i.rd] = extract_bits!(@extend data[15;0]);
+                let source = effective_address(&registers, i.rs1, i.imm);
+                if !self.misaligned.allow(source, 2) {
+                    return Err(StepError::LoadMisaligned(source as u32));
+                }
+                let value = self.memory().read_u16(source)
+                    .map_err(|_| StepError::LoadAccessFault(source as u32))?;
+                if i.rd != 0 {
+                    registers[i.rd] = value as i16 as i32;
+                }
             }
             Instruction::LW (i) => {
-                registers[i.rd] = self.data_memory.read_i32((registers[i.rs1] +i.imm) as usize)
+                let source = effective_address(&registers, i.rs1, i.imm);
+                if !self.misaligned.allow(source, 4) {
+                    return Err(StepError::LoadMisaligned(source as u32));
+                }
+                let value = self.memory().read_u32(source)
+                    .map_err(|_| StepError::LoadAccessFault(source as u32))?;
+                if i.rd != 0 {
+                    registers[i.rd] = value as i32;
+                }
             }
             Instruction::LBU (i) => {
-                let offset = (registers[i.rs1] +i.imm) as usize;
-                let data = self.data_memory.read_i32(offset);
-                registers[i.rd] = extract_bits!(data[15;0]);
+                let source = effective_address(&registers, i.rs1, i.imm);
+                let value = self.memory().read_u8(source)
+                    .map_err(|_| StepError::LoadAccessFault(source as u32))?;
+                if i.rd != 0 {
+                    registers[i.rd] = value as i32;
+                }
             }
             Instruction::LHU (i) => {
-                let destination = (registers[i.rs1] +i.imm) as usize;
-                let data = self.data_memory.read_i32(destination);
-                registers[i.rd] = extract_bits!(data[15;0]);
+                let source = effective_address(&registers, i.rs1, i.imm);
+                if !self.misaligned.allow(source, 2) {
+                    return Err(StepError::LoadMisaligned(source as u32));
+                }
+                let value = self.memory().read_u16(source)
+                    .map_err(|_| StepError::LoadAccessFault(source as u32))?;
+                if i.rd != 0 {
+                    registers[i.rd] = value as i32;
+                }
             }
             Instruction::SB (i) => {
-                let destination = (registers[i.rs1] +i.imm) as usize;
-                self.data_memory
-                    .write_i32(destination, registers[i.rs2] & 0xFF);
+                let destination = effective_address(&registers, i.rs1, i.imm);
+                self.memory().write_u8(destination, registers[i.rs2] as u8)
+                    .map_err(|_| StepError::StoreAccessFault(destination as u32))?;
             }
             Instruction::SH (i) => {
-                let destination = (registers[i.rs1] +i.imm) as usize;
-                self.data_memory
-                    .write_i32(destination, registers[i.rs2] & 0xFFFF);
+                let destination = effective_address(&registers, i.rs1, i.imm);
+                if !self.misaligned.allow(destination, 2) {
+                    return Err(StepError::StoreMisaligned(destination as u32));
+                }
+                self.memory().write_u16(destination, registers[i.rs2] as u16)
+                    .map_err(|_| StepError::StoreAccessFault(destination as u32))?;
             }
             Instruction::SW (i) => {
-                let destination = (registers[i.rs1] +i.imm) as usize;
-                self.data_memory.write_i32(destination, registers[i.rs2]);
+                let destination = effective_address(&registers, i.rs1, i.imm);
+                if !self.misaligned.allow(destination, 4) {
+                    return Err(StepError::StoreMisaligned(destination as u32));
+                }
+                self.memory().write_u32(destination, registers[i.rs2] as u32)
+                    .map_err(|_| StepError::StoreAccessFault(destination as u32))?;
             }
             Instruction::ADDI (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 let (value, _) = registers[i.rs1].overflowing_add(i.imm);
                 registers[i.rd] = value;
             }
             Instruction::SLTI (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
-                registers[i.rd as usize] = match registers[i.rs1] < i.imm {
+                registers[i.rd] = match registers[i.rs1] < i.imm {
                     true => 1,
                     false => 0,
                 };
             }
             Instruction::SLTIU (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
-                registers[i.rd as usize] = match (registers[i.rs1] as u32) < (i.imm as u32) {
+                registers[i.rd] = match (registers[i.rs1] as u32) < (i.imm as u32) {
                     true => 1,
                     false => 0,
                 };
             }
             Instruction::XORI (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 registers[i.rd] = registers[i.rs1] ^i.imm;
             }
             Instruction::ORI (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 registers[i.rd] = registers[i.rs1] |i.imm;
             }
             Instruction::ANDI (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 registers[i.rd] = registers[i.rs1] &i.imm;
             }
             Instruction::SLLI (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 registers[i.rd] = registers[i.rs1] << i.imm;
             }
             Instruction::SRLI (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 registers[i.rd] = ((registers[i.rs1] as u32) >> i.imm) as i32;
             }
             Instruction::SRAI (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 registers[i.rd] = registers[i.rs1] >> i.imm;
             }
             Instruction::ADD (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 let (result, _) = registers[i.rs1].overflowing_add(registers[i.rs2]);
                 registers[i.rd] = result;
             }
             Instruction::SUB (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 let (result, _) = registers[i.rs1].overflowing_sub(registers[i.rs2]);
                 registers[i.rd] = result;
             }
             Instruction::SLL (i) => {
+                if i.rd == 0 {
+                    return Ok(());
+                }
                 // Lower five bits
                 registers[i.rd] = registers[i.rs1] << (registers[i.rs2] & 0x1F)
             }
             Instruction::SLT (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 registers[i.rd] = match registers[i.rs1] < registers[i.rs2] {
                     true => 1,
@@ -195,7 +240,7 @@ impl Pineapple {
             }
             Instruction::SLTU (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 registers[i.rd] = match (registers[i.rs1] as u32) < (registers[i.rs2] as u32) {
                     true => 1,
@@ -204,54 +249,241 @@ impl Pineapple {
             }
             Instruction::XOR (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 registers[i.rd] = registers[i.rs1] ^ registers[i.rs2];
             }
             Instruction::SRL (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 // Specifically uses the lower 5 bits only
                 registers[i.rd] = (registers[i.rs1] as u32 >> (registers[i.rs2] & 0b11111)) as i32
             }
             Instruction::SRA (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 // Specifically uses the lower 5 bits only
                 registers[i.rd] = registers[i.rs1] >> (registers[i.rs2] & 0b11111)
             }
             Instruction::OR (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 registers[i.rd] = registers[i.rs1] | registers[i.rs2];
             }
             Instruction::AND (i) => {
                 if i.rd == 0 {
-                    return;
+                    return Ok(());
                 }
                 registers[i.rd] = registers[i.rs1] & registers[i.rs2];
             }
-            Instruction::FENCE {
-                fm,
-                pred,
-                succ,
-                rs1,
-                rd,
-            } => {
-                unimplemented!(
-                    "Unimplemented instruction! {}{}{}{}{}",
-                    fm,
-                    pred,
-                    succ,
-                    rs1,
-                    rd
-                )
-            }
-            Instruction::ECALL => todo!(),
-            Instruction::EBREAK => todo!(),
+            Instruction::MUL (i) => {
+                if i.rd == 0 {
+                    return Ok(());
+                }
+                registers[i.rd] = registers[i.rs1].wrapping_mul(registers[i.rs2]);
+            }
+            Instruction::MULH (i) => {
+                if i.rd == 0 {
+                    return Ok(());
+                }
+                let product = registers[i.rs1] as i64 * registers[i.rs2] as i64;
+                registers[i.rd] = (product >> 32) as i32;
+            }
+            Instruction::MULHSU (i) => {
+                if i.rd == 0 {
+                    return Ok(());
+                }
+                let product = registers[i.rs1] as i64 * registers[i.rs2] as u32 as i64;
+                registers[i.rd] = (product >> 32) as i32;
+            }
+            Instruction::MULHU (i) => {
+                if i.rd == 0 {
+                    return Ok(());
+                }
+                let product = registers[i.rs1] as u32 as u64 * registers[i.rs2] as u32 as u64;
+                registers[i.rd] = (product >> 32) as i32;
+            }
+            // Division never traps: dividing by zero gives all ones (or the dividend for the
+            // remainder), and i32::MIN / -1 overflows back to i32::MIN with a remainder of 0
+            Instruction::DIV (i) => {
+                if i.rd == 0 {
+                    return Ok(());
+                }
+                registers[i.rd] = match registers[i.rs2] {
+                    0 => -1,
+                    divisor => registers[i.rs1].wrapping_div(divisor),
+                };
+            }
+            Instruction::DIVU (i) => {
+                if i.rd == 0 {
+                    return Ok(());
+                }
+                registers[i.rd] = match registers[i.rs2] as u32 {
+                    0 => -1,
+                    divisor => ((registers[i.rs1] as u32) / divisor) as i32,
+                };
+            }
+            Instruction::REM (i) => {
+                if i.rd == 0 {
+                    return Ok(());
+                }
+                registers[i.rd] = match registers[i.rs2] {
+                    0 => registers[i.rs1],
+                    divisor => registers[i.rs1].wrapping_rem(divisor),
+                };
+            }
+            Instruction::REMU (i) => {
+                if i.rd == 0 {
+                    return Ok(());
+                }
+                registers[i.rd] = match registers[i.rs2] as u32 {
+                    0 => registers[i.rs1],
+                    divisor => ((registers[i.rs1] as u32) % divisor) as i32,
+                };
+            }
+            Instruction::LR (i) => {
+                let source = registers[i.rs1] as u32 as usize;
+                if !source.is_multiple_of(4) {
+                    return Err(StepError::LoadMisaligned(source as u32));
+                }
+                let value = self.memory().load_reserved(self.hart_id, source)
+                    .map_err(|_| StepError::LoadAccessFault(source as u32))?;
+                if i.rd != 0 {
+                    registers[i.rd] = value as i32;
+                }
+            }
+            // SC and the AMOs fault like stores, even when they only end up reading
+            Instruction::SC (i) => {
+                let destination = registers[i.rs1] as u32 as usize;
+                if !destination.is_multiple_of(4) {
+                    return Err(StepError::StoreMisaligned(destination as u32));
+                }
+                let stored = self.memory()
+                    .store_conditional(self.hart_id, destination, registers[i.rs2] as u32)
+                    .map_err(|_| StepError::StoreAccessFault(destination as u32))?;
+                if i.rd != 0 {
+                    registers[i.rd] = if stored { 0 } else { 1 };
+                }
+            }
+            Instruction::AMOSWAP (i)
+            | Instruction::AMOADD (i)
+            | Instruction::AMOXOR (i)
+            | Instruction::AMOAND (i)
+            | Instruction::AMOOR (i)
+            | Instruction::AMOMIN (i)
+            | Instruction::AMOMAX (i)
+            | Instruction::AMOMINU (i)
+            | Instruction::AMOMAXU (i) => {
+                let destination = registers[i.rs1] as u32 as usize;
+                if !destination.is_multiple_of(4) {
+                    return Err(StepError::StoreMisaligned(destination as u32));
+                }
+                let fault = |_| StepError::StoreAccessFault(destination as u32);
+                // Holding the bus for both halves keeps other harts out of the middle
+                let mut memory = self.memory();
+                let old = memory.read_u32(destination).map_err(fault)? as i32;
+                let operand = registers[i.rs2];
+                let new = match instruction {
+                    Instruction::AMOSWAP (_) => operand,
+                    Instruction::AMOADD (_) => old.wrapping_add(operand),
+                    Instruction::AMOXOR (_) => old ^ operand,
+                    Instruction::AMOAND (_) => old & operand,
+                    Instruction::AMOOR (_) => old | operand,
+                    Instruction::AMOMIN (_) => old.min(operand),
+                    Instruction::AMOMAX (_) => old.max(operand),
+                    Instruction::AMOMINU (_) => (old as u32).min(operand as u32) as i32,
+                    _ => (old as u32).max(operand as u32) as i32,
+                };
+                memory.write_u32(destination, new as u32).map_err(fault)?;
+                if i.rd != 0 {
+                    registers[i.rd] = old;
+                }
+            }
+            Instruction::FENCE { .. } => {
+                // A single in-order hart never observes reordered memory, so this is a no-op
+            }
+            Instruction::ECALL => {
+                let handler = match self.syscall_handler.as_mut() {
+                    Some(handler) => handler,
+                    None => return Err(StepError::EnvironmentCall),
+                };
+                let mut memory = self.data_memory.lock().unwrap();
+                self.special_registry.serve(self.hart_id);
+                let mut context = SyscallContext {
+                    registers: &mut registers,
+                    memory: &mut memory,
+                };
+                match handler.ecall(&mut context) {
+                    SyscallAction::Continue => {}
+                    SyscallAction::Exit(code) => return Err(StepError::Exit(code)),
+                    SyscallAction::Fault(reason) => return Err(StepError::SyscallFault(reason)),
+                }
+            }
+            Instruction::EBREAK => {
+                // Semihosting calls are marked by a pair of otherwise pointless shifts around an
+                // uncompressed EBREAK
+                let is_semihosting = size == 4
+                    && self.fetch(model.jump_target(address, -4))
+                    == Ok(semihosting::ENTRY_NOP)
+                    && self.fetch(*pc) == Ok(semihosting::EXIT_NOP);
+                let handler = match self.semihosting_handler.as_mut() {
+                    Some(handler) if is_semihosting => handler,
+                    _ => return Err(StepError::Breakpoint),
+                };
+                let mut memory = self.data_memory.lock().unwrap();
+                self.special_registry.serve(self.hart_id);
+                let mut context = SyscallContext {
+                    registers: &mut registers,
+                    memory: &mut memory,
+                };
+                match handler.semihost(&mut context) {
+                    SyscallAction::Continue => {}
+                    SyscallAction::Exit(code) => return Err(StepError::Exit(code)),
+                    SyscallAction::Fault(reason) => return Err(StepError::SyscallFault(reason)),
+                }
+            }
+            Instruction::MRET => {
+                *pc = model.code_address(self.traps.mret());
+            }
+            // Interrupts are checked before every step, so waiting is the same as carrying on
+            Instruction::WFI => {}
+            Instruction::CSRRW (i) | Instruction::CSRRS (i) | Instruction::CSRRC (i) => {
+                let mut csrs = CsrFile {
+                    traps: &mut self.traps,
+                    instret: &mut self.instret,
+                    misa: self.extensions.misa(),
+                    mhartid: self.hart_id as u32,
+                    registry: &self.special_registry,
+                    clint: &self.clint,
+                };
+                let old = csrs.execute(instruction, registers[i.rs1] as u32)?;
+                if i.rd != 0 {
+                    registers[i.rd] = old as i32;
+                }
+            }
+            Instruction::CSRRWI (i) | Instruction::CSRRSI (i) | Instruction::CSRRCI (i) => {
+                let mut csrs = CsrFile {
+                    traps: &mut self.traps,
+                    instret: &mut self.instret,
+                    misa: self.extensions.misa(),
+                    mhartid: self.hart_id as u32,
+                    registry: &self.special_registry,
+                    clint: &self.clint,
+                };
+                let old = csrs.execute(instruction, i.rs1 as u32)?;
+                if i.rd != 0 {
+                    registers[i.rd] = old as i32;
+                }
+            }
         }
+        Ok(())
     }
 }
+
+// rs1 + imm, wrapping around the 32 bit address space
+fn effective_address(registers: &[i32], rs1: usize, imm: i32) -> usize {
+    registers[rs1].wrapping_add(imm) as u32 as usize
+}
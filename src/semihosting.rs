@@ -0,0 +1,327 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    time::Instant,
+};
+
+use crate::syscall::{HostIo, HostIoBuilder, SyscallAction, SyscallContext};
+
+/// `slli x0, x0, 0x1f`, which has to come right before the EBREAK of a semihosting call
+pub(crate) const ENTRY_NOP: i32 = 0x01f01013;
+/// `srai x0, x0, 7`, which has to come right after the EBREAK of a semihosting call
+pub(crate) const EXIT_NOP: i32 = 0x40705013;
+
+// Operation numbers from the ARM semihosting specification
+const SYS_OPEN: i32 = 0x01;
+const SYS_CLOSE: i32 = 0x02;
+const SYS_WRITEC: i32 = 0x03;
+const SYS_WRITE0: i32 = 0x04;
+const SYS_WRITE: i32 = 0x05;
+const SYS_READ: i32 = 0x06;
+const SYS_CLOCK: i32 = 0x10;
+const SYS_EXIT: i32 = 0x18;
+const SYS_EXIT_EXTENDED: i32 = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: i32 = 0x20026;
+const MAX_PATH: usize = 4096;
+
+/// Host side implementation of semihosting calls.
+///
+/// A semihosting call is an EBREAK sandwiched between `slli x0, x0, 0x1f` and
+/// `srai x0, x0, 7`. The operation number is in a0 and a pointer to its parameter block, or
+/// for some operations the parameter itself, is in a1. Results go back in a0.
+///
+/// Register one with [`crate::Pineapple::set_semihosting_handler`].
+pub trait SemihostingHandler {
+    fn semihost(&mut self, context: &mut SyscallContext) -> SyscallAction;
+}
+
+enum Stream {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// A [`SemihostingHandler`] implementing SYS_OPEN, SYS_CLOSE, SYS_WRITEC, SYS_WRITE0, SYS_WRITE,
+/// SYS_READ, SYS_CLOCK, SYS_EXIT and SYS_EXIT_EXTENDED.
+///
+/// Opening the special file `:tt` gives the console: stdin for read modes, stdout for write
+/// modes and stderr for append modes. Other files can only be opened inside the sandbox
+/// directory, without one every open fails.
+pub struct Semihosting {
+    io: HostIo,
+    handles: HashMap<i32, Stream>,
+    next_handle: i32,
+    started: Instant,
+}
+
+impl HostIoBuilder for Semihosting {
+    fn host_io(&mut self) -> &mut HostIo {
+        &mut self.io
+    }
+}
+
+impl Default for Semihosting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Semihosting {
+    /// Creates a handler wired up to the host process' own stdin, stdout and stderr.
+    pub fn new() -> Self {
+        Semihosting {
+            io: HostIo::default(),
+            handles: HashMap::new(),
+            next_handle: 1,
+            started: Instant::now(),
+        }
+    }
+
+    fn open(&mut self, context: &SyscallContext, block: u32) -> Option<i32> {
+        let name = context
+            .read_c_string(read_word(context, block, 0)? as u32, MAX_PATH)
+            .ok()?;
+        let name = String::from_utf8(name).ok()?;
+        // Modes follow fopen: "r", "rb", "r+", "r+b", then the same for "w" and "a"
+        let mode = read_word(context, block, 1)?;
+        let stream = if name == ":tt" {
+            match mode {
+                0..=3 => Stream::Stdin,
+                4..=7 => Stream::Stdout,
+                _ => Stream::Stderr,
+            }
+        } else {
+            let host = self.io.host_path(&name)?;
+            let mut options = OpenOptions::new();
+            match mode / 4 {
+                0 => options.read(true),
+                1 => options.write(true).create(true).truncate(true),
+                _ => options.append(true).create(true),
+            };
+            if mode % 4 >= 2 {
+                options.read(true).write(true);
+            }
+            Stream::File(options.open(host).ok()?)
+        };
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(handle, stream);
+        Some(handle)
+    }
+
+    // Returns how many bytes could not be written
+    fn write(&mut self, context: &SyscallContext, block: u32) -> Option<i32> {
+        let handle = read_word(context, block, 0)?;
+        let len = read_word(context, block, 2)?;
+        let data = context
+            .read_memory(read_word(context, block, 1)? as u32, len.max(0) as usize)
+            .ok()?;
+        let stream: &mut dyn Write = match self.handles.get_mut(&handle)? {
+            Stream::Stdin => return Some(len),
+            Stream::Stdout => &mut self.io.stdout,
+            Stream::Stderr => &mut self.io.stderr,
+            Stream::File(file) => file,
+        };
+        match stream.write_all(&data).and_then(|_| stream.flush()) {
+            Ok(()) => Some(0),
+            Err(_) => Some(len),
+        }
+    }
+
+    // Returns how many of the requested bytes were not read
+    fn read(&mut self, context: &mut SyscallContext, block: u32) -> Option<i32> {
+        let handle = read_word(context, block, 0)?;
+        let buffer = read_word(context, block, 1)?;
+        let len = read_word(context, block, 2)?;
+        // Only allocate once the guest's buffer is known to be backed by memory
+        if !context.is_mapped(buffer as u32, len.max(0) as usize) {
+            return None;
+        }
+        let mut data = vec![0; len.max(0) as usize];
+        let count = match self.handles.get_mut(&handle)? {
+            Stream::Stdin => self.io.stdin.read(&mut data),
+            Stream::File(file) => file.read(&mut data),
+            Stream::Stdout | Stream::Stderr => return Some(len),
+        }
+        .ok()?;
+        context.write_memory(buffer as u32, &data[..count]).ok()?;
+        Some(len - count as i32)
+    }
+
+    fn console(&mut self, data: &[u8]) {
+        // The console has nowhere to report errors to
+        let _ = self
+            .io
+            .stdout
+            .write_all(data)
+            .and_then(|_| self.io.stdout.flush());
+    }
+}
+
+impl SemihostingHandler for Semihosting {
+    fn semihost(&mut self, context: &mut SyscallContext) -> SyscallAction {
+        let parameter = context.register(11);
+        let result = match context.register(10) {
+            SYS_OPEN => self.open(context, parameter as u32).unwrap_or(-1),
+            SYS_CLOSE => match read_word(context, parameter as u32, 0)
+                .and_then(|handle| self.handles.remove(&handle))
+            {
+                Some(_) => 0,
+                None => -1,
+            },
+            SYS_WRITEC => match context.read_memory(parameter as u32, 1) {
+                Ok(data) => {
+                    self.console(&data);
+                    return SyscallAction::Continue;
+                }
+                Err(()) => return SyscallAction::Fault("Bad SYS_WRITEC address".to_string()),
+            },
+            SYS_WRITE0 => match context.read_c_string(parameter as u32, usize::MAX) {
+                Ok(data) => {
+                    self.console(&data);
+                    return SyscallAction::Continue;
+                }
+                Err(()) => return SyscallAction::Fault("Bad SYS_WRITE0 address".to_string()),
+            },
+            SYS_WRITE => self.write(context, parameter as u32).unwrap_or(-1),
+            SYS_READ => self.read(context, parameter as u32).unwrap_or(-1),
+            // Centiseconds since the handler was created
+            SYS_CLOCK => (self.started.elapsed().as_millis() / 10) as i32,
+            // On 32 bit targets the parameter is the reason itself rather than a block
+            SYS_EXIT => match parameter {
+                ADP_STOPPED_APPLICATION_EXIT => return SyscallAction::Exit(0),
+                _ => return SyscallAction::Exit(1),
+            },
+            SYS_EXIT_EXTENDED => {
+                return match (
+                    read_word(context, parameter as u32, 0),
+                    read_word(context, parameter as u32, 1),
+                ) {
+                    (Some(ADP_STOPPED_APPLICATION_EXIT), Some(code)) => SyscallAction::Exit(code),
+                    (Some(_), Some(_)) => SyscallAction::Exit(1),
+                    _ => SyscallAction::Fault("Bad SYS_EXIT_EXTENDED block".to_string()),
+                }
+            }
+            operation => {
+                return SyscallAction::Fault(format!(
+                    "Unsupported semihosting operation {:#x}",
+                    operation
+                ))
+            }
+        };
+        context.set_register(10, result);
+        SyscallAction::Continue
+    }
+}
+
+// Reads the `idx`th word of a parameter block
+fn read_word(context: &SyscallContext, block: u32, idx: u32) -> Option<i32> {
+    let bytes = context.read_memory(block.wrapping_add(idx * 4), 4).ok()?;
+    Some(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscall::SharedBuffer;
+    use crate::{assembler::assemble, Pineapple, StepError};
+
+    const SEMIHOST: &str = "
+        slli x0, x0, 0x1f
+        ebreak
+        srai x0, x0, 7
+    ";
+
+    fn load(source: &str, data: &[u8]) -> Pineapple {
+        let source = source.replace("semihost", SEMIHOST);
+        let mut pineapple = Pineapple::new();
+        pineapple.set_program(&assemble(&source).unwrap(), 0);
+        pineapple.load_binary(data, 0x1000).unwrap();
+        pineapple
+    }
+
+    fn words(words: &[i32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn console_output_and_exit() {
+        // 0x1000: ":tt", 0x1004: "hi\n", 0x1010: SYS_OPEN block, 0x1020: SYS_WRITE block
+        let mut data = b":tt\0hi\n\0".to_vec();
+        data.resize(0x10, 0);
+        data.extend(words(&[0x1000, 4, 3, 0]));
+        data.extend(words(&[1, 0x1004, 3]));
+        let mut pineapple = load(
+            "
+            li a0, 4
+            li a1, 0x1004
+            semihost
+            li a0, 1
+            li a1, 0x1010
+            semihost
+            mv s0, a0
+            li a0, 5
+            li a1, 0x1020
+            semihost
+            mv s1, a0
+            li a0, 0x10
+            semihost
+            mv s2, a0
+            li a0, 0x18
+            li a1, 0x20026
+            semihost
+            ",
+            &data,
+        );
+        let stdout = SharedBuffer::default();
+        pineapple.set_semihosting_handler(Box::new(
+            Semihosting::new().stdout(Box::new(stdout.clone())),
+        ));
+        assert_eq!(pineapple.run_for(1000), Err(StepError::Exit(0)));
+        assert_eq!(*stdout.0.lock().unwrap(), b"hi\nhi\n");
+        let registers = pineapple.get_registers().unwrap();
+        assert_eq!(registers[8..10], [1, 0]);
+        assert!(registers[18] >= 0);
+    }
+
+    #[test]
+    fn read_console_and_plain_breakpoints() {
+        let mut data = b":tt\0".to_vec();
+        data.extend(words(&[0x1000, 0, 3]));
+        data.extend(words(&[1, 0x1100, 8]));
+        data.extend(words(&[1, 0x1100, 0x7FFFFFFF]));
+        let mut pineapple = load(
+            "
+            li a0, 1
+            li a1, 0x1004
+            semihost
+            li a0, 6
+            li a1, 0x1010
+            semihost
+            mv s0, a0
+            li a0, 6
+            li a1, 0x101C
+            semihost
+            mv s1, a0
+            ebreak
+            ",
+            &data,
+        );
+        pineapple
+            .set_semihosting_handler(Box::new(Semihosting::new().stdin(Box::new(&b"abc"[..]))));
+        // An EBREAK without the surrounding shifts is still a breakpoint
+        assert_eq!(pineapple.run_for(1000), Err(StepError::Breakpoint));
+        // A buffer larger than memory fails without being allocated
+        assert_eq!(pineapple.get_registers().unwrap()[8..10], [5, -1]);
+        assert_eq!(pineapple.get_data_range(0x1100, 0x1104), Ok(vec![0x636261]));
+    }
+
+    #[test]
+    fn no_handler_is_a_breakpoint() {
+        let mut pineapple = load("li a0, 4\nsemihost", &[]);
+        assert_eq!(pineapple.run_for(10), Err(StepError::Breakpoint));
+    }
+}
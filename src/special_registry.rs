@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::bus::Device;
+
+/// Writing a value stops the simulation, with the value as the exit code. Reads as 0.
+pub const HALT: u32 = 0x0;
+/// Reads the low 32 bits of the cycle counter, writes are ignored.
+pub const CYCLES: u32 = 0x4;
+/// Bits 7:0 select the video mode. Writing with bit 31 set flips the frame, and bits 30:16 read
+/// back how many flips there have been.
+pub const VIDEO: u32 = 0x8;
+/// Writing a value passes it to the host's signal handler. Reads back the last value written.
+pub const SIGNAL: u32 = 0xC;
+
+const VIDEO_FLIP: u32 = 1 << 31;
+
+type SignalHandler = Box<dyn FnMut(u32) + Send>;
+
+/// State shared between the Special Registry on the bus and the [`crate::Pineapple`] owning it.
+#[derive(Default)]
+pub(crate) struct RegistryState {
+    // Exit codes written to the halt register, by the hart that wrote them
+    halt: Mutex<HashMap<usize, i32>>,
+    // The hart holding the bus, whose stores are being served
+    hart: AtomicUsize,
+    cycles: AtomicU64,
+    video_mode: AtomicU32,
+    video_flips: AtomicU32,
+    signal: AtomicU32,
+    signal_handler: Mutex<Option<SignalHandler>>,
+}
+
+impl RegistryState {
+    pub fn tick(&self) {
+        self.cycles.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles.load(Ordering::Relaxed)
+    }
+
+    pub fn set_cycles(&self, cycles: u64) {
+        self.cycles.store(cycles, Ordering::Relaxed);
+    }
+
+    /// Attributes the accesses that follow to `hart`, until another hart takes the bus.
+    pub fn serve(&self, hart: usize) {
+        self.hart.store(hart, Ordering::Relaxed);
+    }
+
+    /// The exit code `hart` wrote to the halt register since the last call, if any.
+    pub fn take_halt(&self, hart: usize) -> Option<i32> {
+        self.halt.lock().unwrap().remove(&hart)
+    }
+
+    pub fn video_mode(&self) -> u8 {
+        self.video_mode.load(Ordering::Relaxed) as u8
+    }
+
+    pub fn video_flips(&self) -> u32 {
+        self.video_flips.load(Ordering::Relaxed)
+    }
+
+    pub fn set_signal_handler(&self, handler: SignalHandler) {
+        *self.signal_handler.lock().unwrap() = Some(handler);
+    }
+
+    fn read_register(&self, register: u32) -> u32 {
+        match register {
+            CYCLES => self.cycles() as u32,
+            VIDEO => self.video_mode() as u32 | (self.video_flips() & 0x7FFF) << 16,
+            SIGNAL => self.signal.load(Ordering::Relaxed),
+            _ => 0,
+        }
+    }
+
+    fn write_register(&self, register: u32, data: u32) {
+        match register {
+            HALT => {
+                let hart = self.hart.load(Ordering::Relaxed);
+                self.halt.lock().unwrap().insert(hart, data as i32);
+            }
+            VIDEO => {
+                self.video_mode.store(data & 0xFF, Ordering::Relaxed);
+                if data & VIDEO_FLIP != 0 {
+                    self.video_flips.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            SIGNAL => {
+                self.signal.store(data, Ordering::Relaxed);
+                if let Some(handler) = self.signal_handler.lock().unwrap().as_mut() {
+                    handler(data);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The 16 byte control and status block at 0x80000000.
+///
+/// Registers are 32 bits wide. Narrower writes only take effect at the start of a register, and
+/// are zero extended.
+pub(crate) struct SpecialRegistry {
+    state: Arc<RegistryState>,
+}
+
+impl SpecialRegistry {
+    pub fn new(state: Arc<RegistryState>) -> Self {
+        SpecialRegistry { state }
+    }
+}
+
+impl Device for SpecialRegistry {
+    fn read_u8(&self, offset: u32) -> Result<u8, ()> {
+        let register = self.state.read_register(offset & !3);
+        Ok((register >> ((offset % 4) * 8)) as u8)
+    }
+
+    fn write_u8(&mut self, offset: u32, data: u8) -> Result<(), ()> {
+        self.write_u32(offset, data as u32)
+    }
+
+    fn read_u32(&self, offset: u32) -> Result<u32, ()> {
+        Ok(self.state.read_register(offset))
+    }
+
+    fn write_u16(&mut self, offset: u32, data: u16) -> Result<(), ()> {
+        self.write_u32(offset, data as u32)
+    }
+
+    fn write_u32(&mut self, offset: u32, data: u32) -> Result<(), ()> {
+        if offset.is_multiple_of(4) {
+            self.state.write_register(offset, data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::assemble, Pineapple, StepError};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn registers() {
+        let signals = Arc::new(Mutex::new(Vec::new()));
+        let mut pineapple = Pineapple::new();
+        let recorded = signals.clone();
+        pineapple
+            .set_host_signal_handler(Box::new(move |value| recorded.lock().unwrap().push(value)));
+        let program = assemble(
+            "
+            li t0, 0x80000000
+            lw a0, 4(t0)
+            li t1, 0x80000002
+            sw t1, 8(t0)
+            lw a1, 8(t0)
+            li t1, 42
+            sw t1, 12(t0)
+            sb t1, 12(t0)
+            lw a2, 12(t0)
+            lw a3, 0(t0)
+            li t1, -3
+            sw t1, 0(t0)
+            li a0, 1
+            ",
+        )
+        .unwrap();
+        pineapple.set_program(&program, 0);
+        assert_eq!(pineapple.run_for(100), Err(StepError::Exit(-3)));
+        // Stops on the store to the halt register
+        assert_eq!(pineapple.get_program_counter(), Ok(0x30));
+        assert_eq!(pineapple.cycles(), 12);
+
+        let registers = pineapple.get_registers().unwrap();
+        assert_eq!(registers[10..14], [1, 0x00010002, 42, 0]);
+        assert_eq!(pineapple.video_mode(), 2);
+        assert_eq!(pineapple.video_flips(), 1);
+        assert_eq!(*signals.lock().unwrap(), vec![42, 42]);
+    }
+}
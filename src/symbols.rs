@@ -0,0 +1,53 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Names for addresses in a guest program, from assembler labels or an ELF symbol table.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    by_address: BTreeMap<u32, String>,
+    by_name: HashMap<String, u32>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a symbol, if an address already has a name the first one is kept for display.
+    pub fn insert(&mut self, name: &str, address: u32) {
+        self.by_address
+            .entry(address)
+            .or_insert_with(|| name.to_string());
+        self.by_name.insert(name.to_string(), address);
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u32> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn name_of(&self, address: u32) -> Option<&str> {
+        self.by_address.get(&address).map(String::as_str)
+    }
+
+    /// The closest symbol at or before `address`, and how far past it `address` is.
+    pub fn nearest(&self, address: u32) -> Option<(&str, u32)> {
+        self.by_address
+            .range(..=address)
+            .next_back()
+            .map(|(start, name)| (name.as_str(), address - start))
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Every symbol as `(name, address)`, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.by_name
+            .iter()
+            .map(|(name, address)| (name.as_str(), *address))
+    }
+}
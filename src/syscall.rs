@@ -0,0 +1,267 @@
+use std::{
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::memory::MemorySystem;
+
+/// What the hart should do once an environment call has been handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyscallAction {
+    /// Carry on with the instruction after the ECALL
+    Continue,
+    /// Stop the program with an exit code
+    Exit(i32),
+    /// The call couldn't be serviced, execution stops on the ECALL
+    Fault(String),
+}
+
+/// Host side implementation of the guest's ECALL instruction.
+///
+/// Register one with [`crate::Pineapple::set_syscall_handler`].
+pub trait SyscallHandler {
+    fn ecall(&mut self, context: &mut SyscallContext) -> SyscallAction;
+}
+
+/// The guest state a [`SyscallHandler`] is allowed to see and change.
+pub struct SyscallContext<'a> {
+    pub(crate) registers: &'a mut [i32],
+    pub(crate) memory: &'a mut MemorySystem,
+}
+
+#[allow(clippy::result_unit_err)]
+impl SyscallContext<'_> {
+    pub fn register(&self, idx: usize) -> i32 {
+        self.registers[idx]
+    }
+
+    /// Writes a general purpose register, writes to x0 are ignored.
+    pub fn set_register(&mut self, idx: usize, value: i32) {
+        if idx != 0 {
+            self.registers[idx] = value;
+        }
+    }
+
+    /// Reads `len` bytes of guest memory, which have to sit inside a single device.
+    pub fn read_memory(&self, address: u32, len: usize) -> Result<Vec<u8>, ()> {
+        self.memory.read(address as usize, len)
+    }
+
+    /// Whether `len` bytes starting at `address` sit inside a single device, so they can be read
+    /// or written in one go.
+    pub fn is_mapped(&self, address: u32, len: usize) -> bool {
+        self.memory.is_backed(address as usize, len)
+    }
+
+    /// Writes guest memory, the range has to sit inside a single device.
+    pub fn write_memory(&mut self, address: u32, data: &[u8]) -> Result<(), ()> {
+        self.memory.load(address as usize, data)
+    }
+
+    /// Reads a NUL terminated string, without the terminator, giving up after `max_len` bytes.
+    pub fn read_c_string(&self, address: u32, max_len: usize) -> Result<Vec<u8>, ()> {
+        let mut bytes = Vec::new();
+        for offset in 0..max_len as u32 {
+            match self.read_memory(address.wrapping_add(offset), 1)?[0] {
+                0 => return Ok(bytes),
+                byte => bytes.push(byte),
+            }
+        }
+        Err(())
+    }
+}
+
+/// The host streams and sandbox directory behind a handler's console and file access.
+pub struct HostIo {
+    pub(crate) stdin: Box<dyn Read + Send>,
+    pub(crate) stdout: Box<dyn Write + Send>,
+    pub(crate) stderr: Box<dyn Write + Send>,
+    pub(crate) sandbox: Option<PathBuf>,
+}
+
+impl Default for HostIo {
+    /// The host process' own stdin, stdout and stderr, without a sandbox.
+    fn default() -> Self {
+        HostIo {
+            stdin: Box::new(io::stdin()),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            sandbox: None,
+        }
+    }
+}
+
+impl HostIo {
+    /// Maps a guest path into the sandbox, see [`sandboxed_path`].
+    pub(crate) fn host_path(&self, path: &str) -> Option<PathBuf> {
+        sandboxed_path(self.sandbox.as_ref()?, path)
+    }
+}
+
+/// Builder methods shared by the handlers that give the guest a console and files.
+pub trait HostIoBuilder: Sized {
+    fn host_io(&mut self) -> &mut HostIo;
+
+    fn stdin(mut self, stdin: Box<dyn Read + Send>) -> Self {
+        self.host_io().stdin = stdin;
+        self
+    }
+
+    fn stdout(mut self, stdout: Box<dyn Write + Send>) -> Self {
+        self.host_io().stdout = stdout;
+        self
+    }
+
+    fn stderr(mut self, stderr: Box<dyn Write + Send>) -> Self {
+        self.host_io().stderr = stderr;
+        self
+    }
+
+    /// Allows the guest to open files, but only below `directory`.
+    fn sandbox<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.host_io().sandbox = Some(directory.into());
+        self
+    }
+}
+
+// An output stream whose contents tests can look at afterwards
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(pub std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Maps a guest path onto the host below `sandbox`, refusing anything that would escape it.
+///
+/// Symlinks inside the sandbox are followed, so the result is checked against where the path
+/// really leads. A path that doesn't exist yet has to be in a directory that does.
+pub(crate) fn sandboxed_path(sandbox: &Path, path: &str) -> Option<PathBuf> {
+    let mut host = sandbox.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => host.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    let root = sandbox.canonicalize().ok()?;
+    let resolved = match host.canonicalize() {
+        Ok(resolved) => resolved,
+        // A file about to be created, but not a dangling symlink that would create it elsewhere
+        Err(_) if host.symlink_metadata().is_err() => {
+            host.parent()?.canonicalize().ok()?.join(host.file_name()?)
+        }
+        Err(_) => return None,
+    };
+    resolved.starts_with(&root).then_some(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, Pineapple, StepError};
+
+    struct Adder;
+
+    impl SyscallHandler for Adder {
+        fn ecall(&mut self, context: &mut SyscallContext) -> SyscallAction {
+            match context.register(17) {
+                1 => {
+                    let sum = context.register(10) + context.register(11);
+                    context.set_register(10, sum);
+                    SyscallAction::Continue
+                }
+                2 => match context.write_memory(context.register(10) as u32, b"hi") {
+                    Ok(()) => SyscallAction::Continue,
+                    Err(()) => SyscallAction::Fault("Bad address".to_string()),
+                },
+                93 => SyscallAction::Exit(context.register(10)),
+                _ => SyscallAction::Fault("Unknown syscall".to_string()),
+            }
+        }
+    }
+
+    fn load(source: &str) -> Pineapple {
+        let mut pineapple = Pineapple::new();
+        pineapple.set_program(&assemble(source).unwrap(), 0);
+        pineapple
+    }
+
+    #[test]
+    fn handler_can_continue_and_exit() {
+        let mut pineapple = load(
+            "
+            li a0, 2
+            li a1, 40
+            li a7, 1
+            ecall
+            li a7, 93
+            ecall
+            ",
+        );
+        pineapple.set_syscall_handler(Box::new(Adder));
+        assert_eq!(pineapple.run(), StepError::Exit(42));
+        // Stops on the ECALL
+        assert_eq!(pineapple.get_program_counter(), Ok(20));
+    }
+
+    #[test]
+    fn handler_memory_access_and_faults() {
+        let mut pineapple = load(
+            "
+            li a0, 0x100
+            li a7, 2
+            ecall
+            li a0, 0x30000
+            ecall
+            ",
+        );
+        pineapple.set_syscall_handler(Box::new(Adder));
+        assert_eq!(
+            pineapple.run(),
+            StepError::SyscallFault("Bad address".to_string())
+        );
+        assert_eq!(pineapple.get_data_range(0x100, 0x104), Ok(vec![0x6968]));
+    }
+
+    #[test]
+    fn breakpoint_and_missing_handler() {
+        let mut pineapple = load("nop\nebreak");
+        assert_eq!(pineapple.run_for(10), Err(StepError::Breakpoint));
+        assert_eq!(pineapple.get_program_counter(), Ok(4));
+
+        let mut pineapple = load("ecall");
+        assert_eq!(pineapple.step().err(), Some(StepError::EnvironmentCall));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sandbox_doesnt_follow_symlinks_out() {
+        let base = std::env::temp_dir().join(format!("pineapple-sandbox-{}", std::process::id()));
+        let sandbox = base.join("sandbox");
+        std::fs::create_dir_all(sandbox.join("inner")).unwrap();
+        std::os::unix::fs::symlink(&base, sandbox.join("out")).unwrap();
+        std::os::unix::fs::symlink(base.join("missing"), sandbox.join("dangling")).unwrap();
+
+        let root = sandbox.canonicalize().unwrap();
+        assert_eq!(
+            sandboxed_path(&sandbox, "/inner/new.txt"),
+            Some(root.join("inner").join("new.txt"))
+        );
+        assert_eq!(sandboxed_path(&sandbox, "out/secret.txt"), None);
+        assert_eq!(sandboxed_path(&sandbox, "out"), None);
+        assert_eq!(sandboxed_path(&sandbox, "dangling"), None);
+        assert_eq!(sandboxed_path(&sandbox, "missing/new.txt"), None);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
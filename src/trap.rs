@@ -0,0 +1,320 @@
+//! Machine mode traps, the only privilege mode the Pineapple has.
+
+use crate::{clint, MemoryModel, Pineapple, StepError};
+
+/// mstatus bit enabling interrupts
+pub const MSTATUS_MIE: u32 = 1 << 3;
+/// mstatus bit holding MIE from before the last trap
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+/// mstatus bits holding the privilege mode before the last trap, always machine mode
+pub const MSTATUS_MPP: u32 = 0b11 << 11;
+
+/// mcause bit set for interrupts
+pub const INTERRUPT: u32 = 1 << 31;
+
+// Exception codes in mcause
+pub const INSTRUCTION_MISALIGNED: u32 = 0;
+pub const INSTRUCTION_ACCESS_FAULT: u32 = 1;
+pub const ILLEGAL_INSTRUCTION: u32 = 2;
+pub const BREAKPOINT: u32 = 3;
+pub const LOAD_MISALIGNED: u32 = 4;
+pub const LOAD_ACCESS_FAULT: u32 = 5;
+pub const STORE_MISALIGNED: u32 = 6;
+pub const STORE_ACCESS_FAULT: u32 = 7;
+pub const MACHINE_ECALL: u32 = 11;
+
+// Interrupt codes in mcause, also the bit numbers in mie and mip
+pub const MACHINE_SOFTWARE_INTERRUPT: u32 = 3;
+pub const MACHINE_TIMER_INTERRUPT: u32 = 7;
+
+/// Who deals with exceptions: the host, through [`StepError`], or the guest's trap handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrapHandling {
+    /// Every exception stops execution and is returned by [`Pineapple::step`]
+    #[default]
+    Host,
+    /// Exceptions vector through mtvec. ECALLs and EBREAKs that a syscall or semihosting handler
+    /// services, and the exits they ask for, still go to the host
+    Guest,
+}
+
+/// The machine mode trap CSRs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapRegisters {
+    pub mstatus: u32,
+    /// Handler base address in bits 31:2, and 1 in bits 1:0 to vector interrupts to
+    /// `base + 4 * code`
+    pub mtvec: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    pub mie: u32,
+    pub mscratch: u32,
+}
+
+impl Default for TrapRegisters {
+    fn default() -> Self {
+        TrapRegisters {
+            mstatus: MSTATUS_MPP,
+            mtvec: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            mie: 0,
+            mscratch: 0,
+        }
+    }
+}
+
+impl TrapRegisters {
+    // Restores the interrupt enable from before the trap, returning where to go back to
+    pub(crate) fn mret(&mut self) -> u32 {
+        let mie = if self.mstatus & MSTATUS_MPIE != 0 {
+            MSTATUS_MIE
+        } else {
+            0
+        };
+        self.mstatus = (self.mstatus & !MSTATUS_MIE) | mie | MSTATUS_MPIE | MSTATUS_MPP;
+        self.mepc
+    }
+}
+
+// mcause and mtval for an error the guest can handle
+fn exception(error: &StepError, pc: u32) -> Option<(u32, u32)> {
+    Some(match error {
+        StepError::IllegalInstruction(e) => (ILLEGAL_INSTRUCTION, e.word as u32),
+        StepError::Breakpoint => (BREAKPOINT, pc),
+        StepError::EnvironmentCall => (MACHINE_ECALL, 0),
+        StepError::InstructionMisaligned(address) => (INSTRUCTION_MISALIGNED, *address),
+        StepError::InstructionAccessFault(address) => (INSTRUCTION_ACCESS_FAULT, *address),
+        StepError::LoadMisaligned(address) => (LOAD_MISALIGNED, *address),
+        StepError::LoadAccessFault(address) => (LOAD_ACCESS_FAULT, *address),
+        StepError::StoreMisaligned(address) => (STORE_MISALIGNED, *address),
+        StepError::StoreAccessFault(address) => (STORE_ACCESS_FAULT, *address),
+        StepError::Exit(_) | StepError::SyscallFault(_) => return None,
+    })
+}
+
+impl MemoryModel {
+    // Converts a code address back into a byte address
+    fn byte_address(self, pc: usize) -> u32 {
+        match self {
+            MemoryModel::Unified => pc as u32,
+            MemoryModel::Harvard => (pc * 4) as u32,
+        }
+    }
+}
+
+impl Pineapple {
+    pub fn trap_handling(&self) -> TrapHandling {
+        self.trap_handling
+    }
+
+    pub fn set_trap_handling(&mut self, handling: TrapHandling) {
+        self.trap_handling = handling;
+    }
+
+    pub fn trap_registers(&self) -> TrapRegisters {
+        self.traps
+    }
+
+    /// Replaces the trap CSRs. mstatus keeps reading machine mode as the previous privilege.
+    pub fn set_trap_registers(&mut self, registers: TrapRegisters) {
+        self.traps = registers;
+        self.traps.mstatus |= MSTATUS_MPP;
+    }
+
+    // Hands an exception to the guest if it's handling them and returns its mcause, otherwise
+    // gives it back
+    pub(crate) fn trap_exception(&mut self, error: StepError) -> Result<u32, StepError> {
+        if self.trap_handling != TrapHandling::Guest {
+            return Err(error);
+        }
+        let pc = *self.program_counter.read().unwrap();
+        let (cause, tval) = exception(&error, self.memory_model.byte_address(pc)).ok_or(error)?;
+        self.enter_trap(cause, tval, self.traps.mtvec & !3);
+        Ok(cause)
+    }
+
+    // Takes the highest priority interrupt that's pending and enabled, returning its mcause
+    pub(crate) fn take_interrupt(&mut self) -> Option<u32> {
+        if self.traps.mstatus & MSTATUS_MIE == 0 {
+            return None;
+        }
+        let pending = self.pending_interrupts() & self.traps.mie;
+        let code = if pending & clint::MACHINE_SOFTWARE_INTERRUPT != 0 {
+            MACHINE_SOFTWARE_INTERRUPT
+        } else if pending & clint::MACHINE_TIMER_INTERRUPT != 0 {
+            MACHINE_TIMER_INTERRUPT
+        } else {
+            return None;
+        };
+        let base = self.traps.mtvec & !3;
+        let handler = match self.traps.mtvec & 3 {
+            1 => base.wrapping_add(4 * code),
+            _ => base,
+        };
+        self.enter_trap(INTERRUPT | code, 0, handler);
+        Some(INTERRUPT | code)
+    }
+
+    fn enter_trap(&mut self, cause: u32, tval: u32, handler: u32) {
+        let mut pc = self.program_counter.write().unwrap();
+        let traps = &mut self.traps;
+        traps.mepc = self.memory_model.byte_address(*pc);
+        traps.mcause = cause;
+        traps.mtval = tval;
+        let mpie = if traps.mstatus & MSTATUS_MIE != 0 {
+            MSTATUS_MPIE
+        } else {
+            0
+        };
+        traps.mstatus = (traps.mstatus & !(MSTATUS_MIE | MSTATUS_MPIE)) | mpie | MSTATUS_MPP;
+        *pc = self.memory_model.code_address(handler);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, Extensions, Instruction, Step};
+
+    #[test]
+    fn exceptions_vector_to_the_guest() {
+        let mut pineapple = Pineapple::new();
+        let program = assemble(
+            "
+                li t0, 0x20000
+                lw a0, 0(t0)
+                ebreak
+            ",
+        )
+        .unwrap();
+        pineapple.set_program(&program, 0);
+        // The handler counts traps in a1 and returns to mepc
+        let handler = assemble("addi a1, a1, 1\nmret").unwrap();
+        pineapple.set_program(&handler, 0x100);
+
+        // The host sees the fault by default
+        assert_eq!(
+            pineapple.run_for(10),
+            Err(StepError::LoadAccessFault(0x20000))
+        );
+
+        pineapple.set_trap_handling(TrapHandling::Guest);
+        pineapple.set_trap_registers(TrapRegisters {
+            mtvec: 0x100,
+            ..Default::default()
+        });
+        // Trapping only moves to the handler, which runs from the next step
+        assert_eq!(pineapple.get_program_counter(), Ok(4));
+        assert_eq!(pineapple.step(), Ok(Step::Trapped(LOAD_ACCESS_FAULT)));
+        assert_eq!(pineapple.get_program_counter(), Ok(0x100));
+        let traps = pineapple.trap_registers();
+        assert_eq!(
+            (traps.mepc, traps.mcause, traps.mtval),
+            (4, LOAD_ACCESS_FAULT, 0x20000)
+        );
+        assert!(matches!(
+            pineapple.step(),
+            Ok(Step::Executed(Instruction::ADDI(_)))
+        ));
+        assert_eq!(pineapple.get_program_counter(), Ok(0x104));
+
+        // MRET goes back to the faulting load, which faults again
+        pineapple.step().unwrap();
+        assert_eq!(pineapple.get_program_counter(), Ok(4));
+        pineapple.run_for(2).unwrap();
+        assert_eq!(pineapple.get_registers().unwrap()[11], 2);
+
+        // Skip the load and hit the breakpoint instead
+        let mut traps = pineapple.trap_registers();
+        traps.mepc = 8;
+        pineapple.set_trap_registers(traps);
+        pineapple.step().unwrap();
+        assert_eq!(pineapple.step(), Ok(Step::Trapped(BREAKPOINT)));
+        let traps = pineapple.trap_registers();
+        assert_eq!((traps.mepc, traps.mcause, traps.mtval), (8, BREAKPOINT, 8));
+
+        // A handler that can't be fetched traps again, into itself
+        pineapple.set_trap_registers(TrapRegisters {
+            mtvec: 0x20000,
+            ..Default::default()
+        });
+        pineapple.jump_to(8);
+        assert_eq!(pineapple.step(), Ok(Step::Trapped(BREAKPOINT)));
+        assert_eq!(
+            pineapple.step(),
+            Ok(Step::Trapped(INSTRUCTION_ACCESS_FAULT))
+        );
+        let traps = pineapple.trap_registers();
+        assert_eq!((traps.mepc, traps.mtval), (0x20000, 0x20000));
+    }
+
+    #[test]
+    fn misaligned_jumps_trap_on_the_jump() {
+        let mut pineapple = Pineapple::new();
+        pineapple.set_extensions(Extensions::rv32i());
+        let program = assemble("li t0, 0x102\njalr ra, 0(t0)").unwrap();
+        pineapple.set_program(&program, 0);
+        pineapple.set_trap_handling(TrapHandling::Guest);
+        pineapple.set_trap_registers(TrapRegisters {
+            mtvec: 0x100,
+            ..Default::default()
+        });
+        pineapple.step().unwrap();
+        assert_eq!(pineapple.step(), Ok(Step::Trapped(INSTRUCTION_MISALIGNED)));
+        let traps = pineapple.trap_registers();
+        assert_eq!((traps.mepc, traps.mtval), (4, 0x102));
+        // The link register isn't written either
+        assert_eq!(pineapple.get_registers().unwrap()[1], 0);
+    }
+
+    #[test]
+    fn timer_interrupts() {
+        let mut pineapple = Pineapple::new();
+        let program = assemble(
+            "
+                li t0, 0x02004000
+                li t1, 10
+                sw t1, 0(t0)        # mtimecmp low
+                sw zero, 4(t0)      # mtimecmp high
+            spin:
+                addi a0, a0, 1
+                j spin
+            ",
+        )
+        .unwrap();
+        pineapple.set_program(&program, 0);
+        // Vectored: the timer interrupt goes to 0x200 + 4 * 7
+        let handler = assemble("li t1, -1\nsw t1, 0(t0)\naddi a1, a1, 1\nmret").unwrap();
+        pineapple.set_program(&handler, 0x21C);
+        pineapple.set_trap_registers(TrapRegisters {
+            mtvec: 0x201,
+            mstatus: MSTATUS_MIE,
+            mie: 1 << MACHINE_TIMER_INTERRUPT,
+            ..Default::default()
+        });
+
+        // mtime reaches 10 after ten instructions, and the eleventh step takes the interrupt
+        pineapple.run_for(10).unwrap();
+        assert_eq!(
+            pineapple.step(),
+            Ok(Step::Trapped(INTERRUPT | MACHINE_TIMER_INTERRUPT))
+        );
+        let traps = pineapple.trap_registers();
+        assert_eq!(traps.mcause, INTERRUPT | MACHINE_TIMER_INTERRUPT);
+        assert_eq!(traps.mstatus & (MSTATUS_MIE | MSTATUS_MPIE), MSTATUS_MPIE);
+        assert_eq!(pineapple.get_program_counter(), Ok(0x21C));
+
+        // Once the handler pushes mtimecmp away, the interrupt stays quiet
+        pineapple.run_for(100).unwrap();
+        let registers = pineapple.get_registers().unwrap();
+        assert_eq!(registers[11], 1);
+        assert!(registers[10] > 40);
+        assert_eq!(
+            pineapple.trap_registers().mstatus & (MSTATUS_MIE | MSTATUS_MPIE),
+            MSTATUS_MIE | MSTATUS_MPIE
+        );
+    }
+}
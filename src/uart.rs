@@ -0,0 +1,348 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::bus::Device;
+
+/// Receive buffer when reading, transmit holding register when writing
+pub const RBR_THR: u32 = 0;
+/// Interrupt enable register
+pub const IER: u32 = 1;
+/// Interrupt identification register when reading, FIFO control register when writing
+pub const IIR_FCR: u32 = 2;
+/// Line control register, bit 7 switches offsets 0 and 1 to the divisor latch
+pub const LCR: u32 = 3;
+/// Modem control register, bit 4 loops transmitted bytes back into the receiver
+pub const MCR: u32 = 4;
+/// Line status register
+pub const LSR: u32 = 5;
+/// Modem status register
+pub const MSR: u32 = 6;
+/// Scratch register
+pub const SCR: u32 = 7;
+
+/// LSR bit set while there's a received byte waiting
+pub const LSR_DATA_READY: u8 = 0x01;
+/// LSR bit set while the transmit holding register is empty, which is always
+pub const LSR_THR_EMPTY: u8 = 0x20;
+/// LSR bit set while the transmitter is idle, which is always
+pub const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
+
+const LCR_DLAB: u8 = 0x80;
+const MCR_LOOPBACK: u8 = 0x10;
+const FCR_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+
+/// A 16550 compatible UART, 8 bytes wide.
+///
+/// Transmitted bytes go straight to the host's output and received bytes come from the host's
+/// input, so the baud rate and line settings are stored but otherwise ignored. Map it with
+/// [`crate::Pineapple::attach_device`], or as an MMIO region of a
+/// [`crate::config::MachineConfig`]. Without an input the receiver stays empty, and without an
+/// output transmitted bytes are dropped.
+pub struct Uart {
+    state: Mutex<UartState>,
+}
+
+struct UartState {
+    input: Option<Receiver<u8>>,
+    output: Box<dyn Write + Send>,
+    rx: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Uart {
+    /// A UART with nothing connected.
+    pub fn new() -> Self {
+        Uart {
+            state: Mutex::new(UartState {
+                input: None,
+                output: Box::new(io::sink()),
+                rx: VecDeque::new(),
+                ier: 0,
+                fcr: 0,
+                lcr: 0,
+                mcr: 0,
+                scr: 0,
+                divisor: 0,
+            }),
+        }
+    }
+
+    /// A UART connected to the host process' own stdin and stdout.
+    pub fn stdio() -> Self {
+        Self::new()
+            .input(Box::new(io::stdin()))
+            .output(Box::new(io::stdout()))
+    }
+
+    /// Receives the bytes read from `input`.
+    ///
+    /// A background thread does the reading, so the guest can poll the line status without
+    /// blocking on the host.
+    pub fn input(self, mut input: Box<dyn Read + Send>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 256];
+            loop {
+                match input.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        if buffer[..len].iter().any(|byte| sender.send(*byte).is_err()) {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                }
+            }
+        });
+        self.input_channel(receiver)
+    }
+
+    /// Receives the bytes sent on `input`'s channel.
+    pub fn input_channel(self, input: Receiver<u8>) -> Self {
+        self.state.lock().unwrap().input = Some(input);
+        self
+    }
+
+    /// Writes transmitted bytes to `output`, flushing after each one.
+    pub fn output(self, output: Box<dyn Write + Send>) -> Self {
+        self.state.lock().unwrap().output = output;
+        self
+    }
+
+    /// Sends transmitted bytes on `output`'s channel.
+    pub fn output_channel(self, output: Sender<u8>) -> Self {
+        self.output(Box::new(ChannelWriter(output)))
+    }
+}
+
+impl UartState {
+    // Moves whatever the host has sent so far into the receive buffer
+    fn poll_input(&mut self) {
+        if let Some(input) = &self.input {
+            loop {
+                match input.try_recv() {
+                    Ok(byte) => self.rx.push_back(byte),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.input = None;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Only guest reads of the receive buffer `consume` the byte they return
+    fn read(&mut self, offset: u32, consume: bool) -> Result<u8, ()> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        Ok(match offset {
+            RBR_THR if dlab => self.divisor as u8,
+            RBR_THR => {
+                self.poll_input();
+                let byte = if consume {
+                    self.rx.pop_front()
+                } else {
+                    self.rx.front().copied()
+                };
+                byte.unwrap_or(0)
+            }
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                self.poll_input();
+                let fifo = if self.fcr & FCR_ENABLE != 0 { 0xC0 } else { 0 };
+                let pending = if self.ier & 0x01 != 0 && !self.rx.is_empty() {
+                    0x04
+                } else if self.ier & 0x02 != 0 {
+                    0x02
+                } else {
+                    0x01
+                };
+                fifo | pending
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.poll_input();
+                let ready = if self.rx.is_empty() {
+                    0
+                } else {
+                    LSR_DATA_READY
+                };
+                ready | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+            }
+            MSR => 0,
+            SCR => self.scr,
+            _ => return Err(()),
+        })
+    }
+
+    fn write(&mut self, offset: u32, data: u8) -> Result<(), ()> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.divisor = (self.divisor & 0xFF00) | data as u16,
+            RBR_THR if self.mcr & MCR_LOOPBACK != 0 => self.rx.push_back(data),
+            // A host that has gone away just stops seeing output
+            RBR_THR => {
+                let _ = self
+                    .output
+                    .write_all(&[data])
+                    .and_then(|_| self.output.flush());
+            }
+            IER if dlab => self.divisor = (self.divisor & 0x00FF) | (data as u16) << 8,
+            IER => self.ier = data & 0x0F,
+            IIR_FCR => {
+                if data & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = data & FCR_ENABLE;
+            }
+            LCR => self.lcr = data,
+            MCR => self.mcr = data & 0x1F,
+            LSR | MSR => {}
+            SCR => self.scr = data,
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+}
+
+impl Device for Uart {
+    fn read_u8(&self, offset: u32) -> Result<u8, ()> {
+        self.state.lock().unwrap().read(offset, true)
+    }
+
+    fn write_u8(&mut self, offset: u32, data: u8) -> Result<(), ()> {
+        self.state.get_mut().unwrap().write(offset, data)
+    }
+
+    // Host side reads mustn't take received bytes away from the guest
+    fn read(&self, offset: u32, len: usize) -> Result<Vec<u8>, ()> {
+        let mut state = self.state.lock().unwrap();
+        (offset..offset + len as u32)
+            .map(|offset| state.read(offset, false))
+            .collect()
+    }
+}
+
+struct ChannelWriter(Sender<u8>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.0
+                .send(*byte)
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, Pineapple, StepError};
+    use std::{io::Cursor, time::Duration};
+
+    // Echoes received bytes back in upper case until it reads a newline
+    const ECHO: &str = "
+            li t0, 0x10000000
+            li t1, 0x83
+            sb t1, 3(t0)        # set DLAB
+            li t1, 12
+            sb t1, 0(t0)        # 9600 baud
+            sb zero, 1(t0)
+            li t1, 0x03
+            sb t1, 3(t0)        # 8N1
+        wait:
+            lbu t1, 5(t0)
+            andi t1, t1, 1
+            beqz t1, wait
+            lbu t2, 0(t0)
+            li t3, 10
+            beq t2, t3, done
+            addi t2, t2, -32
+            sb t2, 0(t0)
+            j wait
+        done:
+            ebreak
+        ";
+
+    fn run(uart: Uart) -> Pineapple {
+        let mut pineapple = Pineapple::new();
+        pineapple
+            .attach_device(0x10000000, 8, Box::new(uart))
+            .unwrap();
+        pineapple.set_program(&assemble(ECHO).unwrap(), 0);
+        assert_eq!(pineapple.run_for(1_000_000), Err(StepError::Breakpoint));
+        pineapple
+    }
+
+    #[test]
+    fn channels() {
+        let (to_guest, input) = mpsc::channel();
+        let (output, from_guest) = mpsc::channel();
+        for byte in b"abc\n" {
+            to_guest.send(*byte).unwrap();
+        }
+        let pineapple = run(Uart::new().input_channel(input).output_channel(output));
+        assert_eq!(from_guest.try_iter().collect::<Vec<_>>(), b"ABC");
+        // The host can look at the registers without side effects
+        assert_eq!(
+            pineapple.get_data_range(0x10000000, 0x10000008),
+            Ok(vec![0x03010000, 0x00006000])
+        );
+    }
+
+    #[test]
+    fn streams_and_loopback() {
+        let (output, from_guest) = mpsc::channel();
+        run(Uart::new()
+            .input(Box::new(Cursor::new(b"hi\n".to_vec())))
+            .output_channel(output));
+        assert_eq!(
+            from_guest
+                .recv_timeout(Duration::from_secs(5))
+                .into_iter()
+                .chain(from_guest.try_iter())
+                .collect::<Vec<_>>(),
+            b"HI"
+        );
+
+        let mut uart = Uart::new();
+        uart.write_u8(MCR, MCR_LOOPBACK).unwrap();
+        uart.write_u8(RBR_THR, b'x').unwrap();
+        assert_eq!(
+            uart.read_u8(LSR),
+            Ok(LSR_DATA_READY | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY)
+        );
+        assert_eq!(uart.read(RBR_THR, 1), Ok(vec![b'x']));
+        assert_eq!(uart.read_u8(RBR_THR), Ok(b'x'));
+        assert_eq!(uart.read_u8(LSR), Ok(LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY));
+        assert_eq!(uart.read_u8(8), Err(()));
+    }
+}
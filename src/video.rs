@@ -0,0 +1,346 @@
+use std::io::{self, Write};
+
+use crate::{font, Pineapple};
+
+/// How video RAM is turned into pixels.
+///
+/// The guest selects one with bits 7:0 of the Special Registry's video register, see
+/// [`PixelFormat::from_mode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 1 bit per pixel with the most significant bit leftmost. Set bits use palette entry 15,
+    /// clear bits entry 0
+    Mono,
+    /// 1 byte per pixel, indexing the palette
+    Indexed8,
+    /// 2 bytes per pixel, little endian with red in bits 15:11, green in 10:5 and blue in 4:0
+    Rgb565,
+    /// 2 bytes per 8x8 character cell: the character, then an attribute byte with the foreground
+    /// palette index in bits 3:0 and the background in bits 7:4
+    Text,
+}
+
+impl PixelFormat {
+    /// The format for a video mode number: 0 is mono, 1 indexed, 2 RGB565 and 3 text.
+    pub fn from_mode(mode: u8) -> Option<Self> {
+        match mode {
+            0 => Some(PixelFormat::Mono),
+            1 => Some(PixelFormat::Indexed8),
+            2 => Some(PixelFormat::Rgb565),
+            3 => Some(PixelFormat::Text),
+            _ => None,
+        }
+    }
+
+    pub fn mode(self) -> u8 {
+        match self {
+            PixelFormat::Mono => 0,
+            PixelFormat::Indexed8 => 1,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Text => 3,
+        }
+    }
+}
+
+/// Describes how to draw video RAM: its pixel format, row width and palette.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    format: PixelFormat,
+    width: u32,
+    palette: Vec<[u8; 3]>,
+}
+
+impl Framebuffer {
+    /// A framebuffer using the default width for `format`, which makes the 2 KiB of video RAM
+    /// 128x128 in mono, 64x32 indexed, 32x32 in RGB565 and 40x25 characters in text mode. The
+    /// palette is the xterm 256 colour one.
+    pub fn new(format: PixelFormat) -> Self {
+        let width = match format {
+            PixelFormat::Mono => 128,
+            PixelFormat::Indexed8 => 64,
+            PixelFormat::Rgb565 => 32,
+            PixelFormat::Text => 40,
+        };
+        Framebuffer {
+            format,
+            width,
+            palette: (0..=255).map(xterm_colour).collect(),
+        }
+    }
+
+    /// Pixels per row, or characters per row in text mode. Mono rows are padded to whole bytes.
+    pub fn width(mut self, width: u32) -> Self {
+        self.width = width.max(1);
+        self
+    }
+
+    /// Replaces the palette, entries past the end are black.
+    pub fn palette(mut self, palette: &[[u8; 3]]) -> Self {
+        self.palette = palette.to_vec();
+        self
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Width and height in pixels of the image drawn from `len` bytes of video RAM, leaving out
+    /// any partial row at the end.
+    pub fn dimensions(&self, len: usize) -> (u32, u32) {
+        let width = self.width as usize;
+        match self.format {
+            PixelFormat::Mono => (self.width, (len / width.div_ceil(8)) as u32),
+            PixelFormat::Indexed8 => (self.width, (len / width) as u32),
+            PixelFormat::Rgb565 => (self.width, (len / (width * 2)) as u32),
+            PixelFormat::Text => (self.width * 8, (len / (width * 2)) as u32 * 8),
+        }
+    }
+
+    fn colour(&self, index: u8) -> [u8; 3] {
+        self.palette.get(index as usize).copied().unwrap_or([0; 3])
+    }
+
+    /// Draws `vram` into an RGBA image.
+    pub fn render(&self, vram: &[u8]) -> Image {
+        let (width, height) = self.dimensions(vram.len());
+        let mut image = Image::new(width, height);
+        let columns = self.width as usize;
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                let colour = match self.format {
+                    PixelFormat::Mono => {
+                        let byte = vram[y * columns.div_ceil(8) + x / 8];
+                        self.colour(if byte & (0x80 >> (x % 8)) != 0 { 15 } else { 0 })
+                    }
+                    PixelFormat::Indexed8 => self.colour(vram[y * columns + x]),
+                    PixelFormat::Rgb565 => {
+                        let idx = (y * columns + x) * 2;
+                        rgb565(u16::from_le_bytes([vram[idx], vram[idx + 1]]))
+                    }
+                    PixelFormat::Text => {
+                        let idx = ((y / 8) * columns + x / 8) * 2;
+                        let (character, attribute) = (vram[idx], vram[idx + 1]);
+                        if font::glyph(character)[y % 8] & (1 << (x % 8)) != 0 {
+                            self.colour(attribute & 0xF)
+                        } else {
+                            self.colour(attribute >> 4)
+                        }
+                    }
+                };
+                image.set_pixel(x as u32, y as u32, colour);
+            }
+        }
+        image
+    }
+}
+
+/// An RGBA image, 4 bytes per pixel in rows from the top left.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+impl Image {
+    fn new(width: u32, height: u32) -> Self {
+        Image {
+            width,
+            height,
+            data: vec![0; width as usize * height as usize * 4],
+        }
+    }
+
+    fn set_pixel(&mut self, x: u32, y: u32, [r, g, b]: [u8; 3]) {
+        let idx = (y as usize * self.width as usize + x as usize) * 4;
+        self.data[idx..idx + 4].copy_from_slice(&[r, g, b, 0xFF]);
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The RGBA value at `(x, y)`, panicking if it's outside the image.
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        assert!(x < self.width && y < self.height, "Pixel out of bounds");
+        let idx = (y as usize * self.width as usize + x as usize) * 4;
+        [
+            self.data[idx],
+            self.data[idx + 1],
+            self.data[idx + 2],
+            self.data[idx + 3],
+        ]
+    }
+
+    pub fn as_rgba(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn write_png<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(png_error)?;
+        writer.write_image_data(&self.data).map_err(png_error)
+    }
+
+    /// Writes a binary PPM, which has no alpha channel.
+    pub fn write_ppm<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+        let rgb: Vec<u8> = self
+            .data
+            .chunks(4)
+            .flat_map(|pixel| pixel[..3].iter().copied())
+            .collect();
+        writer.write_all(&rgb)
+    }
+}
+
+fn png_error(e: png::EncodingError) -> io::Error {
+    match e {
+        png::EncodingError::IoError(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+fn rgb565(pixel: u16) -> [u8; 3] {
+    let r = (pixel >> 11) as u8 & 0x1F;
+    let g = (pixel >> 5) as u8 & 0x3F;
+    let b = pixel as u8 & 0x1F;
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+}
+
+// The 16 system colours, a 6x6x6 colour cube and a 24 step grey ramp
+fn xterm_colour(index: u8) -> [u8; 3] {
+    const SYSTEM: [[u8; 3]; 16] = [
+        [0x00, 0x00, 0x00],
+        [0x80, 0x00, 0x00],
+        [0x00, 0x80, 0x00],
+        [0x80, 0x80, 0x00],
+        [0x00, 0x00, 0x80],
+        [0x80, 0x00, 0x80],
+        [0x00, 0x80, 0x80],
+        [0xC0, 0xC0, 0xC0],
+        [0x80, 0x80, 0x80],
+        [0xFF, 0x00, 0x00],
+        [0x00, 0xFF, 0x00],
+        [0xFF, 0xFF, 0x00],
+        [0x00, 0x00, 0xFF],
+        [0xFF, 0x00, 0xFF],
+        [0x00, 0xFF, 0xFF],
+        [0xFF, 0xFF, 0xFF],
+    ];
+    match index {
+        0..=15 => SYSTEM[index as usize],
+        16..=231 => {
+            let level = |value: u8| if value == 0 { 0 } else { 55 + value * 40 };
+            let cube = index - 16;
+            [level(cube / 36), level(cube / 6 % 6), level(cube % 6)]
+        }
+        _ => [8 + (index - 232) * 10; 3],
+    }
+}
+
+#[allow(clippy::result_unit_err)]
+impl Pineapple {
+    /// Draws video RAM in the mode the guest selected through the Special Registry.
+    ///
+    /// Fails if the machine has no video RAM or the mode is unknown.
+    pub fn render_video(&self) -> Result<Image, ()> {
+        let format = PixelFormat::from_mode(self.video_mode()).ok_or(())?;
+        self.render_video_with(&Framebuffer::new(format))
+    }
+
+    /// Draws video RAM with `framebuffer`, ignoring the mode the guest selected.
+    pub fn render_video_with(&self, framebuffer: &Framebuffer) -> Result<Image, ()> {
+        Ok(framebuffer.render(&self.video_ram()?))
+    }
+
+    pub(crate) fn video_ram(&self) -> Result<Vec<u8>, ()> {
+        let (base, size) = self.memory().video_memory().ok_or(())?;
+        self.memory().read(base as usize, size as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, StepError};
+
+    #[test]
+    fn guest_drawing() {
+        let mut pineapple = Pineapple::new();
+        let program = assemble(
+            "
+            li t0, 0x40000000
+            li t1, 0xF800       # red
+            sh t1, 0(t0)
+            li t1, 0x07E0       # green
+            sh t1, 66(t0)       # (1, 1)
+            li t0, 0x80000000
+            li t1, 2
+            sw t1, 8(t0)
+            ebreak
+            ",
+        )
+        .unwrap();
+        pineapple.set_program(&program, 0);
+        assert_eq!(pineapple.run_for(100), Err(StepError::Breakpoint));
+        assert_eq!(pineapple.get_video_memory().unwrap()[0], 0xF800);
+
+        let image = pineapple.render_video().unwrap();
+        assert_eq!((image.width(), image.height()), (32, 32));
+        assert_eq!(image.pixel(0, 0), [0xFF, 0, 0, 0xFF]);
+        assert_eq!(image.pixel(1, 1), [0, 0xFF, 0, 0xFF]);
+        assert_eq!(image.pixel(1, 0), [0, 0, 0, 0xFF]);
+
+        let mono = pineapple
+            .render_video_with(&Framebuffer::new(PixelFormat::Mono))
+            .unwrap();
+        assert_eq!((mono.width(), mono.height()), (128, 128));
+        // 0xF800 is stored as 0x00 0xF8
+        assert_eq!(mono.pixel(8, 0), [0xFF; 4]);
+        assert_eq!(mono.pixel(13, 0), [0, 0, 0, 0xFF]);
+
+        let mut ppm = Vec::new();
+        image.write_ppm(&mut ppm).unwrap();
+        assert!(ppm.starts_with(b"P6\n32 32\n255\n\xFF\x00\x00"));
+        assert_eq!(ppm.len(), 13 + 32 * 32 * 3);
+    }
+
+    #[test]
+    fn text_and_palettes() {
+        let framebuffer = Framebuffer::new(PixelFormat::Text).width(2);
+        // 'T' in bright white on blue, then a blank cell
+        let image = framebuffer.render(&[b'T', 0x4F, b' ', 0x00]);
+        assert_eq!((image.width(), image.height()), (16, 8));
+        // The top row of 'T' is 0x3F, so its first six pixels are lit
+        assert_eq!(image.pixel(0, 0), [0xFF; 4]);
+        assert_eq!(image.pixel(6, 0), [0, 0, 0x80, 0xFF]);
+        assert_eq!(image.pixel(8, 0), [0, 0, 0, 0xFF]);
+
+        let indexed = Framebuffer::new(PixelFormat::Indexed8)
+            .width(2)
+            .palette(&[[1, 2, 3]])
+            .render(&[0, 1, 196, 255]);
+        assert_eq!(indexed.pixel(0, 0), [1, 2, 3, 0xFF]);
+        assert_eq!(indexed.pixel(1, 0), [0, 0, 0, 0xFF]);
+        let xterm = Framebuffer::new(PixelFormat::Indexed8)
+            .width(2)
+            .render(&[196, 255]);
+        assert_eq!(xterm.pixel(0, 0), [0xFF, 0, 0, 0xFF]);
+        assert_eq!(xterm.pixel(1, 0), [0xEE, 0xEE, 0xEE, 0xFF]);
+
+        let mut png = Vec::new();
+        image.write_png(&mut png).unwrap();
+        let decoder = png::Decoder::new(png.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut decoded = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut decoded).unwrap();
+        assert_eq!(decoded, image.as_rgba());
+    }
+}